@@ -11,6 +11,7 @@ CREATE TABLE "instruments" (
   "model" varchar(100) NOT NULL,
   "price" numeric(10, 2) NOT NULL,
   "count" int NOT NULL,
+  "archived" boolean NOT NULL DEFAULT false,
   PRIMARY KEY ("instrument_id"),
   CONSTRAINT "FK_instruments.instrument_type_id"
     FOREIGN KEY ("instrument_type_id")
@@ -49,6 +50,7 @@ CREATE TABLE "person_details" (
 CREATE TABLE "students" (
   "student_id" int GENERATED ALWAYS AS IDENTITY,
   "person_details_id" int NOT NULL,
+  "active" boolean NOT NULL DEFAULT true,
   PRIMARY KEY ("student_id"),
   CONSTRAINT "FK_students_person_details.id"
     FOREIGN KEY ("person_details_id")
@@ -94,6 +96,9 @@ CREATE TABLE "rentings" (
   "instrument_id" int NOT NULL,
   "start_date" timestamptz NOT NULL,
   "end_date" timestamptz,
+  "termination_note" varchar(500),
+  "agreed_price" numeric(10, 2) NOT NULL,
+  "deposit" numeric(10, 2) NOT NULL DEFAULT 0,
   PRIMARY KEY ("rent_id"),
   CONSTRAINT "FK_rentings.student_id"
     FOREIGN KEY ("student_id")
@@ -103,6 +108,77 @@ CREATE TABLE "rentings" (
       REFERENCES "instruments"("instrument_id")
 );
 
+\ir one_active_renting.sql
+
+\ir rental_limit.sql
+
+CREATE TABLE "rental_charges" (
+  "charge_id" int GENERATED ALWAYS AS IDENTITY,
+  "rent_id" int,
+  "amount" numeric(10, 2) NOT NULL,
+  "late_fee" numeric(10, 2) NOT NULL DEFAULT 0,
+  "charged_at" timestamptz NOT NULL,
+  PRIMARY KEY ("charge_id")
+);
+
+-- No foreign key on rent_id, notes may be kept for rentings which no longer exist
+CREATE TABLE "renting_notes" (
+  "note_id" int GENERATED ALWAYS AS IDENTITY,
+  "rent_id" int NOT NULL,
+  "note" varchar(500) NOT NULL,
+  "created_at" timestamptz NOT NULL,
+  PRIMARY KEY ("note_id")
+);
+
+CREATE TABLE "damages" (
+  "damage_id" int GENERATED ALWAYS AS IDENTITY,
+  "rent_id" int,
+  "note" varchar(500) NOT NULL,
+  "amount" numeric(10, 2) NOT NULL DEFAULT 0,
+  "recorded_at" timestamptz NOT NULL,
+  PRIMARY KEY ("damage_id")
+);
+
+CREATE TABLE "reservations" (
+  "reservation_id" int GENERATED ALWAYS AS IDENTITY,
+  "student_id" int NOT NULL,
+  "instrument_id" int NOT NULL,
+  "created_at" timestamptz NOT NULL,
+  "active" boolean NOT NULL DEFAULT true,
+  "fulfilled_rent_id" int,
+  PRIMARY KEY ("reservation_id"),
+  CONSTRAINT "FK_reservations.student_id"
+    FOREIGN KEY ("student_id")
+      REFERENCES "students"("student_id"),
+  CONSTRAINT "FK_reservations.instrument_id"
+    FOREIGN KEY ("instrument_id")
+      REFERENCES "instruments"("instrument_id")
+);
+
+-- Creates rentings_archive and the foreign keys of the tables above to rentings and the archive
+\ir rentings_archive.sql
+
+CREATE TABLE "maintenance" (
+  "maintenance_id" int GENERATED ALWAYS AS IDENTITY,
+  "instrument_id" int NOT NULL,
+  "note" varchar(500) NOT NULL,
+  "started_at" timestamptz NOT NULL,
+  "ended_at" timestamptz,
+  PRIMARY KEY ("maintenance_id"),
+  CONSTRAINT "FK_maintenance.instrument_id"
+    FOREIGN KEY ("instrument_id")
+      REFERENCES "instruments"("instrument_id")
+);
+
+CREATE TABLE "audit_log" (
+  "audit_id" int GENERATED ALWAYS AS IDENTITY,
+  "session" varchar(100) NOT NULL,
+  "executed_at" timestamptz NOT NULL,
+  "command" varchar(1000) NOT NULL,
+  "succeeded" boolean NOT NULL,
+  PRIMARY KEY ("audit_id")
+);
+
 CREATE TABLE "skill_levels" (
   "skill_value" int UNIQUE,
   "skill_level" varchar(100) UNIQUE,
@@ -136,6 +212,8 @@ CREATE TABLE "payments" (
       REFERENCES "instructors"("instructor_id")
 );
 
+\ir payments_paid_at.sql
+
 CREATE TABLE "siblings" (
   "first_student_id" int NOT NULL,
   "second_student_id" int NOT NULL,
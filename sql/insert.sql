@@ -8,6 +8,11 @@ INSERT INTO instruments (instrument_type_id, brand, model, price, count) values
 
 INSERT INTO business_rules (name, value) values ('rent_max_count', '2');
 INSERT INTO business_rules (name, value) values ('rent_max_time', '12');
+INSERT INTO business_rules (name, value) values ('sibling_discount_pct', '10');
+INSERT INTO business_rules (name, value) values ('late_fee_per_day', '5');
+INSERT INTO business_rules (name, value) values ('deposit_required_above', '250');
+INSERT INTO business_rules (name, value) values ('deposit_amount', '100');
+INSERT INTO business_rules (name, value) values ('reserve_max_count', '2');
 
 INSERT INTO addresses (line_1, line_2, city, zip) values ('Ap #231-7514 Tellus. Rd.','Luctus Ltd','Gävle','43974');
 INSERT INTO addresses (line_1, city, zip) values ('482-660 Ut Rd.','Hudiksvall','36473');
@@ -35,8 +40,8 @@ INSERT INTO rooms (room_number, max_capacity) values (201, 40);
 INSERT INTO lessons (room_id, topic, skill_level, min_places, max_places, start_date, end_date, cost) values (1, 'basic guitar', 0, 1, 1, '2022-05-20 15:00:10-09', '2022-05-20 16:00:10-09', 101.10);
 INSERT INTO lessons (room_id, topic, skill_level, min_places, max_places, genre, start_date, end_date, cost) values (2, 'basic piano', 2, 10, 40, 'classic piano', '2022-05-20 15:00:10-09', '2022-05-20 16:00:10-09', 202.20);
 
-INSERT INTO rentings (student_id, instrument_id, start_date) values (1, 2, '2022-04-01 00:00:00-09');
-INSERT INTO rentings (student_id, instrument_id, start_date, end_date) values (2, 2, '2022-04-01 00:00:00-09', '2022-05-15 00:00:00-09');
+INSERT INTO rentings (student_id, instrument_id, start_date, agreed_price) values (1, 2, '2022-04-01 00:00:00-09', 202.02);
+INSERT INTO rentings (student_id, instrument_id, start_date, end_date, agreed_price) values (2, 2, '2022-04-01 00:00:00-09', '2022-05-15 00:00:00-09', 202.02);
 
 INSERT INTO skill_levels (skill_value, skill_level) values (0, 'Beginner');
 INSERT INTO skill_levels (skill_value, skill_level) values (1, 'Intermediate');
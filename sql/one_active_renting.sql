@@ -0,0 +1,7 @@
+-- A student can have at most one active renting of an instrument, so terminating by student and
+-- instrument always finds at most one renting. The trade-off is that a student can no longer rent
+-- two units of the same instrument at once, which the school does not allow in practice anyway.
+-- Run by create.sql and by db::create_one_active_renting_index on existing databases
+CREATE UNIQUE INDEX IF NOT EXISTS "rentings_one_active_per_instrument"
+  ON "rentings" ("student_id", "instrument_id")
+  WHERE "end_date" IS NULL;
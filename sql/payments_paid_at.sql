@@ -0,0 +1,15 @@
+-- When a payment was paid, NULL while it is unpaid. Payments marked paid before the column existed
+-- only have their due date, which is the best known date of them.
+-- Run by create.sql and by db::create_payments_paid_at on existing databases, so every statement
+-- must be safe to run more than once
+ALTER TABLE "payments" ADD COLUMN IF NOT EXISTS "paid_at" timestamptz;
+UPDATE "payments" SET "paid_at" = "due_date" WHERE "paid" AND "paid_at" IS NULL;
+
+DO $$
+BEGIN
+  IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = 'payments_paid_at') THEN
+    ALTER TABLE "payments" ADD CONSTRAINT "payments_paid_at"
+      CHECK ("paid" = ("paid_at" IS NOT NULL));
+  END IF;
+END;
+$$;
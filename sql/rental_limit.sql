@@ -0,0 +1,40 @@
+-- Enforces the max rentals business rule, rent_max_count unless set otherwise, in the database as
+-- well as in the application. Run by create.sql and by db::create_rental_limit_trigger on
+-- existing databases, replacing the trigger if it is already installed
+CREATE OR REPLACE FUNCTION "check_rental_limit"() RETURNS trigger AS $$
+DECLARE
+  max_rentals bigint;
+  current_rentals bigint;
+BEGIN
+  IF NEW.end_date IS NOT NULL THEN
+    RETURN NEW;
+  END IF;
+
+  -- The application sets the name of the rule it checks for the transaction, see db::begin_tx
+  SELECT TRIM(value)::bigint INTO max_rentals FROM business_rules
+    WHERE name = COALESCE(NULLIF(current_setting('sgdb.max_rentals_key', true), ''), 'rent_max_count');
+  IF max_rentals IS NULL THEN
+    RETURN NEW;
+  END IF;
+
+  -- Another session's insert is not visible until it commits, so inserts for the same student
+  -- wait for each other here before counting
+  PERFORM 1 FROM students WHERE student_id = NEW.student_id FOR NO KEY UPDATE;
+
+  SELECT COUNT(*) INTO current_rentals
+    FROM rentings WHERE student_id = NEW.student_id AND end_date IS NULL;
+  IF current_rentals >= max_rentals THEN
+    RAISE EXCEPTION 'Student % already has % active rentals (limit %)',
+        NEW.student_id, current_rentals, max_rentals
+      USING CONSTRAINT = 'rentings_rental_limit',
+            DETAIL = format('%s %s %s', NEW.student_id, current_rentals, max_rentals);
+  END IF;
+
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS "rentings_rental_limit" ON "rentings";
+CREATE TRIGGER "rentings_rental_limit"
+  BEFORE INSERT ON "rentings"
+  FOR EACH ROW EXECUTE FUNCTION "check_rental_limit"();
@@ -0,0 +1,69 @@
+-- Terminated rentings moved out of rentings by db::archive_rentings, keeping their rent_id.
+-- Run by create.sql and by db::create_rentings_archive on existing databases, so every statement
+-- must be safe to run more than once
+CREATE TABLE IF NOT EXISTS "rentings_archive" (
+  "rent_id" int NOT NULL,
+  "student_id" int NOT NULL,
+  "instrument_id" int NOT NULL,
+  "start_date" timestamptz NOT NULL,
+  "end_date" timestamptz NOT NULL,
+  "termination_note" varchar(500),
+  "agreed_price" numeric(10, 2) NOT NULL,
+  "deposit" numeric(10, 2) NOT NULL DEFAULT 0,
+  "archived_at" timestamptz NOT NULL,
+  PRIMARY KEY ("rent_id"),
+  CONSTRAINT "FK_rentings_archive.student_id"
+    FOREIGN KEY ("student_id")
+      REFERENCES "students"("student_id"),
+  CONSTRAINT "FK_rentings_archive.instrument_id"
+    FOREIGN KEY ("instrument_id")
+      REFERENCES "instruments"("instrument_id")
+);
+
+-- Charges, damage and fulfilled reservations reference their renting through rent_id while it is
+-- in rentings and through the archived column once it has been moved to rentings_archive
+ALTER TABLE "rental_charges" ADD COLUMN IF NOT EXISTS "archived_rent_id" int;
+ALTER TABLE "rental_charges" ALTER COLUMN "rent_id" DROP NOT NULL;
+ALTER TABLE "damages" ADD COLUMN IF NOT EXISTS "archived_rent_id" int;
+ALTER TABLE "damages" ALTER COLUMN "rent_id" DROP NOT NULL;
+ALTER TABLE "reservations" ADD COLUMN IF NOT EXISTS "fulfilled_archived_rent_id" int;
+
+-- Databases archived before the archived columns existed have rows pointing at archived rentings
+UPDATE "rental_charges" SET "archived_rent_id" = "rent_id", "rent_id" = NULL
+  WHERE "rent_id" IN (SELECT "rent_id" FROM "rentings_archive");
+UPDATE "damages" SET "archived_rent_id" = "rent_id", "rent_id" = NULL
+  WHERE "rent_id" IN (SELECT "rent_id" FROM "rentings_archive");
+UPDATE "reservations" SET "fulfilled_archived_rent_id" = "fulfilled_rent_id", "fulfilled_rent_id" = NULL
+  WHERE "fulfilled_rent_id" IN (SELECT "rent_id" FROM "rentings_archive");
+
+DO $$
+DECLARE
+  c record;
+BEGIN
+  FOR c IN SELECT * FROM (VALUES
+    ('rental_charges', 'FK_rental_charges.rent_id',
+     'FOREIGN KEY ("rent_id") REFERENCES "rentings"("rent_id")'),
+    ('rental_charges', 'FK_rental_charges.archived_rent_id',
+     'FOREIGN KEY ("archived_rent_id") REFERENCES "rentings_archive"("rent_id")'),
+    ('rental_charges', 'rental_charges_one_renting',
+     'CHECK (num_nonnulls("rent_id", "archived_rent_id") = 1)'),
+    ('damages', 'FK_damages.rent_id',
+     'FOREIGN KEY ("rent_id") REFERENCES "rentings"("rent_id")'),
+    ('damages', 'FK_damages.archived_rent_id',
+     'FOREIGN KEY ("archived_rent_id") REFERENCES "rentings_archive"("rent_id")'),
+    ('damages', 'damages_one_renting',
+     'CHECK (num_nonnulls("rent_id", "archived_rent_id") = 1)'),
+    ('reservations', 'FK_reservations.fulfilled_rent_id',
+     'FOREIGN KEY ("fulfilled_rent_id") REFERENCES "rentings"("rent_id")'),
+    ('reservations', 'FK_reservations.fulfilled_archived_rent_id',
+     'FOREIGN KEY ("fulfilled_archived_rent_id") REFERENCES "rentings_archive"("rent_id")'),
+    ('reservations', 'reservations_one_fulfilled_renting',
+     'CHECK (num_nonnulls("fulfilled_rent_id", "fulfilled_archived_rent_id") <= 1)')
+  ) AS t("tbl", "name", "def")
+  LOOP
+    IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = c.name) THEN
+      EXECUTE format('ALTER TABLE %I ADD CONSTRAINT %I %s', c.tbl, c.name, c.def);
+    END IF;
+  END LOOP;
+END;
+$$;
@@ -1,17 +1,88 @@
-use sqlx::{PgPool, Postgres, Transaction};
-use std::{fmt, num::ParseIntError};
+use sqlx::{
+    postgres::PgDatabaseError,
+    types::{time::OffsetDateTime, BigDecimal},
+    Postgres, Transaction,
+};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    env, fmt,
+    fs::{self, File, OpenOptions},
+    io::BufWriter,
+    num::ParseIntError,
+    ops::{Deref, DerefMut},
+    path::Path,
+    process::ExitCode,
+    time::{Duration, Instant},
+};
+use time::{Date, Month};
 
 use crate::{
-    db::{self, Renting},
-    repl::{self},
+    db::{
+        self, AuditEntry, Charge, DamageEntry, DbError, DbPools, InstrumentDto, InstrumentType,
+        Lesson, LockScope, LockWait, Locking, Maintenance, MonthlyActivity, RentalCost, Renting,
+        Reservation, RevenueRow, RuleError, ShelfStock, SqlOutput, Student, TypeUsage,
+    },
+    repl::{self, PromptStyle, ReplMode},
 };
 
-/// Controller struct which holds a DB connection pool and can execute command and run a repl
+/// How long the instrument types loaded by [`Command::Warmup`] are used
+pub const TYPE_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+const FK_VIOLATION: &str = "23503";
+/// Appended to the path of an export while it is being written, see [`export_file`]
+const PARTIAL_SUFFIX: &str = ".partial";
+const UNIQUE_VIOLATION: &str = "23505";
+const READ_ONLY_TRANSACTION: &str = "25006";
+const IN_FAILED_TRANSACTION: &str = "25P02";
+const INVALID_SAVEPOINT: &str = "3B001";
+const RAISE_EXCEPTION: &str = "P0001";
+const RENTAL_LIMIT: &str = "rentings_rental_limit";
+const FK_RENTINGS_INSTRUMENT: &str = "FK_rentings.instrument_id";
+const FK_RENTINGS_STUDENT: &str = "FK_rentings.student_id";
+const ONE_ACTIVE_RENTING: &str = "rentings_one_active_per_instrument";
+/// The school's timezone, used to decide which day something happened. The database applies its
+/// summer time
+const SCHOOL_TIME_ZONE: &str = "Europe/Stockholm";
+const TXN_WARN_KEY: &str = "SGDB_TXN_WARN_SECS";
+/// The savepoint the audit log is written under, see [`Controller`]`.audit()`
+const AUDIT_SAVEPOINT: &str = "sgdb_audit";
+const SESSION_KEY: &str = "SGDB_SESSION";
+const MAX_RENTALS_ENV_KEY: &str = "SGDB_MAX_RENTALS_KEY";
+const TXN_WARN_DEFAULT_SECS: u64 = 300;
+const TXN_WARN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Controller struct which holds the DB connection pools and can execute command and run a repl
 pub struct Controller<'a> {
-    /// The pool of connections to use
-    pool: PgPool,
+    /// The pools of connections to use, read only work goes to the replica if there is one
+    pools: DbPools,
     /// The transaction to execute with, created from pool
     transaction: Option<Transaction<'a, Postgres>>,
+    /// When the current transaction was begun, `None` if there is no transaction
+    transaction_start: Option<Instant>,
+    /// How long a transaction may be open before a warning is printed
+    txn_warn_after: Duration,
+    /// When the last long transaction warning was printed, used to limit the warnings
+    last_txn_warning: Option<Instant>,
+    /// Who is executing commands, written to the audit log
+    session: String,
+    /// If commands run without an open transaction get their own, see [`Command::SetAutocommit`]
+    autocommit: bool,
+    /// If locking rentings waits for other sessions, see [`Command::SetLockWait`]
+    lock_wait: LockWait,
+    /// How rentings are locked against other sessions, see [`Command::SetLocking`]
+    locking: Locking,
+    /// If transactions are begun read only, see [`Command::SetReadOnly`]
+    read_only: bool,
+    /// If [`Command::Sql`] may be run, off unless enabled with [`Controller::allow_sql`]
+    allow_sql: bool,
+    /// The name of the business rule holding the max number of active rentals per student
+    max_rentals_key: String,
+    /// The business rules read in the open transaction, cleared when it ends or rules may have
+    /// been written
+    rules: db::RuleCache,
+    /// The instrument types loaded by [`Command::Warmup`] and when, see
+    /// [`Controller::cached_instrument_types`]
+    type_cache: Option<(Instant, Vec<InstrumentType>)>,
 }
 
 /// The commands available to be executed by the controller
@@ -19,20 +90,686 @@ pub struct Controller<'a> {
 /// Used by running [`Controller`]`.execute()` and passing the command
 #[derive(Debug, PartialEq, Eq)]
 pub enum Command {
-    /// Begin new transaction
-    Begin,
+    /// Add a new instrument of an existing type
+    AddInstrument {
+        instrument_type: String,
+        brand: String,
+        model: String,
+        price: String,
+        count: String,
+    },
+    /// Attach a note to a specific rent_id, which may also be ended or not exist
+    AddNote(String, String),
+    /// Move the rentings which ended more than this many months ago to the archive
+    Archive(u32),
+    /// List the latest entries of the audit log, works without a transaction
+    Audit(u32),
+    /// Mark a student as inactive so they can no longer rent, refused if they have active rentals
+    Deactivate(String),
+    /// Run commands outside of a transaction in their own transaction which is committed if they
+    /// succeed, see [`Command::needs_transaction`]
+    SetAutocommit(bool),
+    /// Decide if commands wait for rentings locked by another session or fail at once with
+    /// [`ControlError::ResourceBusy`]
+    SetLockWait(LockWait),
+    /// Decide how rentings are locked against other sessions, every session must use the same
+    /// strategy, see [`Locking`]
+    SetLocking(Locking),
+    /// Begin every transaction read only, also those begun by autocommit, so commands which
+    /// write fail with [`ControlError::ReadOnlyTransaction`]
+    SetReadOnly(bool),
+    /// Run all the checks of a command without changing anything, see
+    /// [`Command::supports_dry_run`]
+    DryRun(Box<Command>),
+    /// Book a student on a lesson if it has a free place
+    Enroll { student: i32, lesson: i32 },
+    /// Write all active rentings to a timestamped CSV file in the current directory
+    Backup,
+    /// Begin new transaction, which fails if one is already open unless `force` is set, in which
+    /// case the open transaction is rolled back first
+    Begin { force: bool },
+    /// Begin new read only transaction, in which any command that writes fails
+    BeginReadOnly,
+    /// Forget the instrument types loaded by [`Command::Warmup`] and the business rules read in
+    /// the open transaction
+    ClearCache,
     /// Commit current transaction
     Commit,
-    /// List optinally a specific type
-    List(Option<String>),
+    /// List all damage recorded when rentings were terminated
+    Damages,
+    /// Write the rentals or the inventory to the file at `path`, see [`ExportKind`] and
+    /// [`ExportFormat`]
+    Export {
+        what: ExportKind,
+        format: ExportFormat,
+        path: String,
+    },
+    /// Find students whose name contains the given text, ignoring case
+    FindStudent(String),
+    /// Rent the instrument of a specific reservation_id to the student who reserved it
+    Fulfil(String),
+    /// List the lessons an instructor teaches, those which have not started yet or those starting
+    /// between two dates given as YYYY-MM-DD, and how many they teach this month
+    Instructor {
+        instructor: i32,
+        range: Option<(String, String)>,
+    },
+    /// List all instrument types and their ids, works without a transaction
+    InstrumentTypes,
+    /// Create the invoice of a student for a month given as YYYY-MM
+    Invoice { student: String, year_month: String },
+    /// List every rental start, termination and payment of a student in order, with a running
+    /// balance of what they owe
+    Ledger(i32),
+    /// List scheduled lessons, see [`LessonFilter`]
+    Lessons(LessonFilter),
+    /// List instruments, see [`ListFilter`]
+    List(ListFilter),
+    /// List every instrument, of the given type if there is one, including those without any
+    /// unit available
+    ListAll(Option<String>),
+    /// List the units of every instrument currently out for repair
+    MaintenanceList,
+    /// Return the unit of an instrument which has been out for repair the longest
+    MaintenanceOff(String),
+    /// Send a unit of an instrument out for repair with a note of what is being repaired
+    MaintenanceOn(String, String),
+    /// Show the price a user would pay to rent an instrument without renting it
+    Quote(String, String),
+    /// Compare the units of an instrument expected in storage with the number counted
+    Reconcile(String, String),
+    /// List the units of every instrument expected in storage
+    ReconcileReport,
     /// Rent for a user an instrument
     Rent(String, String),
-    /// Roll back current transaction
-    Rollback,
-    /// Terminate a specific rent_id
-    Terminate(String),
-    /// Try to terminate a rent by user and instrument ids
-    TryTerminate(String, String),
+    /// List the active reservations in the order they were made, of one instrument or of all
+    Reservations(Option<i32>),
+    /// Roll back the open transaction if there is one and begin a new one, to start over
+    Reset,
+    /// Reserve for a user an instrument which is rented out
+    Reserve(String, String),
+    /// Show the rental revenue per month and instrument type between two dates given as
+    /// YYYY-MM-DD, both inclusive, counting archived rentings if `include_archived` is set
+    Revenue {
+        from: String,
+        to: String,
+        include_archived: bool,
+    },
+    /// List the charges recorded for a student
+    Payments(i32),
+    /// Release a savepoint, keeping the work done since it
+    ReleaseSavepoint(String),
+    /// Roll back current transaction, or only to the given savepoint if one is named
+    Rollback(Option<String>),
+    /// Roll back the work done since the given savepoint, keeping the savepoint
+    RollbackTo(String),
+    /// Create a savepoint in the current transaction to roll back to later
+    Savepoint(String),
+    /// Show instrument and rental statistics, see [`StatsKind`]
+    Stats(StatsKind),
+    /// Show the current monthly cost of a student's active rentings
+    StudentCost(i32),
+    /// Terminate a specific rent_id, recording damage to the instrument if there is any
+    Terminate(String, Option<Damage>),
+    /// Terminate a specific rent_id and record a note with the reason
+    TerminateWithNote(String, String),
+    /// Try to terminate a rent by user and instrument ids, recording damage to the instrument if
+    /// there is any
+    TryTerminate(String, String, Option<Damage>),
+    /// Try to cancel the reservation of a user for an instrument, failing with
+    /// [`ControlError::UnreserveMultiple`] if there are several
+    TryUnreserve(String, String),
+    /// Remove the booking of a student on a lesson, freeing the place
+    Unenroll { student: i32, lesson: i32 },
+    /// Run a single raw SQL statement in the open transaction, only if allowed with
+    /// [`Controller::allow_sql`]
+    Sql(String),
+    /// Cancel a specific reservation_id
+    Unreserve(String),
+    /// Set up a connection and load the instrument types, so the first command does not wait
+    Warmup,
+}
+
+impl Command {
+    /// Returns if the command changes data in the database, and so is written to the audit log
+    pub const fn is_mutating(&self) -> bool {
+        matches!(
+            self,
+            Self::AddInstrument { .. }
+                | Self::AddNote(..)
+                | Self::Archive(_)
+                | Self::Deactivate(_)
+                | Self::Enroll { .. }
+                | Self::Fulfil(_)
+                | Self::MaintenanceOff(_)
+                | Self::MaintenanceOn(..)
+                | Self::Rent(..)
+                | Self::Reserve(..)
+                | Self::Sql(_)
+                | Self::Terminate(..)
+                | Self::TerminateWithNote(..)
+                | Self::TryTerminate(..)
+                | Self::TryUnreserve(..)
+                | Self::Unenroll { .. }
+                | Self::Unreserve(_)
+        )
+    }
+
+    /// Returns if the command runs in the open transaction, and so fails without one unless
+    /// autocommit is on
+    ///
+    /// Commands which only read use the open transaction if there is one, and otherwise run on
+    /// their own
+    pub const fn needs_transaction(&self) -> bool {
+        !matches!(
+            self,
+            Self::Audit(_)
+                | Self::Begin { .. }
+                | Self::BeginReadOnly
+                | Self::ClearCache
+                | Self::Commit
+                | Self::Damages
+                | Self::Export { .. }
+                | Self::FindStudent(_)
+                | Self::Instructor { .. }
+                | Self::InstrumentTypes
+                | Self::Invoice { .. }
+                | Self::Ledger(_)
+                | Self::Lessons(_)
+                | Self::List(_)
+                | Self::ListAll(_)
+                | Self::MaintenanceList
+                | Self::Payments(_)
+                | Self::Quote(..)
+                | Self::Reconcile(..)
+                | Self::ReconcileReport
+                | Self::Reservations(_)
+                | Self::Reset
+                | Self::Revenue { .. }
+                | Self::StudentCost(_)
+                | Self::ReleaseSavepoint(_)
+                | Self::Rollback(_)
+                | Self::RollbackTo(_)
+                | Self::Savepoint(_)
+                | Self::SetAutocommit(_)
+                | Self::SetLockWait(_)
+                | Self::SetLocking(_)
+                | Self::SetReadOnly(_)
+                | Self::Stats(_)
+                | Self::Warmup
+        )
+    }
+
+    /// Returns if the command can be wrapped in [`Command::DryRun`]
+    pub const fn supports_dry_run(&self) -> bool {
+        matches!(
+            self,
+            Self::AddInstrument { .. }
+                | Self::MaintenanceOff(_)
+                | Self::MaintenanceOn(..)
+                | Self::Rent(..)
+                | Self::Terminate(..)
+                | Self::TerminateWithNote(..)
+                | Self::TryTerminate(..)
+        )
+    }
+}
+
+/// Displays the command as it would be typed, commands the parser has no text for are shown in
+/// the same style
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AddInstrument {
+                instrument_type,
+                brand,
+                model,
+                price,
+                count,
+            } => write!(
+                f,
+                "add {} {} {} {price} {count}",
+                quoted(instrument_type),
+                quoted(brand),
+                quoted(model)
+            ),
+            Self::AddNote(id, note) => write!(f, "note {id} {}", quoted(note)),
+            Self::Archive(months) => write!(f, "archive {months}"),
+            Self::Audit(n) => write!(f, "audit {n}"),
+            Self::Backup => write!(f, "backup"),
+            Self::Begin { force: false } => write!(f, "begin"),
+            Self::Begin { force: true } => write!(f, "fbegin"),
+            Self::BeginReadOnly => write!(f, "begin --read-only"),
+            Self::Commit => write!(f, "commit"),
+            Self::Damages => write!(f, "damages"),
+            Self::Deactivate(u) => write!(f, "deact {u}"),
+            Self::DryRun(c) => write!(f, "{c} --dry-run"),
+            Self::Enroll { student, lesson } => write!(f, "enroll {student} {lesson}"),
+            Self::Export { what, format, path } => {
+                let flags = match format {
+                    ExportFormat::Csv => "",
+                    ExportFormat::Json { pretty: true } => " --json",
+                    ExportFormat::Json { pretty: false } => " --json --compact",
+                };
+                write!(f, "export {what}{flags} {}", quoted(path))
+            }
+            Self::FindStudent(name) => write!(f, "find {name}"),
+            Self::Fulfil(id) => write!(f, "fulfil {id}"),
+            Self::Instructor {
+                instructor,
+                range: Some((from, to)),
+            } => write!(f, "instructor {instructor} {from} {to}"),
+            Self::Instructor {
+                instructor,
+                range: None,
+            } => write!(f, "instructor {instructor}"),
+            Self::InstrumentTypes => write!(f, "types"),
+            Self::Invoice {
+                student,
+                year_month,
+            } => write!(f, "invoice {student} {year_month}"),
+            Self::List(ListFilter {
+                rented_by: Some(s), ..
+            }) => write!(f, "list --rented-by {s}"),
+            Self::List(ListFilter {
+                instrument_type,
+                limit,
+                offset,
+                ..
+            }) => {
+                write!(f, "list")?;
+                if let Some(t) = instrument_type {
+                    write!(f, " {t}")?;
+                }
+                if let Some(l) = limit {
+                    write!(f, " --limit {l}")?;
+                }
+                if let Some(o) = offset {
+                    write!(f, " --offset {o}")?;
+                }
+                Ok(())
+            }
+            Self::ListAll(Some(t)) => write!(f, "listall {t}"),
+            Self::ListAll(None) => write!(f, "listall"),
+            Self::MaintenanceList => write!(f, "maintenance list"),
+            Self::MaintenanceOff(i) => write!(f, "maintenance {i} off"),
+            Self::MaintenanceOn(i, note) => write!(f, "maintenance {i} on {}", quoted(note)),
+            Self::Payments(u) => write!(f, "payments {u}"),
+            Self::Ledger(u) => write!(f, "ledger {u}"),
+            Self::Lessons(LessonFilter {
+                student: Some(u), ..
+            }) => write!(f, "lessons student {u}"),
+            Self::Lessons(LessonFilter { day: Some(d), .. }) => write!(f, "lessons {d}"),
+            Self::Lessons(_) => write!(f, "lessons"),
+            Self::Quote(u, i) => write!(f, "quote {u} {i}"),
+            Self::Reconcile(i, n) => write!(f, "reconcile {i} {n}"),
+            Self::ReconcileReport => write!(f, "reconcile report"),
+            Self::Rent(u, i) => write!(f, "rent {u} {i}"),
+            Self::Reservations(Some(i)) => write!(f, "reservations {i}"),
+            Self::Reservations(None) => write!(f, "reservations"),
+            Self::Reserve(u, i) => write!(f, "reserve {u} {i}"),
+            Self::Reset => write!(f, "reset"),
+            Self::ClearCache => write!(f, "clearcache"),
+            Self::Warmup => write!(f, "warmup"),
+            Self::Revenue {
+                from,
+                to,
+                include_archived,
+            } => write!(f, "revenue {from} {to}{}", archived(*include_archived)),
+            Self::Rollback(Some(sp)) => write!(f, "rollback {sp}"),
+            Self::Rollback(None) => write!(f, "rollback"),
+            Self::RollbackTo(sp) => write!(f, "rollback-to {sp}"),
+            Self::ReleaseSavepoint(sp) => write!(f, "release {sp}"),
+            Self::Savepoint(sp) => write!(f, "save {sp}"),
+            Self::SetAutocommit(true) => write!(f, "set autocommit on"),
+            Self::SetAutocommit(false) => write!(f, "set autocommit off"),
+            Self::SetLockWait(LockWait::Block) => write!(f, "set lock-wait block"),
+            Self::SetLockWait(LockWait::Fail) => write!(f, "set lock-wait fail"),
+            Self::SetLocking(Locking::Rows) => write!(f, "set locking rows"),
+            Self::SetLocking(Locking::Advisory) => write!(f, "set locking advisory"),
+            Self::SetReadOnly(true) => write!(f, "set read-only on"),
+            Self::SetReadOnly(false) => write!(f, "set read-only off"),
+            Self::Stats(StatsKind::Inventory) => write!(f, "stats"),
+            Self::Stats(StatsKind::Types) => write!(f, "stats types"),
+            Self::Stats(StatsKind::Lifetime) => write!(f, "stats lifetime"),
+            Self::Stats(StatsKind::Monthly {
+                months,
+                include_archived,
+            }) => write!(f, "stats monthly {months}{}", archived(*include_archived)),
+            Self::Sql(sql) => write!(f, "sql {sql}"),
+            Self::StudentCost(u) => write!(f, "cost {u}"),
+            Self::Terminate(id, d) => write!(f, "terminate rent {id}{}", damaged(d.as_ref())),
+            Self::TerminateWithNote(id, note) => write!(f, "tn {id} {}", quoted(note)),
+            Self::TryTerminate(u, i, d) => write!(f, "terminate {u} {i}{}", damaged(d.as_ref())),
+            Self::TryUnreserve(u, i) => write!(f, "unreserve {u} {i}"),
+            Self::Unenroll { student, lesson } => write!(f, "unenroll {student} {lesson}"),
+            Self::Unreserve(id) => write!(f, "unreserve {id}"),
+        }
+    }
+}
+
+/// Returns the flag to append to a report command which counts archived rentings
+const fn archived(include_archived: bool) -> &'static str {
+    if include_archived {
+        " --include-archived"
+    } else {
+        ""
+    }
+}
+
+/// Wraps `s` in double quotes if it contains whitespace or quotes, so it reads as a single
+/// argument, escaping the quotes and backslashes in it
+fn quoted(s: &str) -> Cow<'_, str> {
+    if s.contains(|c: char| c.is_whitespace() || c == '"') {
+        Cow::Owned(format!(
+            "\"{}\"",
+            s.replace('\\', "\\\\").replace('"', "\\\"")
+        ))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+/// Formats the `--damaged` argument of a terminate command, empty if there is no damage
+fn damaged(damage: Option<&Damage>) -> String {
+    match damage {
+        Some(Damage {
+            note,
+            amount: Some(a),
+        }) => format!(" --damaged {} {a}", quoted(note)),
+        Some(Damage { note, amount: None }) => format!(" --damaged {}", quoted(note)),
+        None => String::new(),
+    }
+}
+
+/// Filters which narrow down the instruments listed by [`Command::List`]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ListFilter {
+    /// Only list instruments of this type
+    pub instrument_type: Option<String>,
+    /// Only list instruments currently rented by this student
+    pub rented_by: Option<i32>,
+    /// List at most this many instruments, not used with `rented_by`
+    pub limit: Option<u32>,
+    /// Skip this many instruments before listing, not used with `rented_by`
+    pub offset: Option<u32>,
+}
+
+/// Filters which narrow down the lessons listed by [`Command::Lessons`]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct LessonFilter {
+    /// Only list lessons starting on this day, given as YYYY-MM-DD
+    pub day: Option<String>,
+    /// Only list lessons booked by this student
+    pub student: Option<i32>,
+}
+
+/// Damage to an instrument noted when terminating its renting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Damage {
+    /// What was damaged, e.g. "cracked body"
+    pub note: String,
+    /// The amount to charge for the damage, nothing is charged if `None`
+    pub amount: Option<String>,
+}
+
+/// Which statistics [`Command::Stats`] shows
+#[derive(Debug, Default, PartialEq, Eq)]
+pub enum StatsKind {
+    /// The number of instruments, units and active rentals per instrument type
+    #[default]
+    Inventory,
+    /// The active rentals, utilisation and average rental age per instrument type
+    Types,
+    /// The rentings started and ended per month over this many months, counting archived
+    /// rentings if `include_archived` is set
+    Monthly { months: u32, include_archived: bool },
+    /// The number of rentals of each instrument over its whole lifetime
+    Lifetime,
+}
+
+/// What [`Command::Export`] writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportKind {
+    /// The active rentings with the student and instrument they concern
+    Rentals,
+    /// Every instrument with its availability
+    Inventory,
+}
+
+impl fmt::Display for ExportKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rentals => write!(f, "rentals"),
+            Self::Inventory => write!(f, "inventory"),
+        }
+    }
+}
+
+/// How [`Command::Export`] writes the rows
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// CSV with a header row
+    #[default]
+    Csv,
+    /// A JSON object with the time it was generated and an array of the rows, indented if
+    /// `pretty` is set
+    Json { pretty: bool },
+}
+
+/// The rentals of one instrument over its whole lifetime, see [`StatsKind::Lifetime`]
+#[derive(Debug, PartialEq, Eq)]
+pub struct LifetimeRentals {
+    /// The instrument, with the units available now
+    pub instrument: InstrumentDto,
+    /// The number of rentings which are still active
+    pub active: i64,
+    /// The number of rentings ever made, both active and ended
+    pub total: i64,
+}
+
+impl fmt::Display for LifetimeRentals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let i = &self.instrument;
+        write!(
+            f,
+            "{:<4} {:<10} {:<28} {:>8} {:>8}",
+            i.id,
+            i.type_name,
+            format!("{} {}", i.brand, i.model),
+            self.active,
+            self.total
+        )
+    }
+}
+
+/// One line of a monthly invoice, the prorated cost of a single renting
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvoiceLine {
+    /// The renting which is billed
+    pub rent_id: i32,
+    /// The number of days of the month the renting was active
+    pub days: i64,
+    /// The number of days in the month
+    pub days_in_month: i64,
+    /// The full monthly price of the renting
+    pub price: BigDecimal,
+    /// The prorated amount to bill
+    pub amount: BigDecimal,
+}
+
+impl fmt::Display for InvoiceLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Renting {}: {}/{} days of {} => {}",
+            self.rent_id,
+            self.days,
+            self.days_in_month,
+            db::format_currency(&self.price),
+            db::format_currency(&self.amount)
+        )
+    }
+}
+
+/// What happened in one entry of a student's ledger
+#[derive(Debug, PartialEq, Eq)]
+pub enum LedgerEvent {
+    /// A renting started, its deposit is held and not part of the balance
+    RentalStarted {
+        rent_id: i32,
+        instrument: String,
+        price: BigDecimal,
+        deposit: BigDecimal,
+    },
+    /// A renting ended, with the final charge including the late fee, the damage charge and
+    /// the part of the deposit given back
+    RentalEnded {
+        rent_id: i32,
+        charge: BigDecimal,
+        late_fee: BigDecimal,
+        damage: BigDecimal,
+        deposit_refund: BigDecimal,
+    },
+    /// A lesson payment fell due
+    LessonCharge {
+        payment_id: i32,
+        payment_for: String,
+    },
+    /// A lesson payment was paid by the student
+    Payment {
+        payment_id: i32,
+        payment_for: String,
+    },
+}
+
+impl LedgerEvent {
+    /// Orders events which happened at the same time, starts before ends before charges before
+    /// payments and then by id
+    const fn sort_key(&self) -> (u8, i32) {
+        match self {
+            Self::RentalStarted { rent_id, .. } => (0, *rent_id),
+            Self::RentalEnded { rent_id, .. } => (1, *rent_id),
+            Self::LessonCharge { payment_id, .. } => (2, *payment_id),
+            Self::Payment { payment_id, .. } => (3, *payment_id),
+        }
+    }
+}
+
+impl fmt::Display for LedgerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RentalStarted {
+                rent_id,
+                instrument,
+                price,
+                deposit,
+            } => write!(
+                f,
+                "Rent #{rent_id} started, {instrument} at {}/month, deposit {}",
+                db::format_currency(price),
+                db::format_currency(deposit)
+            ),
+            Self::RentalEnded {
+                rent_id,
+                charge,
+                late_fee,
+                damage,
+                deposit_refund,
+            } => write!(
+                f,
+                "Rent #{rent_id} ended, final charge {} (late fee {}), damage {}, deposit refund {}",
+                db::format_currency(charge),
+                db::format_currency(late_fee),
+                db::format_currency(damage),
+                db::format_currency(deposit_refund)
+            ),
+            Self::LessonCharge {
+                payment_id,
+                payment_for,
+            } => write!(f, "Payment #{payment_id} due for {payment_for}"),
+            Self::Payment {
+                payment_id,
+                payment_for,
+            } => write!(f, "Payment #{payment_id} for {payment_for}"),
+        }
+    }
+}
+
+/// One entry of a student's ledger
+#[derive(Debug, PartialEq, Eq)]
+pub struct LedgerEntry {
+    /// When it happened
+    pub at: OffsetDateTime,
+    /// What happened
+    pub event: LedgerEvent,
+    /// How much the entry changed what the student owes, negative for payments and withheld
+    /// deposits
+    pub amount: BigDecimal,
+    /// What the student owes after the entry
+    pub balance: BigDecimal,
+}
+
+impl fmt::Display for LedgerEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}  {:>10} {:>10}  {}",
+            db::format_timestamp(self.at),
+            db::format_currency(&self.amount),
+            db::format_currency(&self.balance),
+            self.event
+        )
+    }
+}
+
+/// The charge recorded when a renting is terminated
+#[derive(Debug, PartialEq, Eq)]
+pub struct TerminationCharge {
+    /// The total amount to collect, the prorated cost of the final period plus any late fee
+    pub amount: BigDecimal,
+    /// The fee for returning the instrument after the lease ended, zero if returned on time
+    pub late_fee: BigDecimal,
+    /// The amount charged for damage to the instrument, zero if none was recorded
+    pub damage: BigDecimal,
+    /// The part of the deposit to give back to the student, i.e. the deposit less the late fee
+    /// and the damage charge
+    pub deposit_refund: BigDecimal,
+}
+
+/// The result of [`Controller`]`.execute()` with when the command completed and how long it took
+#[derive(Debug, PartialEq, Eq)]
+pub struct ControlResultWithMeta {
+    /// The result of the command
+    pub result: ControlResult,
+    /// When the command completed, in UTC
+    pub executed_at: OffsetDateTime,
+    /// How long the command took to run in microseconds
+    pub duration_us: u64,
+    /// Why the command could not be written to the audit log, it ran all the same
+    pub audit_warning: Option<String>,
+}
+
+impl fmt::Display for ControlResultWithMeta {
+    /// Displays the metadata, the result is printed by the repl
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{:.1}ms at {}]",
+            self.duration_us as f64 / 1000.0,
+            self.executed_at
+        )
+    }
+}
+
+/// How [`Controller`]`.execute_all()` handles a failing command
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BatchOptions {
+    /// Stop at the first failing command instead of continuing with the rest
+    pub stop_on_error: bool,
+    /// Run the batch in its own transaction which is committed if every command succeeds and
+    /// rolled back at the first failure, implies `stop_on_error`
+    pub atomic: bool,
 }
 
 /// The results returned by [`Controller`]`.execute()`
@@ -43,36 +780,376 @@ pub enum Command {
 /// For information on each variant see [`Command`]
 #[derive(Debug, PartialEq, Eq)]
 pub enum ControlResult {
-    Begin,
+    AddInstrument(i32),
+    /// The number of rentings moved to the archive
+    Archived(u64),
+    Autocommit(bool),
+    Backup {
+        file: String,
+        rows: usize,
+    },
+    /// `true` if an open transaction was rolled back before beginning, see [`Command::Begin`]
+    Begin(bool),
+    CacheCleared,
     Commit,
-    List(Vec<String>),
-    Rent(u64),
-    Rollback,
-    Terminate(u64),
-    TryTerminate(u64),
+    Damages(Vec<DamageEntry>),
+    Deactivated(i32),
+    Export {
+        what: ExportKind,
+        path: String,
+        rows: usize,
+    },
+    /// What a command run with [`Command::DryRun`] would have done
+    DryRun(String),
+    Enrolled {
+        student: i32,
+        lesson: i32,
+        /// The places left on the lesson after the booking
+        free_places: usize,
+    },
+    FindStudent(Vec<Student>),
+    Fulfilled {
+        reservation_id: i32,
+        rent_id: i32,
+        list_price: BigDecimal,
+        price: BigDecimal,
+        deposit: BigDecimal,
+    },
+    Instructor {
+        instructor: i32,
+        /// The days the lessons were listed for, `None` if the upcoming lessons were listed
+        range: Option<(Date, Date)>,
+        /// The lessons ordered by start
+        lessons: Vec<Lesson>,
+        /// How many lessons the instructor teaches this month
+        this_month: i64,
+    },
+    InstrumentTypes(Vec<InstrumentType>),
+    Invoice {
+        lines: Vec<InvoiceLine>,
+        total: BigDecimal,
+    },
+    List(Vec<InstrumentDto>),
+    /// Every instrument listed by [`Command::ListAll`], including those without a unit available
+    ListAll(Vec<InstrumentDto>),
+    /// A page of the instruments listed by [`Command::List`] with a limit or offset
+    ListPage {
+        instruments: Vec<InstrumentDto>,
+        /// How many instruments come before the page
+        offset: u32,
+        /// How many instruments there are on all pages
+        total: i64,
+    },
+    Audit(Vec<AuditEntry>),
+    Maintenance(Vec<Maintenance>),
+    NoteAdded {
+        note_id: i32,
+        rent_id: i32,
+        /// If the renting is active, `None` if there is no renting with the id
+        active: Option<bool>,
+    },
+    Ledger {
+        /// The entries oldest first
+        entries: Vec<LedgerEntry>,
+        /// What the student owes after the last entry, zero if there are no entries
+        balance: BigDecimal,
+    },
+    Lessons {
+        /// The day the lessons were listed for, `None` if lessons on all days were listed
+        day: Option<Date>,
+        /// The student whose bookings were listed, `None` if all lessons were listed
+        student: Option<i32>,
+        /// The lessons ordered by start
+        lessons: Vec<Lesson>,
+    },
+    /// The lock wait policy set by [`Command::SetLockWait`]
+    LockWait(LockWait),
+    /// The locking strategy set by [`Command::SetLocking`]
+    Locking(Locking),
+    MaintenanceEnded(i32),
+    MaintenanceStarted(i32),
+    Payments(Vec<Charge>),
+    Quote {
+        list_price: BigDecimal,
+        price: BigDecimal,
+    },
+    /// If transactions are begun read only, set by [`Command::SetReadOnly`]
+    ReadOnly(bool),
+    Reconcile {
+        stock: ShelfStock,
+        counted: i64,
+    },
+    ReconcileReport(Vec<ShelfStock>),
+    Rent {
+        rent_id: i32,
+        start_date: OffsetDateTime,
+        list_price: BigDecimal,
+        price: BigDecimal,
+        deposit: BigDecimal,
+        /// The number of active reservations of the instrument made before the student's own
+        reserved_ahead: i64,
+    },
+    Reservations(Vec<Reservation>),
+    Reserved(i32),
+    /// The open transaction, if any, was rolled back and a new one begun
+    Reset,
+    Revenue {
+        rows: Vec<RevenueRow>,
+        total: BigDecimal,
+    },
+    Released(String),
+    /// `true` if the whole transaction was rolled back, `false` if only to a savepoint
+    Rollback(bool),
+    Savepoint(String),
+    Sql(SqlOutput),
+    Stats(Vec<String>),
+    StatsMonthly(Vec<MonthlyActivity>),
+    StatsTypes(Vec<TypeUsage>),
+    StatsLifetime(Vec<LifetimeRentals>),
+    StudentCost {
+        rentals: Vec<RentalCost>,
+        total: BigDecimal,
+    },
+    Terminate {
+        /// The terminated renting
+        renting: Renting,
+        charge: Option<TerminationCharge>,
+        /// The active reservations of the returned instrument, front of the queue first
+        waiting: Vec<Reservation>,
+    },
+    TryTerminate {
+        /// The terminated renting
+        renting: Renting,
+        charge: Option<TerminationCharge>,
+        /// The active reservations of the returned instrument, front of the queue first
+        waiting: Vec<Reservation>,
+    },
+    Unenrolled {
+        student: i32,
+        lesson: i32,
+        /// The places left on the lesson after the booking was removed
+        free_places: usize,
+    },
+    Unreserved(i32),
+    Warmup {
+        /// The number of instrument types cached
+        types_loaded: usize,
+    },
 }
 
 /// The errors returned by [`Controller`]`.execute()`
 #[derive(Debug, PartialEq, Eq)]
 pub enum ControlError {
+    /// The student is already booked on the lesson, contains the student and lesson ids
+    AlreadyEnrolled(i32, i32),
+    /// The student already has an active renting of the instrument, contains the student and
+    /// instrument ids
+    AlreadyRented(i32, i32),
+    /// The student already has an active reservation of the instrument, contains the student and
+    /// instrument ids
+    AlreadyReserved(i32, i32),
+    /// A business rule has a value which can not be used, a configuration problem
+    BadBusinessRule { name: String, value: String },
+    /// The commit failed and the transaction is known to be rolled back, contains the reason
+    CommitRolledBack(String),
+    /// The connection was lost during the commit and it is unknown if the transaction was
+    /// committed, contains the error
+    CommitUncertain(String),
     /// If another kind of error, e.g. [`sqlx::Error`] was returned then this contains the
     /// strinigified version of that error
     Converted(String),
-    /// There are multiple rentings which could be terminated based on user and instrument
-    TerminateMultiple(Vec<Renting>),
+    /// The command can not be dry run, contains the command
+    DryRunUnsupported(String),
+    /// There is no instructor with the given id
+    InstructorNotFound(i32),
+    /// There is no instrument with the given id
+    InstrumentNotFound(i32),
+    /// Every unit of the instrument is rented out
+    InstrumentUnavailable(i32),
+    /// Every unit of the instrument is rented out or in maintenance, with the note of the oldest
+    /// maintenance
+    InMaintenance { instrument: i32, note: String },
+    /// Every place of the lesson is booked
+    LessonFull {
+        lesson: i32,
+        max: usize,
+        booked: usize,
+    },
+    /// There is no lesson with the given id
+    LessonNotFound(i32),
+    /// The student is not booked on the lesson, contains the student and lesson ids
+    NotEnrolled(i32, i32),
+    /// No unit of the instrument is in maintenance
+    NotInMaintenance(i32),
+    /// A command tried to write in a read only transaction
+    ReadOnlyTransaction,
+    /// The renting with the given id has already been terminated
+    RentingAlreadyTerminated(i32),
+    /// The rentings are locked by another session and [`Command::SetLockWait`] is set to fail
+    ResourceBusy,
+    /// The reservation was already cancelled or fulfilled
+    ReservationNotActive(i32),
+    /// There is no reservation with the given id
+    ReservationNotFound(i32),
+    /// Rolling back to the savepoint `name` after `error` failed with `cleanup`, see
+    /// [`Controller::with_savepoint`]
+    SavepointCleanupFailed {
+        name: String,
+        error: Box<ControlError>,
+        cleanup: Box<ControlError>,
+    },
+    /// There is no savepoint with the given name in the current transaction, which is aborted
+    SavepointNotFound(String),
+    /// There is no student whose name contains the given text
+    StudentNameNotFound(String),
+    /// The student has been deactivated and can not rent
+    StudentInactive(i32),
+    /// There is no student with the given id
+    StudentNotFound(i32),
+    /// Raw SQL was run without being allowed, see [`Controller::allow_sql`]
+    SqlNotAllowed,
     /// The user has too many rentals to create a new one
-    TooManyRentals,
+    TooManyRentals {
+        current: i64,
+        max: i64,
+        student: i32,
+    },
+    /// The user has too many reservations to create a new one
+    TooManyReservations {
+        current: i64,
+        max: i64,
+        student: i32,
+    },
     /// The transaction was none when DB function called
     TransactionNone,
+    /// A transaction is open when the command needs to start its own
+    TransactionOpen,
+    /// There are multiple reservations which could be cancelled based on user and instrument
+    UnreserveMultiple(Vec<Reservation>),
 }
 
 impl fmt::Display for ControlError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Self::AlreadyEnrolled(u, l) => {
+                write!(f, "Student {u} is already enrolled in lesson {l}!")
+            }
+            Self::AlreadyRented(u, i) => {
+                write!(f, "Student {u} is already renting instrument {i}!")
+            }
+            Self::AlreadyReserved(u, i) => {
+                write!(f, "Student {u} already has an active reservation of instrument {i}!")
+            }
+            Self::BadBusinessRule { name, value } => write!(
+                f,
+                "Business rule {name} has the invalid value '{value}'! This is a configuration \
+                problem, fix the value in the business_rules table."
+            ),
+            Self::CommitRolledBack(e) => {
+                write!(f, "Commit failed, the transaction was rolled back! ({e})")
+            }
+            Self::CommitUncertain(e) => write!(
+                f,
+                "Commit failed and it is unknown if the transaction was committed! Check whether \
+                the changes were saved before redoing them. ({e})"
+            ),
             Self::Converted(s) => write!(f, "{s}"),
-            Self::TerminateMultiple(_) => write!(f, "Multiple rentings to terminate!"),
-            Self::TooManyRentals => write!(f, "This user has too many rentals!"),
+            Self::DryRunUnsupported(c) => write!(f, "Can not dry run {c}!"),
+            Self::InstructorNotFound(i) => write!(f, "Instructor {i} not found!"),
+            Self::InstrumentNotFound(i) => write!(f, "Instrument {i} not found!"),
+            Self::LessonFull {
+                lesson,
+                max,
+                booked,
+            } => write!(
+                f,
+                "Lesson {lesson} is full, {booked} of {max} places are booked!"
+            ),
+            Self::LessonNotFound(l) => write!(f, "Lesson {l} not found!"),
+            Self::NotEnrolled(u, l) => write!(f, "Student {u} is not enrolled in lesson {l}!"),
+            Self::InstrumentUnavailable(i) => {
+                write!(f, "Every unit of instrument {i} is rented out!")
+            }
+            Self::InMaintenance { instrument, note } => write!(
+                f,
+                "Every unit of instrument {instrument} is rented out or in maintenance ({note})!"
+            ),
+            Self::NotInMaintenance(i) => write!(f, "No unit of instrument {i} is in maintenance!"),
+            Self::ReadOnlyTransaction => write!(
+                f,
+                "Can not write in a read only transaction! Begin a new transaction first."
+            ),
+            Self::RentingAlreadyTerminated(r) => write!(f, "Renting {r} is already terminated!"),
+            Self::ResourceBusy => write!(
+                f,
+                "Another session is modifying rentals for this student or instrument — try again shortly."
+            ),
+            Self::ReservationNotActive(r) => write!(f, "Reservation {r} is no longer active!"),
+            Self::ReservationNotFound(r) => write!(f, "Reservation {r} not found!"),
+            Self::SavepointCleanupFailed {
+                name,
+                error,
+                cleanup,
+            } => write!(
+                f,
+                "{error} Rolling back to savepoint {name} then failed as well: {cleanup}"
+            ),
+            Self::SavepointNotFound(s) => write!(
+                f,
+                "Savepoint {s} not found! The transaction is aborted and must be rolled back."
+            ),
+            Self::StudentNameNotFound(s) => write!(f, "No student named like {s} found!"),
+            Self::StudentInactive(s) => {
+                write!(f, "Student {s} is deactivated and can not rent!")
+            }
+            Self::StudentNotFound(s) => write!(f, "Student {s} not found!"),
+            Self::SqlNotAllowed => write!(
+                f,
+                "Raw SQL is disabled! Start sgdb with --allow-sql to enable it."
+            ),
+            Self::TooManyRentals {
+                current,
+                max,
+                student,
+            } => write!(
+                f,
+                "Student {student} already has {current} active rentals (limit {max})."
+            ),
+            Self::TooManyReservations {
+                current,
+                max,
+                student,
+            } => write!(
+                f,
+                "Student {student} already has {current} active reservations (limit {max})."
+            ),
             Self::TransactionNone => write!(f, "Error! Transaction was None!"),
+            Self::TransactionOpen => write!(
+                f,
+                "Active transaction found. Use 'commit', 'rollback', or 'fbegin'."
+            ),
+            Self::UnreserveMultiple(_) => write!(f, "Multiple reservations to cancel!"),
+        }
+    }
+}
+
+impl From<DbError> for ControlError {
+    fn from(value: DbError) -> Self {
+        match value {
+            DbError::NotFound => sqlx::Error::RowNotFound.into(),
+            DbError::Busy => Self::ResourceBusy,
+            DbError::Other(e) => e.into(),
+            e => Self::Converted(format!("SQL error: {e}")),
+        }
+    }
+}
+
+impl From<RuleError> for ControlError {
+    fn from(value: RuleError) -> Self {
+        match value {
+            RuleError::Invalid { name, value } => Self::BadBusinessRule { name, value },
+            RuleError::Sql(e) => e.into(),
+            e @ RuleError::Missing(_) => Self::Converted(format!("Error: {e}")),
         }
     }
 }
@@ -83,8 +1160,67 @@ impl From<ParseIntError> for ControlError {
     }
 }
 
+/// The transaction a read only command runs in, see [`Controller`]`.read_tx()`
+enum ReadTx<'b, 'a> {
+    /// The open transaction of the controller
+    Open(&'b mut Transaction<'a, Postgres>),
+    /// A read only transaction of its own, which is rolled back when dropped so that the
+    /// connection goes back to the pool
+    Own(Box<Transaction<'a, Postgres>>),
+}
+
+impl<'a> Deref for ReadTx<'_, 'a> {
+    type Target = Transaction<'a, Postgres>;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::Open(tx) => tx,
+            Self::Own(tx) => tx,
+        }
+    }
+}
+
+impl DerefMut for ReadTx<'_, '_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            Self::Open(tx) => tx,
+            Self::Own(tx) => tx,
+        }
+    }
+}
+
 impl From<sqlx::Error> for ControlError {
     fn from(value: sqlx::Error) -> Self {
+        if let Some(e) = value
+            .as_database_error()
+            .and_then(|e| e.try_downcast_ref::<PgDatabaseError>())
+        {
+            if e.code() == READ_ONLY_TRANSACTION {
+                return Self::ReadOnlyTransaction;
+            }
+            if let (RAISE_EXCEPTION, Some(RENTAL_LIMIT), Some(err)) = (
+                e.code(),
+                e.constraint(),
+                e.detail().and_then(rental_limit_detail),
+            ) {
+                return err;
+            }
+            if let (UNIQUE_VIOLATION, Some(ONE_ACTIVE_RENTING), Some((u, i))) = (
+                e.code(),
+                e.constraint(),
+                e.detail().and_then(unique_detail_ids),
+            ) {
+                return Self::AlreadyRented(u, i);
+            }
+            if let (FK_VIOLATION, Some(id)) = (e.code(), e.detail().and_then(fk_detail_id)) {
+                match e.constraint() {
+                    Some(FK_RENTINGS_INSTRUMENT) => return Self::InstrumentNotFound(id),
+                    Some(FK_RENTINGS_STUDENT) => return Self::StudentNotFound(id),
+                    _ => {}
+                }
+            }
+        }
+
         Self::Converted(format!("SQL error: {value}"))
     }
 }
@@ -92,27 +1228,135 @@ impl From<sqlx::Error> for ControlError {
 impl<'a> Controller<'a> {
     /// Creates a new [`Controller`]
     pub async fn new() -> Self {
-        let pool = db::setup_conn()
+        let pools = db::setup_conn()
             .await
             .expect("Failed to set up connection pool!");
 
+        Self::with_pools(pools)
+    }
+
+    /// Creates a new [`Controller`] which uses existing pools, e.g. to share them between several
+    /// controllers
+    pub fn with_pools(pools: DbPools) -> Self {
+        let txn_warn_after = env::var(TXN_WARN_KEY)
+            .ok()
+            .and_then(|s| s.trim().parse::<u64>().ok())
+            .map_or(
+                Duration::from_secs(TXN_WARN_DEFAULT_SECS),
+                Duration::from_secs,
+            );
+
+        let session = [SESSION_KEY, "USER", "USERNAME"]
+            .iter()
+            .find_map(|k| env::var(k).ok().filter(|s| !s.trim().is_empty()))
+            .unwrap_or_else(|| String::from("unknown"));
+
+        let max_rentals_key = env::var(MAX_RENTALS_ENV_KEY)
+            .ok()
+            .filter(|s| !s.trim().is_empty())
+            .unwrap_or_else(|| String::from(db::MAX_RENTALS_KEY));
+
         Self {
-            pool,
+            pools,
             transaction: None,
+            transaction_start: None,
+            txn_warn_after,
+            last_txn_warning: None,
+            session,
+            autocommit: true,
+            lock_wait: LockWait::Block,
+            locking: Locking::Rows,
+            read_only: false,
+            allow_sql: false,
+            max_rentals_key,
+            rules: db::RuleCache::default(),
+            type_cache: None,
+        }
+    }
+
+    /// Allows [`Command::Sql`] to be run, which is refused with [`ControlError::SqlNotAllowed`]
+    /// by default
+    pub const fn allow_sql(&mut self, allow: bool) {
+        self.allow_sql = allow;
+    }
+
+    /// Sets the name of the business rule holding the max number of active rentals per student,
+    /// taken from `SGDB_MAX_RENTALS_KEY` or [`db::MAX_RENTALS_KEY`] by default
+    pub fn max_rentals_key(&mut self, key: &str) {
+        self.max_rentals_key = String::from(key);
+    }
+
+    /// The instrument types loaded by [`Command::Warmup`], listed by [`Command::InstrumentTypes`]
+    /// instead of querying them again
+    ///
+    /// # Returns
+    /// The instrument types, or `None` if they were never loaded, were cleared with
+    /// [`Command::ClearCache`] or are older than [`TYPE_CACHE_TTL`]
+    fn cached_instrument_types(&self) -> Option<&[InstrumentType]> {
+        self.type_cache
+            .as_ref()
+            .filter(|(loaded, _)| loaded.elapsed() < TYPE_CACHE_TTL)
+            .map(|(_, types)| types.as_slice())
+    }
+
+    /// Brings a database created by an older create.sql up to date, so the tables and
+    /// constraints the commands rely on exist before the repl starts
+    ///
+    /// Every step is safe to run again and they run in a single transaction, so either all of
+    /// them are applied or none are
+    ///
+    /// # Returns
+    /// - Ok if the schema is up to date
+    /// - Err [`ControlError`] if a step failed, nothing is changed then
+    pub async fn upgrade_schema(&mut self) -> Result<(), ControlError> {
+        let mut tx = self.pools.primary.begin().await?;
+
+        db::create_rentings_archive(&mut tx).await?;
+        db::create_one_active_renting_index(&mut tx).await?;
+        db::create_payments_paid_at(&mut tx).await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Checks that the business rules every rental depends on are usable, so a misconfigured
+    /// database is reported before the repl starts instead of on the first rental
+    ///
+    /// # Returns
+    /// - Ok if the max number of rentals is set to a number above zero
+    /// - Err [`ControlError::BadBusinessRule`] naming the rule and its value if it is not, or
+    ///   another [`ControlError`] if the rule is missing or can not be read
+    pub async fn check_config(&mut self) -> Result<(), ControlError> {
+        let key = self.max_rentals_key.clone();
+        let mut read = self.read_tx().await?;
+
+        let max = db::get_max_rentals(&mut read, &key).await?;
+        if max <= 0 {
+            return Err(ControlError::BadBusinessRule {
+                name: key,
+                value: max.to_string(),
+            });
         }
+        Ok(())
     }
 
     /// Runs the repl with this [`Controller`] as the parent
     ///
-    /// Returns an [`sqlx::Error`] if `self.transaction` is `Some(t)` when the repl exits and the
-    /// transaction fails to be rolled back
-    pub async fn run_repl(mut self) -> Result<(), sqlx::Error> {
-        repl::repl(&mut self).await;
+    /// Returns the [`ExitCode`] of the repl, see [`repl::repl`], or an [`sqlx::Error`] if
+    /// `self.transaction` is `Some(t)` when the repl exits and the transaction fails to be rolled
+    /// back
+    pub async fn run_repl(
+        mut self,
+        mode: ReplMode,
+        verbose: bool,
+        style: PromptStyle,
+    ) -> Result<ExitCode, sqlx::Error> {
+        let code = repl::repl(&mut self, mode, verbose, style).await;
         if let Some(t) = self.transaction {
             t.rollback().await?;
         }
-        self.pool.close().await;
-        Ok(())
+        self.pools.close().await;
+        Ok(code)
     }
 
     /// Executes a [`Command`] on this controller
@@ -120,202 +1364,4898 @@ impl<'a> Controller<'a> {
     /// # Parameters
     /// - `c` the [`Command`] to execute
     ///
+    /// If autocommit is on and no transaction is open, commands which need one are run in their
+    /// own transaction which is committed if they succeed and rolled back otherwise, see
+    /// [`Controller::autocommits`]
+    ///
+    /// Commands which change data are written to the audit log in the same transaction, so they
+    /// are only kept if it commits. Commits, rollbacks and failed commands are written and
+    /// committed right away. If the audit log can not be written the command still stands, and
+    /// why is returned as the `audit_warning` of the result
+    ///
     /// # Returns
-    /// - Ok [`ControlResult`] if the execution succeeded
+    /// - Ok [`ControlResult`] and the [`ExecutionTime`] it took if the execution succeeded
     /// - Err [`ControlError`] if the execution failed
-    pub async fn execute(&mut self, c: Command) -> Result<ControlResult, ControlError> {
-        match c {
-            Command::Begin => self.begin().await,
-            Command::Commit => self.commit().await,
-            Command::Rent(u, i) => self.rent(&u, &i).await,
-            Command::Rollback => self.rollback().await,
-            Command::Terminate(id) => self.terminate(&id).await,
-            Command::TryTerminate(u, i) => self.try_terminate(&u, &i).await,
-            Command::List(o) => self.list(o).await,
+    pub async fn execute(&mut self, c: Command) -> Result<ControlResultWithMeta, ControlError> {
+        let start = Instant::now();
+        let audit = match c {
+            Command::Begin { force: true }
+            | Command::Commit
+            | Command::Reset
+            | Command::Rollback(_) => Some((c.to_string(), false)),
+            _ if c.is_mutating() => Some((c.to_string(), true)),
+            _ => None,
+        };
+        let autocommit = self.autocommits(&c);
+        if autocommit {
+            self.begin(self.read_only).await?;
         }
-    }
 
-    async fn begin(&mut self) -> Result<ControlResult, ControlError> {
-        if let Some(t) = self.transaction.take() {
-            t.rollback().await?;
-            self.transaction = Some(self.pool.begin().await?);
-        } else {
-            self.transaction = Some(self.pool.begin().await?);
+        let r = self.dispatch(c).await;
+        // The command is not undone because it could not be logged
+        let audit_warning = match audit {
+            Some((command, in_transaction)) => self
+                .audit(&command, r.is_ok() && in_transaction, r.is_ok())
+                .await
+                .err()
+                .map(|e| format!("The command was not written to the audit log: {e}")),
+            None => None,
+        };
+        if autocommit {
+            if r.is_ok() {
+                self.commit().await?;
+            } else {
+                self.rollback(None).await?;
+            }
+        }
+        if let (Err(_), Some(w)) = (&r, &audit_warning) {
+            eprintln!("Warning: {w}");
         }
-        Ok(ControlResult::Begin)
+        Ok(ControlResultWithMeta {
+            result: r?,
+            executed_at: OffsetDateTime::now_utc(),
+            duration_us: u64::try_from(start.elapsed().as_micros()).unwrap_or(u64::MAX),
+            audit_warning,
+        })
     }
 
-    async fn commit(&mut self) -> Result<ControlResult, ControlError> {
-        self.transaction
-            .take()
-            .ok_or(ControlError::TransactionNone)?
-            .commit()
-            .await?;
-
-        Ok(ControlResult::Commit)
+    /// Returns if [`Controller`]`.execute()` would run the command in its own transaction
+    /// because autocommit is on and no transaction is open
+    pub const fn autocommits(&self, c: &Command) -> bool {
+        self.autocommit && self.transaction.is_none() && c.needs_transaction()
     }
 
-    async fn rollback(&mut self) -> Result<ControlResult, ControlError> {
-        self.transaction
-            .take()
-            .ok_or(ControlError::TransactionNone)?
-            .rollback()
-            .await?;
+    /// Executes the commands in order, see [`BatchOptions`] for how failures are handled
+    ///
+    /// An atomic batch fails with [`ControlError::TransactionOpen`] without running anything if a
+    /// transaction is already open, and with [`ControlError::Converted`] if it contains commands
+    /// which begin, commit or roll back transactions or savepoints. Otherwise no transaction is left open when
+    /// it returns
+    ///
+    /// # Returns
+    /// The result of each command which was executed, with the result of the commit last if the
+    /// batch was atomic and every command succeeded
+    pub async fn execute_all(
+        &mut self,
+        cmds: Vec<Command>,
+        options: BatchOptions,
+    ) -> Vec<Result<ControlResult, ControlError>> {
+        if options.atomic {
+            if self.transaction.is_some() {
+                return vec![Err(ControlError::TransactionOpen)];
+            }
+            if let Some(c) = cmds.iter().find(|c| {
+                matches!(
+                    c,
+                    Command::Begin { .. }
+                        | Command::BeginReadOnly
+                        | Command::Commit
+                        | Command::ReleaseSavepoint(_)
+                        | Command::Reset
+                        | Command::Rollback(_)
+                        | Command::RollbackTo(_)
+                        | Command::Savepoint(_)
+                )
+            }) {
+                return vec![Err(ControlError::Converted(format!(
+                    "Can not run {c} in an atomic batch"
+                )))];
+            }
+            if let Err(e) = self.execute(Command::Begin { force: false }).await {
+                return vec![Err(e)];
+            }
+        }
 
-        Ok(ControlResult::Rollback)
-    }
+        let mut results = vec![];
+        for c in cmds {
+            let r = self.execute(c).await.map(|r| r.result);
+            let failed = r.is_err();
+            results.push(r);
 
-    async fn rent(&mut self, user: &str, inst: &str) -> Result<ControlResult, ControlError> {
-        let (u, i) = u_i_parse(user, inst)?;
-        let tx = self.guard()?;
+            if failed && options.atomic {
+                if let Err(e) = self.execute(Command::Rollback(None)).await {
+                    results.push(Err(e));
+                }
+                // Dropping an unfinished transaction rolls it back
+                self.transaction = None;
+                self.transaction_start = None;
+                return results;
+            }
+            if failed && options.stop_on_error {
+                return results;
+            }
+        }
 
-        db::lock_rentings(tx, u, i).await?;
-        let max = db::get_max_rentals(tx).await?.parse::<i64>()?;
-        let ur = db::count_user_rentals(tx, u).await?;
+        if options.atomic {
+            results.push(self.execute(Command::Commit).await.map(|r| r.result));
+        }
+        results
+    }
 
-        if ur >= max {
-            Err(ControlError::TooManyRentals)
-        } else {
-            Ok(ControlResult::Rent(db::rent(tx, u, i).await?))
+    /// Writes a command to the audit log, in the current transaction if `in_transaction` and
+    /// otherwise in its own committed transaction
+    async fn audit(
+        &mut self,
+        command: &str,
+        in_transaction: bool,
+        succeeded: bool,
+    ) -> Result<(), ControlError> {
+        match self.transaction.as_mut() {
+            // A failed insert would abort the transaction of the command
+            Some(_) if in_transaction => {
+                self.savepoint_query("SAVEPOINT", AUDIT_SAVEPOINT).await?;
+                let recorded = match self.transaction.as_mut() {
+                    Some(tx) => db::record_audit(tx, &self.session, command, succeeded)
+                        .await
+                        .map_err(ControlError::from),
+                    None => Err(ControlError::TransactionNone),
+                };
+                if recorded.is_err() {
+                    self.savepoint_query("ROLLBACK TO SAVEPOINT", AUDIT_SAVEPOINT)
+                        .await?;
+                }
+                self.savepoint_query("RELEASE SAVEPOINT", AUDIT_SAVEPOINT)
+                    .await?;
+                recorded?;
+            }
+            _ => {
+                let mut tx = self.pools.primary.begin().await?;
+                db::record_audit(&mut tx, &self.session, command, succeeded).await?;
+                tx.commit().await?;
+            }
         }
+        Ok(())
     }
 
-    async fn try_terminate(
+    // A wildcard arm would let a new command compile without being handled, see
+    // tests::all_commands_are_handled
+    #[deny(clippy::wildcard_enum_match_arm)]
+    async fn dispatch(&mut self, c: Command) -> Result<ControlResult, ControlError> {
+        match c {
+            Command::AddInstrument {
+                instrument_type,
+                brand,
+                model,
+                price,
+                count,
+            } => {
+                self.add_instrument(&instrument_type, &brand, &model, &price, &count)
+                    .await
+            }
+            Command::AddNote(id, note) => self.add_note(&id, &note).await,
+            Command::Archive(months) => self.archive(months).await,
+            Command::Audit(n) => self.audit_log(n).await,
+            Command::Backup => self.backup().await,
+            Command::Begin { force: false } => self.begin(self.read_only).await,
+            Command::Begin { force: true } => self.force_begin().await,
+            Command::BeginReadOnly => self.begin(true).await,
+            Command::Commit => self.commit().await,
+            Command::Damages => self.damages().await,
+            Command::Deactivate(u) => self.deactivate(&u).await,
+            Command::DryRun(c) => self.dry_run(*c).await,
+            Command::Enroll { student, lesson } => self.enroll(student, lesson).await,
+            Command::Payments(u) => self.payments(u).await,
+            Command::Ledger(u) => self.ledger(u).await,
+            Command::Lessons(f) => self.lessons(f).await,
+            Command::Quote(u, i) => self.quote(&u, &i).await,
+            Command::Reconcile(i, n) => self.reconcile(&i, &n).await,
+            Command::ReconcileReport => self.reconcile_report().await,
+            Command::Rent(u, i) => self.rent(&u, &i).await,
+            Command::Reservations(i) => self.reservations(i).await,
+            Command::Reserve(u, i) => self.reserve(&u, &i).await,
+            Command::Reset => self.force_begin().await.map(|_| ControlResult::Reset),
+            Command::Revenue {
+                from,
+                to,
+                include_archived,
+            } => self.revenue(&from, &to, include_archived).await,
+            Command::ReleaseSavepoint(name) => self.release_savepoint(&name).await,
+            Command::Rollback(savepoint) => self.rollback(savepoint.as_deref()).await,
+            Command::RollbackTo(name) => self.rollback(Some(&name)).await,
+            Command::Savepoint(name) => self.savepoint(&name).await,
+            Command::SetAutocommit(on) => {
+                self.autocommit = on;
+                Ok(ControlResult::Autocommit(on))
+            }
+            Command::SetLockWait(wait) => {
+                self.lock_wait = wait;
+                Ok(ControlResult::LockWait(wait))
+            }
+            Command::SetLocking(locking) => {
+                self.locking = locking;
+                Ok(ControlResult::Locking(locking))
+            }
+            Command::SetReadOnly(on) => {
+                self.read_only = on;
+                Ok(ControlResult::ReadOnly(on))
+            }
+            Command::Stats(StatsKind::Inventory) => self.stats().await,
+            Command::Stats(StatsKind::Types) => self.stats_types().await,
+            Command::Stats(StatsKind::Lifetime) => self.stats_lifetime().await,
+            Command::Stats(StatsKind::Monthly {
+                months,
+                include_archived,
+            }) => self.stats_monthly(months, include_archived).await,
+            Command::Sql(sql) => self.sql(&sql).await,
+            Command::StudentCost(u) => self.student_cost(u).await,
+            Command::Terminate(id, d) => self.terminate(&id, None, d.as_ref()).await,
+            Command::TerminateWithNote(id, n) => self.terminate(&id, Some(&n), None).await,
+            Command::TryTerminate(u, i, d) => self.try_terminate(&u, &i, d.as_ref()).await,
+            Command::TryUnreserve(u, i) => self.try_unreserve(&u, &i).await,
+            Command::Unenroll { student, lesson } => self.unenroll(student, lesson).await,
+            Command::Unreserve(id) => self.unreserve(&id).await,
+            Command::Warmup => self.warmup().await,
+            Command::ClearCache => Ok(self.clear_cache()),
+            Command::Export { what, format, path } => self.export(what, format, path).await,
+            Command::FindStudent(name) => self.find_student(&name).await,
+            Command::Fulfil(id) => self.fulfil(&id).await,
+            Command::Instructor { instructor, range } => self.instructor(instructor, range).await,
+            Command::InstrumentTypes => self.instrument_types().await,
+            Command::Invoice {
+                student,
+                year_month,
+            } => self.invoice(&student, &year_month).await,
+            Command::List(f) => self.list(f).await,
+            Command::ListAll(t) => self.list_all(t).await,
+            Command::MaintenanceList => self.maintenance_list().await,
+            Command::MaintenanceOff(i) => self.maintenance_off(&i).await,
+            Command::MaintenanceOn(i, note) => self.maintenance_on(&i, &note).await,
+        }
+    }
+
+    async fn add_instrument(
         &mut self,
-        user: &str,
-        inst: &str,
+        instrument_type: &str,
+        brand: &str,
+        model: &str,
+        price: &str,
+        count: &str,
     ) -> Result<ControlResult, ControlError> {
-        let (u, i) = u_i_parse(user, inst)?;
+        let price = price
+            .parse::<BigDecimal>()
+            .map_err(|e| ControlError::Converted(format!("ParseBigDecimal error: {e}")))?;
+        let count = count.parse::<i32>()?;
+        let (brand, model) = (brand.trim(), model.trim());
+        db::validate_instrument_fields(brand, model, &price, count)
+            .map_err(ControlError::Converted)?;
+
         let tx = self.guard()?;
+        let id = db::add_instrument(
+            tx,
+            &instrument_type.to_lowercase(),
+            brand,
+            model,
+            &price,
+            count,
+        )
+        .await?;
+        Ok(ControlResult::AddInstrument(id))
+    }
 
-        db::lock_rentings(tx, u, i).await?;
-        let vec = db::find_to_terminate(tx, u, i).await?;
+    async fn add_note(&mut self, id: &str, note: &str) -> Result<ControlResult, ControlError> {
+        let rent_id = id.parse::<i32>()?;
+        let tx = self.guard()?;
 
-        match vec.len() {
-            0 => Err(sqlx::Error::RowNotFound.into()),
-            1 => Ok(ControlResult::TryTerminate(
-                db::terminate_rid(tx, vec[0].get_id()).await?,
-            )),
-            _ => Err(ControlError::TerminateMultiple(vec)),
-        }
+        let note_id = db::add_renting_note(tx, rent_id, note).await?;
+        let active = match db::get_renting(tx, rent_id).await {
+            Ok(r) => Some(r.get_end_date().is_none()),
+            Err(sqlx::Error::RowNotFound) => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(ControlResult::NoteAdded {
+            note_id,
+            rent_id,
+            active,
+        })
     }
 
-    async fn terminate(&mut self, id: &str) -> Result<ControlResult, ControlError> {
-        let tx = self.guard()?;
-        let i = id.parse::<i32>()?;
-        Ok(ControlResult::Terminate(db::terminate_rid(tx, i).await?))
+    async fn begin(&mut self, read_only: bool) -> Result<ControlResult, ControlError> {
+        if self.transaction.is_some() {
+            return Err(ControlError::TransactionOpen);
+        }
+        self.transaction = Some(if read_only {
+            db::begin_readonly_tx(self.pools.reader()).await?
+        } else {
+            db::begin_tx(&self.pools.primary, &self.max_rentals_key).await?
+        });
+        self.transaction_start = Some(Instant::now());
+        self.last_txn_warning = None;
+        self.rules.clear();
+        Ok(ControlResult::Begin(false))
     }
 
-    async fn list(&mut self, o: Option<String>) -> Result<ControlResult, ControlError> {
-        let tx = self.guard()?;
+    /// Begins a new transaction, rolling back the open one first if there is one
+    async fn force_begin(&mut self) -> Result<ControlResult, ControlError> {
+        let rolled_back = match self.transaction.take() {
+            Some(t) => {
+                self.transaction_start = None;
+                t.rollback().await?;
+                true
+            }
+            None => false,
+        };
+
+        self.begin(self.read_only).await?;
+        Ok(ControlResult::Begin(rolled_back))
+    }
 
-        let rows = match o {
-            Some(t) => db::list_type(tx, format!("{}%", t.to_lowercase())).await?,
-            None => db::list_all(tx).await?,
+    /// Commits the open transaction
+    ///
+    /// If the commit fails the error tells whether the transaction is known to be rolled back or
+    /// the connection was lost and its outcome is unknown. In the latter case the status of the
+    /// transaction is looked up on a new connection if it had written anything.
+    async fn commit(&mut self) -> Result<ControlResult, ControlError> {
+        self.transaction_start = None;
+        self.rules.clear();
+        let mut tx = self
+            .transaction
+            .take()
+            .ok_or(ControlError::TransactionNone)?;
+        let id = match db::transaction_id(&mut tx).await {
+            Ok(id) => id,
+            Err(e)
+                if e.as_database_error()
+                    .and_then(|e| e.code())
+                    .is_some_and(|c| c == IN_FAILED_TRANSACTION) =>
+            {
+                // PostgreSQL answers the commit of an aborted transaction with a rollback
+                tx.rollback().await?;
+                return Err(ControlError::CommitRolledBack(
+                    "an earlier command failed".to_string(),
+                ));
+            }
+            Err(_) => None,
         };
 
-        if rows.is_empty() {
-            return Err(sqlx::Error::RowNotFound.into());
+        let Err(e) = tx.commit().await else {
+            return Ok(ControlResult::Commit);
+        };
+        if e.as_database_error().is_some() {
+            // The server refused the commit, which ends the transaction
+            return Err(ControlError::CommitRolledBack(e.to_string()));
         }
+        let Some(id) = id else {
+            // Nothing was written, so there is nothing which could have been committed
+            return Err(ControlError::CommitRolledBack(e.to_string()));
+        };
 
-        let mut ret = vec![];
-        for i in rows {
-            let rent_count = db::count_instrument_rentals(tx, i.get_id()).await?;
-            let available = i64::from(i.get_count()) - rent_count;
-            if available > 0 {
-                ret.push(i.to_string(available));
+        match db::transaction_status(&self.pools.primary, id).await {
+            Ok(Some(s)) if s == "committed" => {
+                eprintln!("Warning: the commit reported an error ({e}) but was committed.");
+                Ok(ControlResult::Commit)
             }
+            Ok(Some(s)) if s == "aborted" => Err(ControlError::CommitRolledBack(e.to_string())),
+            _ => Err(ControlError::CommitUncertain(e.to_string())),
         }
-        Ok(ControlResult::List(ret))
     }
 
-    fn guard<'b>(&'b mut self) -> Result<&'b mut Transaction<'a, Postgres>, ControlError> {
-        self.transaction
-            .as_mut()
-            .ok_or(ControlError::TransactionNone)
-    }
-}
+    async fn rollback(&mut self, savepoint: Option<&str>) -> Result<ControlResult, ControlError> {
+        if let Some(name) = savepoint {
+            self.savepoint_query("ROLLBACK TO SAVEPOINT", name).await?;
+            return Ok(ControlResult::Rollback(false));
+        }
 
-fn u_i_parse(u: &str, i: &str) -> Result<(i32, i32), ControlError> {
-    Ok((u.parse::<i32>()?, i.parse::<i32>()?))
-}
+        self.transaction_start = None;
+        self.rules.clear();
+        if let Err(e) = self
+            .transaction
+            .take()
+            .ok_or(ControlError::TransactionNone)?
+            .rollback()
+            .await
+        {
+            // The connection is closed on error and the server rolls back what it was running
+            eprintln!("Warning: the rollback reported an error ({e}), nothing was committed.");
+        }
+
+        Ok(ControlResult::Rollback(true))
+    }
+
+    async fn savepoint(&mut self, name: &str) -> Result<ControlResult, ControlError> {
+        self.savepoint_query("SAVEPOINT", name).await?;
+        Ok(ControlResult::Savepoint(name.to_string()))
+    }
+
+    async fn release_savepoint(&mut self, name: &str) -> Result<ControlResult, ControlError> {
+        self.savepoint_query("RELEASE SAVEPOINT", name).await?;
+        Ok(ControlResult::Released(name.to_string()))
+    }
+
+    /// Runs `f` inside the savepoint `name` of the current transaction, so that if it fails only
+    /// its own work is rolled back and the transaction stays open
+    ///
+    /// Savepoints can be nested by calling this inside `f` with another name
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// # async fn f(c: &mut sgdb::controller::Controller<'_>) -> Result<(), sgdb::controller::ControlError> {
+    /// c.with_savepoint("batch_rent", async |c| {
+    ///     c.execute(sgdb::controller::Command::Rent("3".into(), "1".into())).await?;
+    ///     c.execute(sgdb::controller::Command::Rent("3".into(), "2".into())).await?;
+    ///     Ok(())
+    /// })
+    /// .await
+    /// # }
+    /// ```
+    ///
+    /// # Returns
+    /// - Ok what `f` returned, after releasing the savepoint
+    /// - Err [`ControlError`] if the savepoint could not be created, or what `f` returned after
+    ///   rolling back to and releasing the savepoint
+    /// - Err [`ControlError::SavepointCleanupFailed`] with what `f` returned if rolling back to
+    ///   or releasing the savepoint failed
+    pub async fn with_savepoint<F, T>(&mut self, name: &str, f: F) -> Result<T, ControlError>
+    where
+        F: AsyncFnOnce(&mut Self) -> Result<T, ControlError>,
+    {
+        self.savepoint_query("SAVEPOINT", name).await?;
+        match f(self).await {
+            Ok(v) => {
+                self.savepoint_query("RELEASE SAVEPOINT", name).await?;
+                Ok(v)
+            }
+            Err(e) => {
+                let cleanup = match self.savepoint_query("ROLLBACK TO SAVEPOINT", name).await {
+                    Ok(()) => self.savepoint_query("RELEASE SAVEPOINT", name).await,
+                    Err(c) => Err(c),
+                };
+                match cleanup {
+                    Ok(()) => Err(e),
+                    Err(c) => Err(ControlError::SavepointCleanupFailed {
+                        name: name.to_string(),
+                        error: Box::new(e),
+                        cleanup: Box::new(c),
+                    }),
+                }
+            }
+        }
+    }
+
+    /// Runs `statement` on the savepoint `name` in the current transaction, after checking that
+    /// the name can be put in the query as is
+    async fn savepoint_query(&mut self, statement: &str, name: &str) -> Result<(), ControlError> {
+        if !is_identifier(name) {
+            return Err(ControlError::Converted(format!(
+                "Invalid savepoint name {name}"
+            )));
+        }
+        if statement.starts_with("ROLLBACK") {
+            // Rules written after the savepoint are undone with it
+            self.rules.clear();
+        }
+        let tx = self.guard()?;
+        match sqlx::query(&format!("{statement} {name};"))
+            .execute(&mut **tx)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e)
+                if e.as_database_error()
+                    .and_then(|e| e.code())
+                    .is_some_and(|c| c == INVALID_SAVEPOINT) =>
+            {
+                Err(ControlError::SavepointNotFound(name.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn sql(&mut self, sql: &str) -> Result<ControlResult, ControlError> {
+        if !self.allow_sql {
+            return Err(ControlError::SqlNotAllowed);
+        }
+        // The transaction is the controller's, begin, commit and savepoint have their own commands
+        if db::is_transaction_control(sql) {
+            return Err(ControlError::Converted(
+                "Can not control the transaction with sql, use begin, commit, rollback or savepoint"
+                    .into(),
+            ));
+        }
+        // The statements may write the business rules
+        self.rules.clear();
+        let tx = self.guard()?;
+
+        Ok(ControlResult::Sql(db::run_sql(tx, sql).await?))
+    }
+
+    async fn rent(&mut self, user: &str, inst: &str) -> Result<ControlResult, ControlError> {
+        let (u, i) = u_i_parse(user, inst)?;
+        let key = self.max_rentals_key.clone();
+        let (wait, locking) = (self.lock_wait, self.locking);
+        let (tx, rules) = self.guard_rules()?;
+
+        let (list_price, price, deposit) = check_rent(tx, rules, u, i, &key, wait, locking).await?;
+        let (rent_id, start_date) = db::rent(tx, u, i, &price, &deposit).await?;
+        let reserved_ahead = db::count_reservations_ahead(tx, u, i).await?;
+        Ok(ControlResult::Rent {
+            rent_id,
+            start_date,
+            list_price,
+            price,
+            deposit,
+            reserved_ahead,
+        })
+    }
+
+    /// Runs all the reads and checks of a mutating command without changing anything, and
+    /// describes what the command would have done
+    ///
+    /// Only rent, terminate, add and maintenance on/off can be dry run, see
+    /// [`Command::supports_dry_run`]
+    async fn dry_run(&mut self, c: Command) -> Result<ControlResult, ControlError> {
+        // The note is only stored, so a termination with a note is checked like any other
+        let c = match c {
+            Command::TerminateWithNote(id, _) => Command::Terminate(id, None),
+            c => c,
+        };
+
+        let (wait, locking) = (self.lock_wait, self.locking);
+        let s = match c {
+            Command::Rent(user, inst) => {
+                let (u, i) = u_i_parse(&user, &inst)?;
+                let key = self.max_rentals_key.clone();
+                let (tx, rules) = self.guard_rules()?;
+                let (_, price, deposit) = check_rent(tx, rules, u, i, &key, wait, locking).await?;
+                let mut s = format!(
+                    "Would create rental of instrument {i} for student {u} at {}/month",
+                    db::format_currency(&price)
+                );
+                if deposit > BigDecimal::from(0) {
+                    s.push_str(&format!(
+                        " with a deposit of {}",
+                        db::format_currency(&deposit)
+                    ));
+                }
+                s
+            }
+            Command::Terminate(id, d) => {
+                let damage = damage_amount(d.as_ref())?;
+                let id = id.parse::<i32>()?;
+                let tx = self.guard()?;
+                match db::get_renting(tx, id).await {
+                    Ok(r) if r.get_end_date().is_none() => {
+                        describe_termination(id, r.get_instrument_id(), &damage)
+                    }
+                    Ok(_) | Err(sqlx::Error::RowNotFound) => {
+                        format!("Would terminate nothing, renting {id} is not active")
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Command::TryTerminate(user, inst, d) => {
+                let (u, i) = u_i_parse(&user, &inst)?;
+                let damage = damage_amount(d.as_ref())?;
+                let tx = self.guard()?;
+
+                db::lock_rentings(tx, LockScope::StudentOrInstrument(u, i), wait, locking).await?;
+                if !db::student_exists(tx, u).await? {
+                    return Err(ControlError::StudentNotFound(u));
+                }
+                let Some(r) = db::find_to_terminate(tx, u, i).await? else {
+                    return Err(sqlx::Error::RowNotFound.into());
+                };
+                describe_termination(r.get_id(), i, &damage)
+            }
+            Command::AddInstrument {
+                instrument_type,
+                brand,
+                model,
+                price,
+                count,
+            } => {
+                let price = parse_decimal(&price)?;
+                let count = count.parse::<i32>()?;
+                let (brand, model) = (brand.trim(), model.trim());
+                db::validate_instrument_fields(brand, model, &price, count)
+                    .map_err(ControlError::Converted)?;
+                let tx = self.guard()?;
+                // Fails the same way the insert does if the type does not exist
+                db::get_instrument_type_id(tx, &instrument_type.to_lowercase()).await?;
+                format!(
+                    "Would add {count} {} {brand} {model} at {}/month",
+                    instrument_type.to_lowercase(),
+                    db::format_currency(&price)
+                )
+            }
+            Command::MaintenanceOn(inst, note) => {
+                let i = inst.parse::<i32>()?;
+                let tx = self.guard()?;
+
+                db::lock_rentings(tx, LockScope::InstrumentOnly(i), wait, locking).await?;
+                if !db::instrument_exists(tx, i).await? {
+                    return Err(ControlError::InstrumentNotFound(i));
+                }
+                check_available(tx, i).await?;
+                format!("Would send one unit of instrument {i} for repair: {note}")
+            }
+            Command::MaintenanceOff(inst) => {
+                let i = inst.parse::<i32>()?;
+                let tx = self.guard()?;
+
+                let m = db::list_maintenance(tx, Some(i)).await?;
+                let m = m.first().ok_or(ControlError::NotInMaintenance(i))?;
+                format!("Would end maintenance {} of instrument {i}", m.get_id())
+            }
+            c => return Err(ControlError::DryRunUnsupported(c.to_string())),
+        };
+
+        Ok(ControlResult::DryRun(s))
+    }
+
+    async fn fulfil(&mut self, id: &str) -> Result<ControlResult, ControlError> {
+        let id = id.parse::<i32>()?;
+        let key = self.max_rentals_key.clone();
+        let (wait, locking) = (self.lock_wait, self.locking);
+        let (tx, rules) = self.guard_rules()?;
+
+        let r = match db::get_reservation(tx, id).await? {
+            None => return Err(ControlError::ReservationNotFound(id)),
+            Some(r) if !r.is_active() => return Err(ControlError::ReservationNotActive(id)),
+            Some(r) => r,
+        };
+        let (u, i) = (r.get_student_id(), r.get_instrument_id());
+        if !db::student_is_active(tx, u).await? {
+            return Err(ControlError::StudentInactive(u));
+        }
+        if !db::instrument_exists_and_active(tx, i).await? {
+            return Err(ControlError::InstrumentNotFound(i));
+        }
+
+        db::lock_rentings(tx, LockScope::StudentOrInstrument(u, i), wait, locking).await?;
+        check_available(tx, i).await?;
+
+        let ur = db::count_user_rentals(tx, u).await?;
+        let max = rules.get_i64(tx, &key, None).await?;
+        if ur >= max {
+            return Err(ControlError::TooManyRentals {
+                current: ur,
+                max,
+                student: u,
+            });
+        }
+
+        let (list_price, price) = price_for(tx, rules, u, i).await?;
+        let deposit = deposit_for(tx, rules, &list_price).await?;
+        let (rent_id, _) = db::rent(tx, u, i, &price, &deposit).await?;
+        // The reservation is locked above so this only fails if the lock was somehow lost, the
+        // error then makes autocommit roll back the renting
+        if db::fulfil_reservation(tx, id, rent_id).await? == 0 {
+            return Err(ControlError::ReservationNotActive(id));
+        }
+
+        Ok(ControlResult::Fulfilled {
+            reservation_id: id,
+            rent_id,
+            list_price,
+            price,
+            deposit,
+        })
+    }
+
+    async fn backup(&mut self) -> Result<ControlResult, ControlError> {
+        let now = OffsetDateTime::now_utc();
+        let stamp = format!(
+            "{:04}-{:02}-{:02}_{:02}-{:02}-{:02}",
+            now.year(),
+            u8::from(now.month()),
+            now.day(),
+            now.hour(),
+            now.minute(),
+            now.second()
+        );
+        // Backups taken within the same second get a counter instead of replacing each other
+        let file = (1..)
+            .map(|n| match n {
+                1 => format!("backup_{stamp}.csv"),
+                n => format!("backup_{stamp}_{n}.csv"),
+            })
+            .find(|f| {
+                !Path::new(f).exists() && !Path::new(&format!("{f}{PARTIAL_SUFFIX}")).exists()
+            })
+            .unwrap_or_default();
+        let tx = self.guard()?;
+        let rows = export_file(tx, ExportKind::Rentals, ExportFormat::Csv, &file, false).await?;
+
+        Ok(ControlResult::Backup { file, rows })
+    }
+
+    async fn export(
+        &mut self,
+        what: ExportKind,
+        format: ExportFormat,
+        path: String,
+    ) -> Result<ControlResult, ControlError> {
+        let mut read = self.read_tx().await?;
+        let rows = export_file(&mut read, what, format, &path, true).await?;
+
+        Ok(ControlResult::Export { what, path, rows })
+    }
+
+    async fn reconcile(
+        &mut self,
+        inst: &str,
+        counted: &str,
+    ) -> Result<ControlResult, ControlError> {
+        let i = inst.parse::<i32>()?;
+        let counted = counted.parse::<i64>()?;
+        if counted < 0 {
+            return Err(ControlError::Converted(format!(
+                "Invalid count {counted}, must not be negative"
+            )));
+        }
+        let mut read = self.read_tx().await?;
+        let tx = &mut *read;
+
+        let stock = db::shelf_stock(tx, Some(i))
+            .await?
+            .pop()
+            .ok_or(ControlError::InstrumentNotFound(i))?;
+        Ok(ControlResult::Reconcile { stock, counted })
+    }
+
+    async fn reconcile_report(&mut self) -> Result<ControlResult, ControlError> {
+        let mut read = self.read_tx().await?;
+        let tx = &mut *read;
+
+        Ok(ControlResult::ReconcileReport(
+            db::shelf_stock(tx, None).await?,
+        ))
+    }
+
+    async fn reserve(&mut self, user: &str, inst: &str) -> Result<ControlResult, ControlError> {
+        let (u, i) = u_i_parse(user, inst)?;
+        let (wait, locking) = (self.lock_wait, self.locking);
+        let (tx, rules) = self.guard_rules()?;
+
+        if !db::student_exists(tx, u).await? {
+            return Err(ControlError::StudentNotFound(u));
+        }
+        if !db::instrument_exists_and_active(tx, i).await? {
+            return Err(ControlError::InstrumentNotFound(i));
+        }
+
+        // Locked as when renting so two sessions can not both pass the checks below
+        db::lock_rentings(tx, LockScope::StudentOnly(u), wait, locking).await?;
+
+        if db::has_active_reservation(tx, u, i).await? {
+            return Err(ControlError::AlreadyReserved(u, i));
+        }
+        if let Some(max) = rules.get_u32(tx, db::MAX_RESERVATIONS_KEY).await? {
+            let max = i64::from(max);
+            let current = db::count_user_reservations(tx, u).await?;
+            if current >= max {
+                return Err(ControlError::TooManyReservations {
+                    current,
+                    max,
+                    student: u,
+                });
+            }
+        }
+
+        Ok(ControlResult::Reserved(
+            db::create_reservation(tx, u, i).await?,
+        ))
+    }
+
+    async fn enroll(&mut self, u: i32, l: i32) -> Result<ControlResult, ControlError> {
+        let tx = self.guard()?;
+
+        if !db::student_exists(tx, u).await? {
+            return Err(ControlError::StudentNotFound(u));
+        }
+        if !db::student_is_active(tx, u).await? {
+            return Err(ControlError::StudentInactive(u));
+        }
+
+        // Held until the transaction ends so a concurrent enroll can not take the same place
+        let Some((max, booked)) = db::lock_lesson_bookings(tx, l).await? else {
+            return Err(ControlError::LessonNotFound(l));
+        };
+        if booked.contains(&u) {
+            return Err(ControlError::AlreadyEnrolled(u, l));
+        }
+        let max = usize::try_from(max).unwrap_or(0);
+        if booked.len() >= max {
+            return Err(ControlError::LessonFull {
+                lesson: l,
+                max,
+                booked: booked.len(),
+            });
+        }
+
+        db::book_lesson(tx, u, l).await?;
+        Ok(ControlResult::Enrolled {
+            student: u,
+            lesson: l,
+            free_places: max - booked.len() - 1,
+        })
+    }
+
+    async fn unenroll(&mut self, u: i32, l: i32) -> Result<ControlResult, ControlError> {
+        let tx = self.guard()?;
+
+        if !db::student_exists(tx, u).await? {
+            return Err(ControlError::StudentNotFound(u));
+        }
+        let Some((max, booked)) = db::lock_lesson_bookings(tx, l).await? else {
+            return Err(ControlError::LessonNotFound(l));
+        };
+        if db::unbook_lesson(tx, u, l).await? == 0 {
+            return Err(ControlError::NotEnrolled(u, l));
+        }
+
+        let max = usize::try_from(max).unwrap_or(0);
+        Ok(ControlResult::Unenrolled {
+            student: u,
+            lesson: l,
+            free_places: max.saturating_sub(booked.len() - 1),
+        })
+    }
+
+    async fn try_unreserve(
+        &mut self,
+        user: &str,
+        inst: &str,
+    ) -> Result<ControlResult, ControlError> {
+        let (u, i) = u_i_parse(user, inst)?;
+        let tx = self.guard()?;
+
+        let mut vec = db::find_reservations(tx, u, i).await?;
+
+        match vec.len() {
+            0 => Err(sqlx::Error::RowNotFound.into()),
+            1 => {
+                let id = vec.remove(0).get_id();
+                db::cancel_reservation(tx, id).await?;
+                Ok(ControlResult::Unreserved(id))
+            }
+            _ => Err(ControlError::UnreserveMultiple(vec)),
+        }
+    }
+
+    async fn unreserve(&mut self, id: &str) -> Result<ControlResult, ControlError> {
+        let id = id.parse::<i32>()?;
+        let tx = self.guard()?;
+
+        match db::get_reservation(tx, id).await? {
+            None => Err(ControlError::ReservationNotFound(id)),
+            Some(r) if !r.is_active() => Err(ControlError::ReservationNotActive(id)),
+            Some(_) => {
+                db::cancel_reservation(tx, id).await?;
+                Ok(ControlResult::Unreserved(id))
+            }
+        }
+    }
+
+    async fn reservations(&mut self, i: Option<i32>) -> Result<ControlResult, ControlError> {
+        let mut read = self.read_tx().await?;
+        let tx = &mut *read;
+
+        Ok(ControlResult::Reservations(
+            db::list_reservations(tx, i).await?,
+        ))
+    }
+
+    async fn quote(&mut self, user: &str, inst: &str) -> Result<ControlResult, ControlError> {
+        let (u, i) = u_i_parse(user, inst)?;
+        let mut read = self.read_tx().await?;
+        let tx = &mut *read;
+
+        if !db::student_exists(tx, u).await? {
+            return Err(ControlError::StudentNotFound(u));
+        }
+        if !db::instrument_exists(tx, i).await? {
+            return Err(ControlError::InstrumentNotFound(i));
+        }
+
+        // A quote may run in its own transaction, so it does not share the controller's rules
+        let (list_price, price) = price_for(tx, &mut db::RuleCache::default(), u, i).await?;
+        Ok(ControlResult::Quote { list_price, price })
+    }
+
+    async fn try_terminate(
+        &mut self,
+        user: &str,
+        inst: &str,
+        damage: Option<&Damage>,
+    ) -> Result<ControlResult, ControlError> {
+        let (u, i) = u_i_parse(user, inst)?;
+        let damage_amount = damage_amount(damage)?;
+        let (wait, locking) = (self.lock_wait, self.locking);
+        let (tx, rules) = self.guard_rules()?;
+
+        db::lock_rentings(tx, LockScope::StudentOrInstrument(u, i), wait, locking).await?;
+        if !db::student_exists(tx, u).await? {
+            return Err(ControlError::StudentNotFound(u));
+        }
+
+        let Some(r) = db::find_to_terminate(tx, u, i).await? else {
+            return Err(sqlx::Error::RowNotFound.into());
+        };
+
+        let renting = db::terminate_rid(tx, r.get_id(), None).await?;
+        if let Some(d) = damage {
+            db::record_damage(tx, renting.get_id(), &d.note, &damage_amount).await?;
+        }
+        let charge = charge_final_period(tx, rules, renting.get_id(), &damage_amount).await?;
+        let waiting = db::list_reservations(tx, Some(i)).await?;
+        Ok(ControlResult::TryTerminate {
+            renting,
+            charge,
+            waiting,
+        })
+    }
+
+    async fn terminate(
+        &mut self,
+        id: &str,
+        note: Option<&str>,
+        damage: Option<&Damage>,
+    ) -> Result<ControlResult, ControlError> {
+        let damage_amount = damage_amount(damage)?;
+        let (tx, rules) = self.guard_rules()?;
+        let i = id.parse::<i32>()?;
+        let renting = match db::terminate_rid(tx, i, note).await {
+            // Terminating twice would charge the final period and damage a second time
+            Err(DbError::NotFound) => {
+                return Err(match db::get_renting(tx, i).await {
+                    Ok(_) => ControlError::RentingAlreadyTerminated(i),
+                    Err(e) => e.into(),
+                })
+            }
+            r => r?,
+        };
+        if let Some(d) = damage {
+            db::record_damage(tx, i, &d.note, &damage_amount).await?;
+        }
+        let charge = charge_final_period(tx, rules, i, &damage_amount).await?;
+        let waiting = db::list_reservations(tx, Some(renting.get_instrument_id())).await?;
+
+        Ok(ControlResult::Terminate {
+            renting,
+            charge,
+            waiting,
+        })
+    }
+
+    async fn deactivate(&mut self, user: &str) -> Result<ControlResult, ControlError> {
+        let u = user.parse::<i32>()?;
+        let (wait, locking) = (self.lock_wait, self.locking);
+        let tx = self.guard()?;
+
+        // Checked under the lock, which waits for a student being removed or renting meanwhile
+        db::lock_rentings(tx, LockScope::StudentOnly(u), wait, locking).await?;
+        if !db::student_exists(tx, u).await? {
+            return Err(ControlError::StudentNotFound(u));
+        }
+        if db::count_user_rentals(tx, u).await? > 0 {
+            return Err(ControlError::Converted(String::from(
+                "Student has active rentals",
+            )));
+        }
+
+        db::deactivate_student(tx, u).await?;
+        Ok(ControlResult::Deactivated(u))
+    }
+
+    async fn damages(&mut self) -> Result<ControlResult, ControlError> {
+        let mut read = self.read_tx().await?;
+        let tx = &mut *read;
+
+        Ok(ControlResult::Damages(db::list_damages(tx).await?))
+    }
+
+    async fn payments(&mut self, u: i32) -> Result<ControlResult, ControlError> {
+        let mut read = self.read_tx().await?;
+        let tx = &mut *read;
+
+        if !db::student_exists(tx, u).await? {
+            return Err(ControlError::StudentNotFound(u));
+        }
+
+        Ok(ControlResult::Payments(db::list_charges(tx, u).await?))
+    }
+
+    async fn ledger(&mut self, u: i32) -> Result<ControlResult, ControlError> {
+        let mut read = self.read_tx().await?;
+        let tx = &mut *read;
+
+        if !db::student_exists(tx, u).await? {
+            return Err(ControlError::StudentNotFound(u));
+        }
+
+        let mut events = vec![];
+        for r in db::list_rental_history(tx, u).await? {
+            events.push((
+                r.get_start_date(),
+                LedgerEvent::RentalStarted {
+                    rent_id: r.get_id(),
+                    instrument: r.get_instrument(),
+                    price: r.get_agreed_price().clone(),
+                    deposit: r.get_deposit().clone(),
+                },
+                BigDecimal::from(0),
+            ));
+            if let Some(end) = r.get_end_date() {
+                let refund = deposit_refund(r.get_deposit(), &(r.get_late_fee() + r.get_damage()));
+                let withheld = r.get_deposit() - &refund;
+                events.push((
+                    end,
+                    LedgerEvent::RentalEnded {
+                        rent_id: r.get_id(),
+                        charge: r.get_charged().clone(),
+                        late_fee: r.get_late_fee().clone(),
+                        damage: r.get_damage().clone(),
+                        deposit_refund: refund,
+                    },
+                    r.get_charged() + r.get_damage() - withheld,
+                ));
+            }
+        }
+        // Lesson payments are charged when due and paid off when paid, so they cancel out once
+        // paid and the balance is everything the student owes
+        for p in db::list_student_payments(tx, u).await? {
+            events.push((
+                p.get_due_date(),
+                LedgerEvent::LessonCharge {
+                    payment_id: p.get_id(),
+                    payment_for: String::from(p.get_payment_for()),
+                },
+                p.get_amount().clone(),
+            ));
+            if let Some(paid_at) = p.get_paid_at() {
+                events.push((
+                    paid_at,
+                    LedgerEvent::Payment {
+                        payment_id: p.get_id(),
+                        payment_for: String::from(p.get_payment_for()),
+                    },
+                    -p.get_amount().clone(),
+                ));
+            }
+        }
+        events.sort_by_key(|(at, event, _)| (*at, event.sort_key()));
+
+        let mut balance = BigDecimal::from(0);
+        let entries = events
+            .into_iter()
+            .map(|(at, event, amount)| {
+                balance += &amount;
+                LedgerEntry {
+                    at,
+                    event,
+                    amount,
+                    balance: balance.clone(),
+                }
+            })
+            .collect();
+        Ok(ControlResult::Ledger { entries, balance })
+    }
+
+    async fn instructor(
+        &mut self,
+        id: i32,
+        range: Option<(String, String)>,
+    ) -> Result<ControlResult, ControlError> {
+        let range = match range {
+            Some((from, to)) => {
+                let (from, to) = (parse_date(&from)?, parse_date(&to)?);
+                if from > to {
+                    return Err(ControlError::Converted(format!(
+                        "Invalid range, {from} is after {to}"
+                    )));
+                }
+                Some((from, to))
+            }
+            None => None,
+        };
+        let mut read = self.read_tx().await?;
+        let tx = &mut *read;
+
+        if !db::instructor_exists(tx, id).await? {
+            return Err(ControlError::InstructorNotFound(id));
+        }
+
+        let lessons = db::list_instructor_lessons(tx, id, range, SCHOOL_TIME_ZONE).await?;
+        let this_month = db::count_instructor_lessons_this_month(tx, id, SCHOOL_TIME_ZONE).await?;
+        Ok(ControlResult::Instructor {
+            instructor: id,
+            range,
+            lessons,
+            this_month,
+        })
+    }
+
+    async fn lessons(&mut self, filter: LessonFilter) -> Result<ControlResult, ControlError> {
+        let day = filter.day.as_deref().map(parse_date).transpose()?;
+        let mut read = self.read_tx().await?;
+        let tx = &mut *read;
+
+        if let Some(u) = filter.student {
+            if !db::student_exists(tx, u).await? {
+                return Err(ControlError::StudentNotFound(u));
+            }
+        }
+
+        let lessons = db::list_lessons(tx, day, filter.student, SCHOOL_TIME_ZONE).await?;
+        Ok(ControlResult::Lessons {
+            day,
+            student: filter.student,
+            lessons,
+        })
+    }
+
+    async fn list(&mut self, filter: ListFilter) -> Result<ControlResult, ControlError> {
+        let mut read = self.read_tx().await?;
+        let tx = &mut *read;
+
+        let Some(s) = filter.rented_by else {
+            let t = filter.instrument_type.map(|t| format!("{t}%"));
+            if filter.limit.is_none() && filter.offset.is_none() {
+                return Ok(ControlResult::List(db::list_available(tx, t).await?));
+            }
+
+            let offset = filter.offset.unwrap_or_default();
+            let (instruments, total) =
+                db::list_page(tx, t, true, filter.limit.map(i64::from), i64::from(offset)).await?;
+            return Ok(ControlResult::ListPage {
+                instruments,
+                offset,
+                total,
+            });
+        };
+
+        let rows = db::list_rented_by(tx, s).await?;
+        if rows.is_empty() {
+            return Err(sqlx::Error::RowNotFound.into());
+        }
+
+        Ok(ControlResult::List(rows))
+    }
+
+    async fn list_all(&mut self, t: Option<String>) -> Result<ControlResult, ControlError> {
+        let mut read = self.read_tx().await?;
+        let tx = &mut *read;
+
+        let t = t.map(|t| format!("{t}%"));
+        Ok(ControlResult::ListAll(
+            db::list_with_availability(tx, t, false, None, 0).await?,
+        ))
+    }
+
+    async fn maintenance_on(
+        &mut self,
+        inst: &str,
+        note: &str,
+    ) -> Result<ControlResult, ControlError> {
+        let i = inst.parse::<i32>()?;
+        let (wait, locking) = (self.lock_wait, self.locking);
+        let tx = self.guard()?;
+
+        db::lock_rentings(tx, LockScope::InstrumentOnly(i), wait, locking).await?;
+        if !db::instrument_exists(tx, i).await? {
+            return Err(ControlError::InstrumentNotFound(i));
+        }
+        check_available(tx, i).await?;
+
+        Ok(ControlResult::MaintenanceStarted(
+            db::start_maintenance(tx, i, note).await?,
+        ))
+    }
+
+    async fn maintenance_off(&mut self, inst: &str) -> Result<ControlResult, ControlError> {
+        let i = inst.parse::<i32>()?;
+        let tx = self.guard()?;
+
+        db::end_maintenance(tx, i)
+            .await?
+            .map(ControlResult::MaintenanceEnded)
+            .ok_or(ControlError::NotInMaintenance(i))
+    }
+
+    async fn maintenance_list(&mut self) -> Result<ControlResult, ControlError> {
+        let mut read = self.read_tx().await?;
+        let tx = &mut *read;
+
+        Ok(ControlResult::Maintenance(
+            db::list_maintenance(tx, None).await?,
+        ))
+    }
+
+    async fn stats(&self) -> Result<ControlResult, ControlError> {
+        let rows = db::instrument_type_stats(self.pools.reader()).await?;
+        let total = db::TypeStats::total(&rows);
+
+        let mut ret: Vec<String> = rows.iter().map(ToString::to_string).collect();
+        ret.push(total.to_string());
+        Ok(ControlResult::Stats(ret))
+    }
+
+    async fn find_student(&mut self, name: &str) -> Result<ControlResult, ControlError> {
+        let mut read = self.read_tx().await?;
+        let tx = &mut *read;
+
+        let escaped = name
+            .trim()
+            .to_lowercase()
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        let students = db::find_students_by_name(tx, &format!("%{escaped}%")).await?;
+
+        if students.is_empty() {
+            Err(ControlError::StudentNameNotFound(name.trim().into()))
+        } else {
+            Ok(ControlResult::FindStudent(students))
+        }
+    }
+
+    async fn audit_log(&self, n: u32) -> Result<ControlResult, ControlError> {
+        let mut tx = db::begin_readonly_tx(self.pools.reader()).await?;
+        let entries = db::list_audit(&mut tx, i64::from(n)).await?;
+        tx.rollback().await?;
+
+        Ok(ControlResult::Audit(entries))
+    }
+
+    /// Loads the instrument types on a single connection of the primary pool, outside of any
+    /// transaction, and checks the max rentals rule on the way
+    async fn warmup(&mut self) -> Result<ControlResult, ControlError> {
+        let (types, _) = db::warmup(&self.pools.primary, &self.max_rentals_key).await?;
+
+        let types_loaded = types.len();
+        self.type_cache = Some((Instant::now(), types));
+        Ok(ControlResult::Warmup { types_loaded })
+    }
+
+    fn clear_cache(&mut self) -> ControlResult {
+        self.type_cache = None;
+        self.rules.clear();
+        ControlResult::CacheCleared
+    }
+
+    async fn instrument_types(&self) -> Result<ControlResult, ControlError> {
+        if let Some(types) = self.cached_instrument_types() {
+            return Ok(ControlResult::InstrumentTypes(types.to_vec()));
+        }
+        let mut tx = db::begin_readonly_tx(self.pools.reader()).await?;
+        let types = db::list_instrument_types(&mut tx).await?;
+        tx.rollback().await?;
+
+        Ok(ControlResult::InstrumentTypes(types))
+    }
+
+    async fn stats_types(&self) -> Result<ControlResult, ControlError> {
+        Ok(ControlResult::StatsTypes(
+            db::stats_by_type(self.pools.reader()).await?,
+        ))
+    }
+
+    async fn stats_lifetime(&self) -> Result<ControlResult, ControlError> {
+        let mut tx = db::begin_readonly_tx(self.pools.reader()).await?;
+        let mut instruments = db::list_all(&mut tx, None, 0).await?;
+        instruments.sort_by_key(db::Instrument::get_id);
+        let counts: HashMap<i32, db::InstrumentRentals> = db::count_rentals_per_instrument(&mut tx)
+            .await?
+            .into_iter()
+            .map(|c| (c.get_instrument_id(), c))
+            .collect();
+        tx.rollback().await?;
+
+        let ret = instruments
+            .into_iter()
+            .map(|i| {
+                let (active, total, repairs) = counts.get(&i.get_id()).map_or((0, 0, 0), |c| {
+                    (c.get_active(), c.get_total(), c.get_repairs())
+                });
+                let available = i64::from(i.get_count()) - active - repairs;
+                LifetimeRentals {
+                    instrument: InstrumentDto::from((i, available)),
+                    active,
+                    total,
+                }
+            })
+            .collect();
+
+        Ok(ControlResult::StatsLifetime(ret))
+    }
+
+    async fn stats_monthly(
+        &self,
+        months: u32,
+        include_archived: bool,
+    ) -> Result<ControlResult, ControlError> {
+        let months = positive_months(months)?;
+
+        Ok(ControlResult::StatsMonthly(
+            db::stats_monthly(
+                self.pools.reader(),
+                months,
+                SCHOOL_TIME_ZONE,
+                include_archived,
+            )
+            .await?,
+        ))
+    }
+
+    async fn archive(&mut self, months: u32) -> Result<ControlResult, ControlError> {
+        let months = positive_months(months)?;
+        let tx = self.guard()?;
+
+        Ok(ControlResult::Archived(
+            db::archive_rentings(tx, months).await?,
+        ))
+    }
+
+    async fn invoice(
+        &mut self,
+        student: &str,
+        year_month: &str,
+    ) -> Result<ControlResult, ControlError> {
+        let u = student.parse::<i32>()?;
+        let (month_start, next_month) = parse_year_month(year_month)?;
+        let mut read = self.read_tx().await?;
+        let tx = &mut *read;
+
+        if !db::student_exists(tx, u).await? {
+            return Err(ControlError::StudentNotFound(u));
+        }
+
+        let rentings = db::find_rentings_in_period(
+            tx,
+            u,
+            month_start.midnight().assume_utc(),
+            next_month.midnight().assume_utc(),
+        )
+        .await?;
+
+        let lines: Vec<InvoiceLine> = rentings
+            .iter()
+            .map(|r| {
+                prorate(
+                    r.get_id(),
+                    r.get_agreed_price(),
+                    r.get_start_date().date(),
+                    r.get_end_date().map(|e| e.date()),
+                    month_start,
+                    next_month,
+                )
+            })
+            .collect();
+        let total = lines.iter().map(|l| &l.amount).sum();
+        Ok(ControlResult::Invoice { lines, total })
+    }
+
+    async fn revenue(
+        &mut self,
+        from: &str,
+        to: &str,
+        include_archived: bool,
+    ) -> Result<ControlResult, ControlError> {
+        let (from, to) = (parse_date(from)?, parse_date(to)?);
+        if from > to {
+            return Err(ControlError::Converted(format!(
+                "Invalid range, {from} is after {to}"
+            )));
+        }
+        let mut read = self.read_tx().await?;
+        let tx = &mut *read;
+
+        let rows = db::revenue(tx, from, to, SCHOOL_TIME_ZONE, include_archived).await?;
+        let total = rows.iter().map(RevenueRow::get_total).sum();
+        Ok(ControlResult::Revenue { rows, total })
+    }
+
+    async fn student_cost(&mut self, u: i32) -> Result<ControlResult, ControlError> {
+        let mut read = self.read_tx().await?;
+        let tx = &mut *read;
+
+        if !db::student_exists(tx, u).await? {
+            return Err(ControlError::StudentNotFound(u));
+        }
+
+        let rentals = db::student_rental_costs(tx, u).await?;
+        let total = rentals.iter().map(RentalCost::get_price).sum();
+        Ok(ControlResult::StudentCost { rentals, total })
+    }
+
+    /// Returns the transaction for a read only command, which is the open transaction so that
+    /// uncommitted work is seen, or a new read only transaction on the reader pool if none is open
+    async fn read_tx<'b>(&'b mut self) -> Result<ReadTx<'b, 'a>, ControlError> {
+        if self.transaction.is_some() {
+            return Ok(ReadTx::Open(self.guard()?));
+        }
+        Ok(ReadTx::Own(Box::new(
+            db::begin_readonly_tx(self.pools.reader()).await?,
+        )))
+    }
+
+    fn guard<'b>(&'b mut self) -> Result<&'b mut Transaction<'a, Postgres>, ControlError> {
+        self.warn_long_transaction();
+        self.transaction
+            .as_mut()
+            .ok_or(ControlError::TransactionNone)
+    }
+
+    /// Like [`Controller`]`.guard()` but also returns the cache of the business rules read in the
+    /// transaction
+    fn guard_rules<'b>(
+        &'b mut self,
+    ) -> Result<(&'b mut Transaction<'a, Postgres>, &'b mut db::RuleCache), ControlError> {
+        self.warn_long_transaction();
+        let tx = self
+            .transaction
+            .as_mut()
+            .ok_or(ControlError::TransactionNone)?;
+        Ok((tx, &mut self.rules))
+    }
+
+    /// Prints a warning if the transaction has been open for too long, see
+    /// [`Controller`]`.txn_warning_due()`
+    fn warn_long_transaction(&mut self) {
+        if self.txn_warning_due() {
+            let secs = self.txn_warn_after.as_secs();
+            let open_for = if secs >= 60 && secs.is_multiple_of(60) {
+                format!("{} minutes", secs / 60)
+            } else {
+                format!("{secs} seconds")
+            };
+            eprintln!(
+                "Warning: transaction has been open for >{open_for}. Consider committing or rolling back."
+            );
+        }
+    }
+
+    /// Checks if the current transaction has been open longer than `txn_warn_after` and no
+    /// warning has been printed within the last [`TXN_WARN_INTERVAL`]
+    fn txn_warning_due(&mut self) -> bool {
+        let overdue = self
+            .transaction_start
+            .is_some_and(|s| s.elapsed() > self.txn_warn_after);
+        let recently_warned = self
+            .last_txn_warning
+            .is_some_and(|w| w.elapsed() < TXN_WARN_INTERVAL);
+
+        if overdue && !recently_warned {
+            self.last_txn_warning = Some(Instant::now());
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Checks that a savepoint name is a plain SQL identifier so it can be put in a query as is
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn u_i_parse(u: &str, i: &str) -> Result<(i32, i32), ControlError> {
+    Ok((u.parse::<i32>()?, i.parse::<i32>()?))
+}
+
+/// Checks that a unit of an instrument is neither rented out nor in maintenance
+///
+/// Fails with [`ControlError::InMaintenance`] if units in maintenance are why none is left,
+/// otherwise with [`ControlError::InstrumentUnavailable`]
+async fn check_available(tx: &mut Transaction<'_, Postgres>, i: i32) -> Result<(), ControlError> {
+    let on_shelf = db::shelf_stock(tx, Some(i))
+        .await?
+        .pop()
+        .map_or(0, |s| s.get_expected());
+    if on_shelf > db::count_instrument_maintenance(tx, i).await? {
+        return Ok(());
+    }
+
+    match db::list_maintenance(tx, Some(i)).await?.first() {
+        Some(m) => Err(ControlError::InMaintenance {
+            instrument: i,
+            note: m.get_note().into(),
+        }),
+        None => Err(ControlError::InstrumentUnavailable(i)),
+    }
+}
+
+/// Looks up the list price of an instrument and the price the user pays after discounts
+async fn price_for(
+    tx: &mut Transaction<'_, Postgres>,
+    rules: &mut db::RuleCache,
+    u: i32,
+    i: i32,
+) -> Result<(BigDecimal, BigDecimal), ControlError> {
+    let list_price = db::get_instrument_price(tx, i).await?;
+    let siblings = db::count_siblings(tx, u).await?;
+    let pct = rules.get_i64(tx, db::SIBLING_DISCOUNT_KEY, Some(0)).await?;
+    // A discount above 100 would make the price negative and one below 0 a surcharge
+    if !(0..=100).contains(&pct) {
+        return Err(ControlError::BadBusinessRule {
+            name: String::from(db::SIBLING_DISCOUNT_KEY),
+            value: pct.to_string(),
+        });
+    }
+
+    let price = discounted_price(&list_price, siblings, pct);
+    Ok((list_price, price))
+}
+
+/// Applies the sibling discount of `pct` percent if the student has any siblings, rounded to
+/// two decimals
+fn discounted_price(list_price: &BigDecimal, siblings: i64, pct: i64) -> BigDecimal {
+    if siblings > 0 {
+        (list_price * BigDecimal::from(100 - pct) / BigDecimal::from(100)).round(2)
+    } else {
+        list_price.round(2)
+    }
+}
+
+/// Looks up the deposit required to rent an instrument with the given list price
+///
+/// No deposit is required if either of the deposit rules is not set
+async fn deposit_for(
+    tx: &mut Transaction<'_, Postgres>,
+    rules: &mut db::RuleCache,
+    list_price: &BigDecimal,
+) -> Result<BigDecimal, ControlError> {
+    let threshold = rules.get_decimal(tx, db::DEPOSIT_THRESHOLD_KEY).await?;
+    let amount = rules.get_decimal(tx, db::DEPOSIT_AMOUNT_KEY).await?;
+
+    match (threshold, amount) {
+        (Some(threshold), Some(amount)) => Ok(required_deposit(list_price, &threshold, &amount)),
+        _ => Ok(BigDecimal::from(0)),
+    }
+}
+
+/// Returns `amount` if `list_price` exceeds `threshold` and zero otherwise
+fn required_deposit(
+    list_price: &BigDecimal,
+    threshold: &BigDecimal,
+    amount: &BigDecimal,
+) -> BigDecimal {
+    if list_price > threshold {
+        amount.round(2)
+    } else {
+        BigDecimal::from(0)
+    }
+}
+
+/// Returns what is left of `deposit` after the late fee and any damage charge are taken from it,
+/// never below zero
+fn deposit_refund(deposit: &BigDecimal, withheld: &BigDecimal) -> BigDecimal {
+    let refund = deposit - withheld;
+    if refund > BigDecimal::from(0) {
+        refund.round(2)
+    } else {
+        BigDecimal::from(0)
+    }
+}
+
+/// Parses the amount to charge for damage, zero if there is no damage or no amount was given
+fn damage_amount(damage: Option<&Damage>) -> Result<BigDecimal, ControlError> {
+    match damage.and_then(|d| d.amount.as_deref()) {
+        Some(a) => {
+            let amount = parse_decimal(a)?;
+            if amount < BigDecimal::from(0) {
+                return Err(ControlError::Converted(format!(
+                    "Invalid damage charge {a}, must not be negative"
+                )));
+            }
+            Ok(amount.round(2))
+        }
+        None => Ok(BigDecimal::from(0)),
+    }
+}
+
+fn parse_decimal(s: &str) -> Result<BigDecimal, ControlError> {
+    s.trim()
+        .parse::<BigDecimal>()
+        .map_err(|e| ControlError::Converted(format!("ParseBigDecimal error: {e}")))
+}
+
+/// Records the charge for the final period of a terminated renting, i.e. the prorated cost from
+/// the start of the month it ended in until its end date, plus a late fee if it ended after the
+/// lease. The deposit to refund is the renting's deposit less the late fee
+///
+/// Returns `None` and prints a warning if the database has no rental charges table
+async fn charge_final_period(
+    tx: &mut Transaction<'_, Postgres>,
+    rules: &mut db::RuleCache,
+    rent_id: i32,
+    damage: &BigDecimal,
+) -> Result<Option<TerminationCharge>, ControlError> {
+    if !db::charges_table_exists(tx).await? {
+        eprintln!("Warning: rental_charges table is missing, no charge was recorded.");
+        return Ok(None);
+    }
+
+    let r = db::get_renting(tx, rent_id).await?;
+    let start = db::local_date(tx, r.get_start_date(), SCHOOL_TIME_ZONE).await?;
+    let end = r.get_end_date().unwrap_or_else(OffsetDateTime::now_utc);
+    let end = db::local_date(tx, end, SCHOOL_TIME_ZONE).await?;
+    let (month_start, next_month) = month_bounds(end.year(), end.month())
+        .ok_or_else(|| ControlError::Converted(format!("Invalid date {end}")))?;
+
+    let line = prorate(
+        rent_id,
+        r.get_agreed_price(),
+        start,
+        Some(end),
+        month_start,
+        next_month,
+    );
+
+    let fee = rules.get_decimal(tx, db::LATE_FEE_KEY).await?;
+    let months = rules.get_u32(tx, db::LEASE_MONTHS_KEY).await?;
+    let late_fee = match (fee, months) {
+        (Some(fee), Some(months)) => {
+            let lease_end = add_months(start, months)
+                .ok_or_else(|| ControlError::Converted(format!("Invalid lease from {start}")))?;
+            late_fee(&fee, lease_end, end)
+        }
+        _ => BigDecimal::from(0),
+    };
+
+    let amount = line.amount + &late_fee;
+    db::record_charge(tx, rent_id, &amount, &late_fee).await?;
+    let deposit_refund = deposit_refund(r.get_deposit(), &(&late_fee + damage));
+    Ok(Some(TerminationCharge {
+        amount,
+        late_fee,
+        damage: damage.clone(),
+        deposit_refund,
+    }))
+}
+
+/// Checks that student `u` can rent instrument `i`, locking the rentings of both first
+///
+/// # Returns
+/// - Ok the list price, the price after discounts and the deposit of the rental
+/// - Err [`ControlError`] if the student or instrument does not exist, the student has too many
+///   rentals or no unit of the instrument is available
+async fn check_rent(
+    tx: &mut Transaction<'_, Postgres>,
+    rules: &mut db::RuleCache,
+    u: i32,
+    i: i32,
+    max_rentals_key: &str,
+    wait: LockWait,
+    locking: Locking,
+) -> Result<(BigDecimal, BigDecimal, BigDecimal), ControlError> {
+    // Checked before locking so unknown or archived ids fail with a clear error instead of
+    // locking nothing and failing on the foreign key when inserting
+    if !db::student_exists(tx, u).await? {
+        return Err(ControlError::StudentNotFound(u));
+    }
+    if !db::student_is_active(tx, u).await? {
+        return Err(ControlError::StudentInactive(u));
+    }
+    if !db::instrument_exists_and_active(tx, i).await? {
+        return Err(ControlError::InstrumentNotFound(i));
+    }
+
+    db::lock_rentings(tx, LockScope::StudentOrInstrument(u, i), wait, locking).await?;
+
+    // Checked under the lock so the unique index is only a backstop, a violation of it would
+    // abort the whole transaction
+    if db::rents_instrument(tx, u, i).await? {
+        return Err(ControlError::AlreadyRented(u, i));
+    }
+    let ur = db::count_user_rentals(tx, u).await?;
+    let max = rules.get_i64(tx, max_rentals_key, None).await?;
+    if ur >= max {
+        return Err(ControlError::TooManyRentals {
+            current: ur,
+            max,
+            student: u,
+        });
+    }
+
+    check_available(tx, i).await?;
+    let (list_price, price) = price_for(tx, rules, u, i).await?;
+    let deposit = deposit_for(tx, rules, &list_price).await?;
+    Ok((list_price, price, deposit))
+}
+
+/// Describes the termination of an active renting for [`Command::DryRun`]
+fn describe_termination(rent_id: i32, instrument: i32, damage: &BigDecimal) -> String {
+    if damage > &BigDecimal::from(0) {
+        format!(
+            "Would terminate rental {rent_id} of instrument {instrument} and charge {} for damage",
+            db::format_currency(damage)
+        )
+    } else {
+        format!("Would terminate rental {rent_id} of instrument {instrument}")
+    }
+}
+
+/// Calculates the late fee of `fee_per_day` for each whole day `returned` is after `lease_end`
+///
+/// Returning on or before the lease end, or a fee which is not positive, means no fee
+fn late_fee(fee_per_day: &BigDecimal, lease_end: Date, returned: Date) -> BigDecimal {
+    let days = (returned - lease_end).whole_days();
+    if days <= 0 || fee_per_day <= &BigDecimal::from(0) {
+        BigDecimal::from(0)
+    } else {
+        (fee_per_day * BigDecimal::from(days)).round(2)
+    }
+}
+
+/// Adds a number of months to a date, clamping the day to the length of the resulting month
+fn add_months(date: Date, months: u32) -> Option<Date> {
+    let total = i64::from(u8::from(date.month())) - 1 + i64::from(months);
+    let year = date.year() + i32::try_from(total / 12).ok()?;
+    let month = Month::try_from(u8::try_from(total % 12 + 1).ok()?).ok()?;
+
+    let (month_start, next_month) = month_bounds(year, month)?;
+    let days_in_month = u8::try_from((next_month - month_start).whole_days()).ok()?;
+    Date::from_calendar_date(year, month, date.day().min(days_in_month)).ok()
+}
+
+/// Parses a month given as YYYY-MM into the first day of that month and of the next month
+fn parse_year_month(s: &str) -> Result<(Date, Date), ControlError> {
+    let invalid = || ControlError::Converted(format!("Invalid month {s}, expected YYYY-MM"));
+
+    let (year, month) = s.trim().split_once('-').ok_or_else(invalid)?;
+    let year = year.parse::<i32>()?;
+    let month = Month::try_from(month.parse::<u8>()?).map_err(|_| invalid())?;
+
+    month_bounds(year, month).ok_or_else(invalid)
+}
+
+/// Parses a date given as YYYY-MM-DD
+fn parse_date(s: &str) -> Result<Date, ControlError> {
+    let invalid = || ControlError::Converted(format!("Invalid date {s}, expected YYYY-MM-DD"));
+
+    let mut parts = s.trim().splitn(3, '-');
+    let (Some(year), Some(month), Some(day)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    let month = Month::try_from(month.parse::<u8>()?).map_err(|_| invalid())?;
+
+    Date::from_calendar_date(year.parse::<i32>()?, month, day.parse::<u8>()?).map_err(|_| invalid())
+}
+
+/// Returns the first day of a month and the first day of the month after it
+fn month_bounds(year: i32, month: Month) -> Option<(Date, Date)> {
+    let next_year = if month == Month::December {
+        year + 1
+    } else {
+        year
+    };
+
+    let start = Date::from_calendar_date(year, month, 1).ok()?;
+    let next = Date::from_calendar_date(next_year, month.next(), 1).ok()?;
+    Some((start, next))
+}
+
+/// Prorates the monthly `price` of a renting by the number of days it was active during the
+/// month starting at `month_start`, rounded to two decimals
+///
+/// The start date counts as an active day while the end date does not
+fn prorate(
+    rent_id: i32,
+    price: &BigDecimal,
+    start: Date,
+    end: Option<Date>,
+    month_start: Date,
+    next_month: Date,
+) -> InvoiceLine {
+    let days_in_month = (next_month - month_start).whole_days();
+    let from = start.max(month_start);
+    let to = end.unwrap_or(next_month).min(next_month);
+    let days = (to - from).whole_days().max(0);
+
+    InvoiceLine {
+        rent_id,
+        days,
+        days_in_month,
+        price: price.clone(),
+        amount: (price * BigDecimal::from(days) / BigDecimal::from(days_in_month)).round(2),
+    }
+}
+
+/// Writes the rentals or the inventory to the file at `path`, replacing it if it exists and
+/// `replace` is set
+///
+/// The rows are streamed to `path` with [`PARTIAL_SUFFIX`] appended, printing how many have been
+/// written every [`db::EXPORT_PROGRESS_ROWS`] rows, and the file is only moved to `path` once
+/// every row is written. If the export fails the partial file is removed, leaving any earlier
+/// file at `path` as it was. Without `replace` the export fails instead of touching a file
+/// another export is writing or has written to `path`.
+///
+/// # Returns
+/// - [`usize`] the number of rows written, not counting the CSV header
+/// - [`ControlError`] if the file could not be created or written, or there is an sql error
+async fn export_file(
+    tx: &mut Transaction<'_, Postgres>,
+    what: ExportKind,
+    format: ExportFormat,
+    path: &str,
+    replace: bool,
+) -> Result<usize, ControlError> {
+    let failed =
+        |e: &dyn fmt::Display| ControlError::Converted(format!("Failed to write {path}: {e}"));
+    let partial = format!("{path}{PARTIAL_SUFFIX}");
+    let file = if replace {
+        File::create(&partial)
+    } else {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&partial)
+    };
+    let mut out = BufWriter::new(file.map_err(|e| failed(&e))?);
+    let mut progress = |n| eprintln!("Exported {n} rows to {path}...");
+    let written = match (what, format) {
+        (ExportKind::Rentals, ExportFormat::Csv) => {
+            db::export_rentals_csv(tx, &mut out, &mut progress).await
+        }
+        (ExportKind::Inventory, ExportFormat::Csv) => {
+            db::export_inventory_csv(tx, &mut out, &mut progress).await
+        }
+        (ExportKind::Rentals, ExportFormat::Json { pretty }) => {
+            db::export_rentals_json(tx, &mut out, pretty, &mut progress).await
+        }
+        (ExportKind::Inventory, ExportFormat::Json { pretty }) => {
+            db::export_inventory_json(tx, &mut out, pretty, &mut progress).await
+        }
+    };
+    drop(out);
+
+    let written = match written {
+        Ok(rows) => {
+            // A hard link fails if `path` exists, where a rename would replace it
+            let moved = if replace {
+                fs::rename(&partial, path)
+            } else {
+                fs::hard_link(&partial, path).and_then(|()| fs::remove_file(&partial))
+            };
+            moved.map(|()| rows).map_err(|e| failed(&e))
+        }
+        Err(sqlx::Error::Io(e)) => Err(failed(&e)),
+        Err(e) => Err(e.into()),
+    };
+    if written.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+
+    written
+}
+
+/// Converts a number of months given to a command to the `i32` the database expects, refusing 0
+fn positive_months(months: u32) -> Result<i32, ControlError> {
+    i32::try_from(months)
+        .ok()
+        .filter(|m| *m > 0)
+        .ok_or_else(|| ControlError::Converted(format!("Invalid number of months {months}")))
+}
+
+/// Extracts the offending id from a foreign key violation detail such as
+/// `Key (student_id)=(999) is not present in table "students".`
+fn fk_detail_id(detail: &str) -> Option<i32> {
+    let start = detail.find("=(")? + 2;
+    let end = start + detail[start..].find(')')?;
+    detail[start..end].parse().ok()
+}
+
+/// Extracts the two ids from a unique violation detail such as
+/// `Key (student_id, instrument_id)=(3, 4) already exists.`
+fn unique_detail_ids(detail: &str) -> Option<(i32, i32)> {
+    let start = detail.find("=(")? + 2;
+    let end = start + detail[start..].find(')')?;
+    let (a, b) = detail[start..end].split_once(',')?;
+    Some((a.trim().parse().ok()?, b.trim().parse().ok()?))
+}
+
+/// Parses the detail of a rental limit violation, given as `student current max`, into a
+/// [`ControlError::TooManyRentals`]
+fn rental_limit_detail(detail: &str) -> Option<ControlError> {
+    let mut parts = detail.split_whitespace();
+    let student = parts.next()?.parse().ok()?;
+    let current = parts.next()?.parse().ok()?;
+    let max = parts.next()?.parse().ok()?;
+
+    Some(ControlError::TooManyRentals {
+        current,
+        max,
+        student,
+    })
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
     const TEST_INSTRUMENT_ID: &str = "1";
+    /// An instrument with enough units to be rented several times at once
+    const TEST_MULTI_INSTRUMENT_ID: &str = "4";
     const TEST_RENT_ID: &str = "0";
     const TEST_STUDENT_ID: &str = "3";
 
-    async fn init<'a>() -> Controller<'a> {
+    async fn init<'a>() -> Controller<'a> {
+        let mut c = Controller::new().await;
+        c.begin(false).await.unwrap();
+        c
+    }
+
+    #[tokio::test]
+    async fn test_rent_too_many() {
+        let mut c = init().await;
+        let max = db::get_max_rentals(c.transaction.as_mut().unwrap(), db::MAX_RENTALS_KEY)
+            .await
+            .unwrap();
+
+        // A student can only rent each instrument once at a time, so rent different ones
+        let instruments = [TEST_MULTI_INSTRUMENT_ID, "3", "2", TEST_INSTRUMENT_ID];
+        let max_idx = usize::try_from(max).unwrap();
+        assert!(
+            max_idx < instruments.len(),
+            "Not enough instruments to exceed max"
+        );
+
+        for inst in &instruments[..max_idx] {
+            let v = c.rent(TEST_STUDENT_ID, inst).await;
+            match v {
+                Ok(r) => assert!(matches!(r, ControlResult::Rent { .. })),
+                Err(_) => {
+                    c.rollback(None).await.unwrap();
+                    panic!("Failed renting, wrong params for rent()?");
+                }
+            }
+        }
+
+        let v = c.rent(TEST_STUDENT_ID, instruments[max_idx]).await;
+        if v.is_ok() {
+            c.rollback(None).await.unwrap();
+            panic!("Renting should fail above max allowed")
+        }
+
+        assert_eq!(
+            v.unwrap_err(),
+            ControlError::TooManyRentals {
+                current: max,
+                max,
+                student: TEST_STUDENT_ID.parse().unwrap(),
+            }
+        );
+        c.rollback(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rent_already_rented() {
+        let mut c = init().await;
+
+        let v = c.rent(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID).await;
+        if v.is_err() {
+            c.rollback(None).await.unwrap();
+            panic!("Failed renting, wrong params for rent()?");
+        }
+
+        let v = c.rent(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID).await;
+        if v.is_ok() {
+            c.rollback(None).await.unwrap();
+            panic!("Renting the same instrument twice should return an error!")
+        }
+
+        assert_eq!(
+            v.unwrap_err(),
+            ControlError::AlreadyRented(
+                TEST_STUDENT_ID.parse().unwrap(),
+                TEST_MULTI_INSTRUMENT_ID.parse().unwrap()
+            )
+        );
+        // The check runs before the insert, so the transaction was not aborted
+        let v = c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        c.rollback(None).await.unwrap();
+        assert!(v.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_guard() {
+        let mut c = Controller::new().await;
+        let v = c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        assert_eq!(v.unwrap_err(), ControlError::TransactionNone);
+        let v = c
+            .try_terminate(TEST_STUDENT_ID, TEST_INSTRUMENT_ID, None)
+            .await;
+        assert_eq!(v.unwrap_err(), ControlError::TransactionNone);
+        let v = c.terminate(TEST_RENT_ID, None, None).await;
+        assert_eq!(v.unwrap_err(), ControlError::TransactionNone);
+        let v = c.list(ListFilter::default()).await;
+        assert!(matches!(v, Ok(ControlResult::List(_))));
+    }
+
+    #[tokio::test]
+    async fn test_rent_returns_id() {
+        let mut c = init().await;
+
+        let rent_id = match c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await {
+            Ok(ControlResult::Rent { rent_id, .. }) => rent_id,
+            _ => {
+                c.rollback(None).await.unwrap();
+                panic!("Failed renting, wrong params for rent()?");
+            }
+        };
+
+        let v = c.terminate(&rent_id.to_string(), None, None).await;
+        let missing = c.terminate("999999", None, None).await;
+        c.rollback(None).await.unwrap();
+
+        match v.unwrap() {
+            ControlResult::Terminate { renting, .. } => {
+                assert_eq!(renting.get_id(), rent_id);
+                assert!(renting.get_end_date().is_some());
+            }
+            v => panic!("Terminate should return ControlResult::Terminate, got {v:?}"),
+        }
+        assert!(missing.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_list_rented_by() {
+        let mut c = init().await;
+        let filter = || ListFilter {
+            rented_by: Some(TEST_STUDENT_ID.parse().unwrap()),
+            ..ListFilter::default()
+        };
+
+        let before = match c.list(filter()).await {
+            Ok(ControlResult::List(v)) => v.len(),
+            _ => 0,
+        };
+
+        if c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await.is_err() {
+            c.rollback(None).await.unwrap();
+            panic!("Failed renting, wrong params for rent()?");
+        }
+
+        let v = c.list(filter()).await.unwrap();
+        c.rollback(None).await.unwrap();
+
+        match v {
+            ControlResult::List(v) => {
+                assert!(v.len() >= before.max(1));
+                assert!(v.iter().any(|i| i.id.to_string() == TEST_INSTRUMENT_ID));
+            }
+            _ => panic!("List should return ControlResult::List"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stats_without_transaction() {
+        let c = Controller::new().await;
+        let types = db::instrument_type_stats(c.pools.reader()).await.unwrap();
+
+        match c.stats().await.unwrap() {
+            ControlResult::Stats(v) => {
+                assert_eq!(v.len(), types.len() + 1);
+                assert_eq!(v.last().unwrap(), &db::TypeStats::total(&types).to_string());
+            }
+            _ => panic!("Stats should return ControlResult::Stats"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stats_types() {
+        let c = Controller::new().await;
+        let types = db::instrument_type_stats(c.pools.reader()).await.unwrap();
+
+        match c.stats_types().await.unwrap() {
+            ControlResult::StatsTypes(v) => {
+                assert_eq!(v.len(), types.len());
+                assert!(v
+                    .windows(2)
+                    .all(|w| w[0].get_utilisation() >= w[1].get_utilisation()));
+            }
+            _ => panic!("Stats types should return ControlResult::StatsTypes"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stats_lifetime() {
+        let c = Controller::new().await;
+
+        match c.stats_lifetime().await.unwrap() {
+            ControlResult::StatsLifetime(v) => {
+                assert!(v
+                    .windows(2)
+                    .all(|w| w[0].instrument.id < w[1].instrument.id));
+                assert!(v.iter().all(|r| r.total >= r.active));
+                // Instrument 2 has both an active and an ended renting
+                let two = v.iter().find(|r| r.instrument.id == 2).unwrap();
+                assert!(two.active >= 1);
+                assert!(two.total > two.active);
+
+                // The grouped query counts the same as the queries per instrument
+                let mut tx = c.pools.primary.begin().await.unwrap();
+                for r in &v {
+                    let id = r.instrument.id;
+                    let active = db::count_active_instrument_rentals(&mut tx, id).await;
+                    let total = db::count_all_instrument_rentals(&mut tx, id).await;
+                    assert_eq!(active.unwrap(), r.active);
+                    assert_eq!(total.unwrap(), r.total);
+                }
+                tx.rollback().await.unwrap();
+            }
+            _ => panic!("Stats lifetime should return ControlResult::StatsLifetime"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stats_monthly() {
+        let c = Controller::new().await;
+        let mut conn = c.pools.reader().acquire().await.unwrap();
+        let today = db::local_date(&mut conn, OffsetDateTime::now_utc(), SCHOOL_TIME_ZONE)
+            .await
+            .unwrap();
+
+        match c.stats_monthly(12, false).await.unwrap() {
+            ControlResult::StatsMonthly(v) => {
+                assert_eq!(v.len(), 12);
+                assert!(v.windows(2).all(|w| w[0].get_month() < w[1].get_month()));
+                let last = v.last().unwrap().get_month();
+                assert_eq!(
+                    (last.year(), last.month(), last.day()),
+                    (today.year(), today.month(), 1)
+                );
+            }
+            _ => panic!("Stats monthly should return ControlResult::StatsMonthly"),
+        }
+        assert!(matches!(
+            c.stats_monthly(0, false).await,
+            Err(ControlError::Converted(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_student_not_found() {
+        let mut c = init().await;
+
+        let v = c.rent("999999", TEST_INSTRUMENT_ID).await;
+        assert_eq!(v.unwrap_err(), ControlError::StudentNotFound(999_999));
+        let v = c.try_terminate("999999", TEST_INSTRUMENT_ID, None).await;
+        assert_eq!(v.unwrap_err(), ControlError::StudentNotFound(999_999));
+
+        let tx = c.transaction.as_mut().unwrap();
+        let price = BigDecimal::from(1);
+        let e = db::rent(
+            tx,
+            999_999,
+            TEST_INSTRUMENT_ID.parse().unwrap(),
+            &price,
+            &price,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            ControlError::from(e),
+            ControlError::StudentNotFound(999_999)
+        );
+        c.rollback(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rental_limit_trigger() {
+        let mut c = init().await;
+        let u = TEST_STUDENT_ID.parse().unwrap();
+        let tx = c.transaction.as_mut().unwrap();
+        // Installed in the transaction, so it is rolled back with the rentings
+        db::create_rental_limit_trigger(tx).await.unwrap();
+        let (current, max) = db::get_rental_allowance(tx, u, db::MAX_RENTALS_KEY)
+            .await
+            .unwrap();
+
+        let price = BigDecimal::from(1);
+        let mut e = None;
+        // Each renting needs a different instrument, a student can rent each only once at a time
+        for i in (1..).take(usize::try_from(max - current + 1).unwrap()) {
+            if let Err(err) = db::rent(tx, u, i, &price, &price).await {
+                e = Some(err);
+                break;
+            }
+        }
+        c.rollback(None).await.unwrap();
+
+        assert_eq!(
+            ControlError::from(e.expect("Renting past the limit should fail")),
+            ControlError::TooManyRentals {
+                current: max,
+                max,
+                student: u
+            }
+        );
+        assert_eq!(rental_limit_detail("3 x 2"), None);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile() {
+        let mut c = init().await;
+
+        let expected = |v: Result<ControlResult, ControlError>| match v {
+            Ok(ControlResult::Reconcile { stock, .. }) => stock.get_expected(),
+            v => panic!("Reconcile should return ControlResult::Reconcile, got {v:?}"),
+        };
+        let before = expected(c.reconcile(TEST_INSTRUMENT_ID, "0").await);
+        if c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await.is_err() {
+            c.rollback(None).await.unwrap();
+            panic!("Failed renting, wrong params for rent()?");
+        }
+        let after = expected(c.reconcile(TEST_INSTRUMENT_ID, "0").await);
+
+        let report = c.reconcile_report().await;
+        let missing = c.reconcile("999999", "1").await;
+        let negative = c.reconcile(TEST_INSTRUMENT_ID, "-1").await;
+        c.rollback(None).await.unwrap();
+
+        assert_eq!(after, before - 1);
+        match report.unwrap() {
+            ControlResult::ReconcileReport(v) => {
+                assert!(v
+                    .iter()
+                    .any(|s| s.get_id().to_string() == TEST_INSTRUMENT_ID
+                        && s.get_expected() == after))
+            }
+            _ => panic!("Reconcile report should return ControlResult::ReconcileReport"),
+        }
+        assert_eq!(
+            missing.unwrap_err(),
+            ControlError::InstrumentNotFound(999_999)
+        );
+        assert!(matches!(negative, Err(ControlError::Converted(_))));
+    }
+
+    #[tokio::test]
+    async fn test_backup() {
+        let mut c = init().await;
+
+        let v = c.backup().await;
+        let again = c.backup().await;
+        c.rollback(None).await.unwrap();
+
+        let (
+            Ok(ControlResult::Backup { file, rows }),
+            Ok(ControlResult::Backup { file: second, .. }),
+        ) = (v, again)
+        else {
+            panic!("Backup should return ControlResult::Backup");
+        };
+        let content = std::fs::read_to_string(&file);
+        std::fs::remove_file(&file).unwrap();
+        std::fs::remove_file(&second).unwrap();
+        let content = content.unwrap();
+
+        assert!(file.starts_with("backup_") && file.ends_with(".csv"));
+        assert_eq!(content.lines().next(), Some(db::ActiveRental::CSV_HEADER));
+        assert_eq!(content.lines().count(), rows + 1);
+        assert_ne!(file, second);
+    }
+
+    #[tokio::test]
+    async fn test_reserve() {
+        const OTHER_STUDENT_ID: &str = "2";
+        let mut c = init().await;
+
+        let first = c.reserve(OTHER_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        let second = c.reserve(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        let queue = c.reservations(TEST_INSTRUMENT_ID.parse().ok()).await;
+        let ahead = match c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await {
+            Ok(ControlResult::Rent { reserved_ahead, .. }) => reserved_ahead,
+            v => {
+                c.rollback(None).await.unwrap();
+                panic!("Failed renting, got {v:?}");
+            }
+        };
+        let duplicate = c.reserve(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        let third = c.reserve(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID).await;
+        let over_limit = c.reserve(TEST_STUDENT_ID, "2").await;
+        let missing = c.reserve(TEST_STUDENT_ID, "999999").await;
+        c.rollback(None).await.unwrap();
+
+        let (Ok(ControlResult::Reserved(first)), Ok(ControlResult::Reserved(second))) =
+            (first, second)
+        else {
+            panic!("Reserve should return ControlResult::Reserved");
+        };
+        match queue.unwrap() {
+            ControlResult::Reservations(v) => {
+                let ids: Vec<i32> = v.iter().map(Reservation::get_id).collect();
+                let (a, b) = (
+                    ids.iter().position(|i| *i == first).unwrap(),
+                    ids.iter().position(|i| *i == second).unwrap(),
+                );
+                assert!(a < b);
+            }
+            _ => panic!("Reservations should return ControlResult::Reservations"),
+        }
+        assert!(ahead >= 1);
+        assert_eq!(
+            duplicate.unwrap_err(),
+            ControlError::AlreadyReserved(
+                TEST_STUDENT_ID.parse().unwrap(),
+                TEST_INSTRUMENT_ID.parse().unwrap()
+            )
+        );
+        assert!(matches!(third, Ok(ControlResult::Reserved(_))));
+        assert_eq!(
+            over_limit.unwrap_err(),
+            ControlError::TooManyReservations {
+                current: 2,
+                max: 2,
+                student: TEST_STUDENT_ID.parse().unwrap()
+            }
+        );
+        assert_eq!(
+            missing.unwrap_err(),
+            ControlError::InstrumentNotFound(999_999)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unreserve() {
+        let mut c = init().await;
+        let (u, i) = u_i_parse(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).unwrap();
+
+        // Made directly since reserve refuses a second one, but they can predate that check
+        let tx = c.transaction.as_mut().unwrap();
+        let mut ids = vec![];
+        for _ in 0..2 {
+            ids.push(db::create_reservation(tx, u, i).await.unwrap());
+        }
+
+        let multiple = c.try_unreserve(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        let by_id = c.unreserve(&ids[0].to_string()).await;
+        let again = c.unreserve(&ids[0].to_string()).await;
+        let single = c.try_unreserve(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        let none_left = c.try_unreserve(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        let missing = c.unreserve("999999").await;
+        c.rollback(None).await.unwrap();
+
+        assert!(matches!(multiple, Err(ControlError::UnreserveMultiple(v)) if v.len() == 2));
+        assert_eq!(by_id.unwrap(), ControlResult::Unreserved(ids[0]));
+        assert_eq!(
+            again.unwrap_err(),
+            ControlError::ReservationNotActive(ids[0])
+        );
+        assert_eq!(single.unwrap(), ControlResult::Unreserved(ids[1]));
+        assert!(none_left.is_err());
+        assert_eq!(
+            missing.unwrap_err(),
+            ControlError::ReservationNotFound(999_999)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_terminate_lists_waiting() {
+        const OTHER_STUDENT_ID: &str = "2";
+        let mut c = init().await;
+
+        let rent_id = match c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await {
+            Ok(ControlResult::Rent { rent_id, .. }) => rent_id,
+            v => {
+                c.rollback(None).await.unwrap();
+                panic!("Failed renting, got {v:?}");
+            }
+        };
+        let reserved = c.reserve(OTHER_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        let v = c.terminate(&rent_id.to_string(), None, None).await;
+        c.rollback(None).await.unwrap();
+
+        let Ok(ControlResult::Reserved(id)) = reserved else {
+            panic!("Reserve should return ControlResult::Reserved, got {reserved:?}");
+        };
+        match v.unwrap() {
+            ControlResult::Terminate {
+                renting, waiting, ..
+            } => {
+                assert_eq!(renting.get_id(), rent_id);
+                assert!(waiting.iter().any(|r| r.get_id() == id));
+                assert!(waiting.iter().all(Reservation::is_active));
+            }
+            v => panic!("Terminate should return ControlResult::Terminate, got {v:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_maintenance() {
+        let available = |r: &Result<ControlResult, ControlError>, id: i32| match r {
+            Ok(ControlResult::List(v)) => v.iter().find(|i| i.id == id).map(|i| i.available),
+            _ => None,
+        };
+        let mut c = init().await;
+
+        let before = c.list(ListFilter::default()).await;
+        let started = c.maintenance_on("4", "broken pedal").await;
+        let after = c.list(ListFilter::default()).await;
+        let last_unit = c.maintenance_on("2", "cracked lid").await;
+        let refused = c.rent(TEST_STUDENT_ID, "2").await;
+        let listed = c.maintenance_list().await;
+        let ended = c.maintenance_off("2").await;
+        let none_left = c.maintenance_off("2").await;
+        c.rollback(None).await.unwrap();
+
+        assert!(matches!(started, Ok(ControlResult::MaintenanceStarted(_))));
+        assert_eq!(available(&after, 4), available(&before, 4).map(|a| a - 1));
+        let Ok(ControlResult::MaintenanceStarted(id)) = last_unit else {
+            panic!(
+                "Maintenance should return ControlResult::MaintenanceStarted, got {last_unit:?}"
+            );
+        };
+        assert_eq!(
+            refused.unwrap_err(),
+            ControlError::InMaintenance {
+                instrument: 2,
+                note: "cracked lid".into()
+            }
+        );
+        match listed.unwrap() {
+            ControlResult::Maintenance(v) => {
+                assert!(v
+                    .iter()
+                    .any(|m| m.get_id() == id && m.get_instrument_id() == 2));
+            }
+            v => panic!("Maintenance list should return ControlResult::Maintenance, got {v:?}"),
+        }
+        assert_eq!(ended.unwrap(), ControlResult::MaintenanceEnded(id));
+        assert_eq!(none_left.unwrap_err(), ControlError::NotInMaintenance(2));
+    }
+
+    #[tokio::test]
+    async fn test_terminate_damaged() {
+        const DEPOSIT_INSTRUMENT_ID: &str = "3";
+        let mut c = init().await;
+
+        let (rent_id, deposit) = match c.rent(TEST_STUDENT_ID, DEPOSIT_INSTRUMENT_ID).await {
+            Ok(ControlResult::Rent {
+                rent_id, deposit, ..
+            }) => (rent_id, deposit),
+            v => {
+                c.rollback(None).await.unwrap();
+                panic!("Failed renting, got {v:?}");
+            }
+        };
+        let damage = Damage {
+            note: "cracked body".into(),
+            amount: Some("30".into()),
+        };
+        let v = c
+            .try_terminate(TEST_STUDENT_ID, DEPOSIT_INSTRUMENT_ID, Some(&damage))
+            .await;
+        let listed = c.damages().await;
+        let negative = Damage {
+            amount: Some("-1".into()),
+            ..damage
+        };
+        let invalid = c.terminate(TEST_RENT_ID, None, Some(&negative)).await;
+        c.rollback(None).await.unwrap();
+
+        match v.unwrap() {
+            ControlResult::TryTerminate {
+                renting,
+                charge: Some(charge),
+                ..
+            } => {
+                assert_eq!(renting.get_id(), rent_id);
+                assert_eq!(charge.damage, BigDecimal::from(30));
+                assert_eq!(
+                    charge.deposit_refund,
+                    deposit_refund(&deposit, &(&charge.late_fee + &charge.damage))
+                );
+            }
+            v => panic!("Terminate should record a charge, got {v:?}"),
+        }
+        match listed.unwrap() {
+            ControlResult::Damages(v) => assert!(v
+                .iter()
+                .any(|d| d.get_rent_id() == rent_id && d.get_amount() == &BigDecimal::from(30))),
+            v => panic!("Damages should return ControlResult::Damages, got {v:?}"),
+        }
+        assert!(matches!(invalid, Err(ControlError::Converted(_))));
+    }
+
+    #[tokio::test]
+    async fn test_instrument_types() {
+        let c = Controller::new().await;
+
+        match c.instrument_types().await.unwrap() {
+            ControlResult::InstrumentTypes(v) => {
+                assert!(!v.is_empty());
+                assert!(v.iter().any(|t| t.instrument_type == "guitar"));
+            }
+            v => panic!("Instrument types should return ControlResult::InstrumentTypes, got {v:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_note() {
+        let mut c = init().await;
+
+        let rent_id = match c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await {
+            Ok(ControlResult::Rent { rent_id, .. }) => rent_id,
+            v => {
+                c.rollback(None).await.unwrap();
+                panic!("Failed renting, got {v:?}");
+            }
+        };
+        let active = c
+            .add_note(&rent_id.to_string(), "returning after summer camp")
+            .await;
+        let cost = c.student_cost(TEST_STUDENT_ID.parse().unwrap()).await;
+        c.terminate(&rent_id.to_string(), None, None).await.unwrap();
+        let ended = c.add_note(&rent_id.to_string(), "came back early").await;
+        let missing = c.add_note("999999", "lost paperwork").await;
+        c.rollback(None).await.unwrap();
+
+        assert!(matches!(
+            active,
+            Ok(ControlResult::NoteAdded {
+                active: Some(true),
+                ..
+            })
+        ));
+        match cost.unwrap() {
+            ControlResult::StudentCost { rentals, .. } => assert!(rentals
+                .iter()
+                .any(|r| r.to_string().contains(": returning after summer camp"))),
+            v => panic!("Cost should return ControlResult::StudentCost, got {v:?}"),
+        }
+        assert!(matches!(
+            ended,
+            Ok(ControlResult::NoteAdded {
+                active: Some(false),
+                ..
+            })
+        ));
+        assert!(matches!(
+            missing,
+            Ok(ControlResult::NoteAdded {
+                rent_id: 999_999,
+                active: None,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_execution_time() {
+        let mut c = init().await;
+
+        let before = OffsetDateTime::now_utc();
+        let v = c.execute(Command::List(ListFilter::default())).await;
+        let after = OffsetDateTime::now_utc();
+        c.rollback(None).await.unwrap();
+
+        let r = v.unwrap();
+        assert!(matches!(r.result, ControlResult::List(_)));
+        // The command is timed inside the call, so it can not take longer than the call did
+        let call_us = (after - before).whole_microseconds();
+        assert!(i128::from(r.duration_us) <= call_us);
+        assert!(before <= r.executed_at && r.executed_at <= after);
+    }
+
+    #[tokio::test]
+    async fn test_fulfil() {
+        let mut c = init().await;
+
+        let tx = c.transaction.as_mut().unwrap();
+        let none_left = db::add_instrument(tx, "guitar", "Test", "Empty", &BigDecimal::from(1), 0)
+            .await
+            .unwrap();
+        let mut ids = vec![];
+        for i in [TEST_INSTRUMENT_ID, &none_left.to_string()] {
+            match c.reserve(TEST_STUDENT_ID, i).await {
+                Ok(ControlResult::Reserved(id)) => ids.push(id),
+                v => {
+                    c.rollback(None).await.unwrap();
+                    panic!("Failed reserving, got {v:?}");
+                }
+            }
+        }
+
+        let fulfilled = c.fulfil(&ids[0].to_string()).await;
+        let again = c.fulfil(&ids[0].to_string()).await;
+        let unavailable = c.fulfil(&ids[1].to_string()).await;
+        let tx = c.transaction.as_mut().unwrap();
+        let (first, second) = (
+            db::get_reservation(tx, ids[0]).await.unwrap().unwrap(),
+            db::get_reservation(tx, ids[1]).await.unwrap().unwrap(),
+        );
+        c.rollback(None).await.unwrap();
+
+        let Ok(ControlResult::Fulfilled {
+            reservation_id,
+            rent_id,
+            ..
+        }) = fulfilled
+        else {
+            panic!("Fulfil should return ControlResult::Fulfilled, got {fulfilled:?}");
+        };
+        assert_eq!(reservation_id, ids[0]);
+        assert!(!first.is_active());
+        assert_eq!(first.get_fulfilled_rent_id(), Some(rent_id));
+        assert_eq!(
+            again.unwrap_err(),
+            ControlError::ReservationNotActive(ids[0])
+        );
+        assert_eq!(
+            unavailable.unwrap_err(),
+            ControlError::InstrumentUnavailable(none_left)
+        );
+        assert!(second.is_active());
+    }
+
+    #[tokio::test]
+    async fn test_instrument_not_found() {
+        let mut c = init().await;
+
+        let v = c.rent(TEST_STUDENT_ID, "999999").await;
+        assert_eq!(v.unwrap_err(), ControlError::InstrumentNotFound(999_999));
+
+        let tx = c.transaction.as_mut().unwrap();
+        let price = BigDecimal::from(1);
+        let e = db::rent(
+            tx,
+            TEST_STUDENT_ID.parse().unwrap(),
+            999_999,
+            &price,
+            &price,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            ControlError::from(e),
+            ControlError::InstrumentNotFound(999_999)
+        );
+        c.rollback(None).await.unwrap();
+    }
+
+    #[test]
+    fn test_discounted_price() {
+        let p = |s: &str| s.parse::<BigDecimal>().unwrap();
+        assert_eq!(discounted_price(&p("101.01"), 0, 10), p("101.01"));
+        assert_eq!(discounted_price(&p("101.01"), 1, 10), p("90.91"));
+        assert_eq!(discounted_price(&p("202.02"), 2, 25), p("151.52"));
+        assert_eq!(discounted_price(&p("100"), 1, 0), p("100"));
+    }
+
+    #[tokio::test]
+    async fn test_add_instrument() {
+        let mut c = init().await;
+
+        let v = c.add_instrument("guitar", "Fender", "", "10", "1").await;
+        assert_eq!(
+            v.unwrap_err(),
+            ControlError::Converted("Model cannot be empty".into())
+        );
+
+        let v = c
+            .add_instrument("guitar", "Fender", "Stratocaster", "10", "1")
+            .await;
+        c.rollback(None).await.unwrap();
+        assert!(matches!(v.unwrap(), ControlResult::AddInstrument(_)));
+    }
+
+    #[tokio::test]
+    async fn test_agreed_price_kept() {
+        let mut c = init().await;
+        let (u, i) = u_i_parse(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).unwrap();
+
+        let price = match c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await {
+            Ok(ControlResult::Rent { price, .. }) => price,
+            _ => {
+                c.rollback(None).await.unwrap();
+                panic!("Failed renting, wrong params for rent()?");
+            }
+        };
+
+        let tx = c.transaction.as_mut().unwrap();
+        sqlx::query!(
+            "UPDATE instruments SET price = price + 50 WHERE instrument_id = $1;",
+            i
+        )
+        .execute(&mut **tx)
+        .await
+        .unwrap();
+
+        let renting = db::find_to_terminate(tx, u, i).await.unwrap();
+        c.rollback(None).await.unwrap();
+
+        assert!(renting
+            .unwrap()
+            .to_string()
+            .ends_with(&format!("agreed price {price:.2}")));
+    }
+
+    #[tokio::test]
+    async fn test_student_cost() {
+        let mut c = init().await;
+        let u = TEST_STUDENT_ID.parse().unwrap();
+
+        let before = match c.student_cost(u).await {
+            Ok(ControlResult::StudentCost { total, .. }) => total,
+            _ => {
+                c.rollback(None).await.unwrap();
+                panic!("Student cost should succeed");
+            }
+        };
+
+        let price = match c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await {
+            Ok(ControlResult::Rent { price, .. }) => price,
+            _ => {
+                c.rollback(None).await.unwrap();
+                panic!("Failed renting, wrong params for rent()?");
+            }
+        };
+
+        let v = c.student_cost(u).await;
+        c.rollback(None).await.unwrap();
+        match v.unwrap() {
+            ControlResult::StudentCost { rentals, total } => {
+                assert!(!rentals.is_empty());
+                assert_eq!(total, before + price);
+            }
+            _ => panic!("Student cost should return ControlResult::StudentCost"),
+        }
+    }
+
+    #[test]
+    fn test_parse_year_month() {
+        let d = |y, m, d| Date::from_calendar_date(y, m, d).unwrap();
+        assert_eq!(
+            parse_year_month("2024-11").unwrap(),
+            (d(2024, Month::November, 1), d(2024, Month::December, 1))
+        );
+        assert_eq!(
+            parse_year_month("2024-12").unwrap(),
+            (d(2024, Month::December, 1), d(2025, Month::January, 1))
+        );
+        assert!(parse_year_month("2024-13").is_err());
+        assert!(parse_year_month("2024").is_err());
+    }
+
+    #[test]
+    fn test_prorate() {
+        let d = |y, m, d| Date::from_calendar_date(y, m, d).unwrap();
+        let price = BigDecimal::from(90);
+        let (nov, dec) = parse_year_month("2024-11").unwrap();
+        let amount = |start, end| prorate(1, &price, start, end, nov, dec).amount;
+
+        // Active the whole month
+        assert_eq!(
+            amount(d(2024, Month::October, 1), None),
+            BigDecimal::from(90)
+        );
+        // Started mid-month
+        assert_eq!(
+            amount(d(2024, Month::November, 16), None),
+            BigDecimal::from(45)
+        );
+        // Terminated mid-month
+        assert_eq!(
+            amount(
+                d(2024, Month::October, 1),
+                Some(d(2024, Month::November, 11))
+            ),
+            BigDecimal::from(30)
+        );
+        // Started and terminated within the month
+        assert_eq!(
+            amount(
+                d(2024, Month::November, 5),
+                Some(d(2024, Month::November, 25))
+            ),
+            BigDecimal::from(60)
+        );
+        // Terminated after the month
+        assert_eq!(
+            amount(
+                d(2024, Month::November, 1),
+                Some(d(2025, Month::January, 5))
+            ),
+            BigDecimal::from(90)
+        );
+
+        // Leap year February has 29 days, 10 days of 100 is 34.48
+        let (feb, mar) = parse_year_month("2024-02").unwrap();
+        let line = prorate(
+            1,
+            &BigDecimal::from(100),
+            d(2024, Month::February, 20),
+            None,
+            feb,
+            mar,
+        );
+        assert_eq!((line.days, line.days_in_month), (10, 29));
+        assert_eq!(line.amount, "34.48".parse::<BigDecimal>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_terminate_records_charge() {
+        let mut c = init().await;
+
+        let (rent_id, price, deposit) = match c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await {
+            Ok(ControlResult::Rent {
+                rent_id,
+                price,
+                deposit,
+                ..
+            }) => (rent_id, price, deposit),
+            _ => {
+                c.rollback(None).await.unwrap();
+                panic!("Failed renting, wrong params for rent()?");
+            }
+        };
+
+        let (charge, refund) = match c.terminate(&rent_id.to_string(), None, None).await {
+            Ok(ControlResult::Terminate {
+                renting,
+                charge: Some(charge),
+                ..
+            }) if renting.get_id() == rent_id => (charge.amount, charge.deposit_refund),
+            v => {
+                c.rollback(None).await.unwrap();
+                panic!("Terminate should record a charge, got {v:?}");
+            }
+        };
+
+        let v = c.payments(TEST_STUDENT_ID.parse().unwrap()).await;
+        c.rollback(None).await.unwrap();
+
+        assert!(charge >= BigDecimal::from(0) && charge <= price);
+        assert_eq!(
+            (deposit, refund),
+            (BigDecimal::from(0), BigDecimal::from(0))
+        );
+        match v.unwrap() {
+            ControlResult::Payments(v) => {
+                assert!(v.iter().any(|p| p.to_string().contains(&format!(
+                    "renting {rent_id} of {}",
+                    db::format_currency(&charge)
+                ))));
+            }
+            _ => panic!("Payments should return ControlResult::Payments"),
+        }
+    }
+
+    #[test]
+    fn test_required_deposit() {
+        let dec = |s: &str| s.parse::<BigDecimal>().unwrap();
+        let (threshold, amount) = (dec("250"), dec("100"));
+
+        assert_eq!(
+            required_deposit(&dec("101.01"), &threshold, &amount),
+            BigDecimal::from(0)
+        );
+        assert_eq!(
+            required_deposit(&dec("250"), &threshold, &amount),
+            BigDecimal::from(0)
+        );
+        assert_eq!(
+            required_deposit(&dec("250.01"), &threshold, &amount),
+            amount
+        );
+        assert_eq!(
+            deposit_refund(&amount, &BigDecimal::from(15)),
+            BigDecimal::from(85)
+        );
+        assert_eq!(
+            deposit_refund(&amount, &BigDecimal::from(150)),
+            BigDecimal::from(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deposit_reduced_by_late_fee() {
+        const EXPENSIVE_INSTRUMENT_ID: &str = "4";
+        let mut c = init().await;
+
+        let (rent_id, deposit) = match c.rent(TEST_STUDENT_ID, EXPENSIVE_INSTRUMENT_ID).await {
+            Ok(ControlResult::Rent {
+                rent_id, deposit, ..
+            }) => (rent_id, deposit),
+            _ => {
+                c.rollback(None).await.unwrap();
+                panic!("Failed renting, wrong params for rent()?");
+            }
+        };
+
+        sqlx::query!(
+            "UPDATE rentings SET start_date = start_date - interval '12 months 10 days' WHERE rent_id = $1;",
+            rent_id
+        )
+        .execute(&mut **c.transaction.as_mut().unwrap())
+        .await
+        .unwrap();
+
+        let v = c.terminate(&rent_id.to_string(), None, None).await;
+        c.rollback(None).await.unwrap();
+
+        assert!(deposit > BigDecimal::from(0));
+        match v.unwrap() {
+            ControlResult::Terminate {
+                renting,
+                charge: Some(charge),
+                ..
+            } => {
+                assert_eq!(renting.get_id(), rent_id);
+                assert!(charge.late_fee > BigDecimal::from(0));
+                assert!(charge.deposit_refund < deposit);
+                assert_eq!(charge.deposit_refund, &deposit - &charge.late_fee);
+            }
+            v => panic!("Terminate should record a charge, got {v:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rollback_to_savepoint() {
+        let mut c = init().await;
+
+        sqlx::query("SAVEPOINT sp1;")
+            .execute(&mut **c.transaction.as_mut().unwrap())
+            .await
+            .unwrap();
+        let rent_id = match c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await {
+            Ok(ControlResult::Rent { rent_id, .. }) => rent_id,
+            _ => {
+                c.rollback(None).await.unwrap();
+                panic!("Failed renting, wrong params for rent()?");
+            }
+        };
+
+        let v = c.rollback(Some("sp1")).await;
+        let renting = db::get_renting(c.transaction.as_mut().unwrap(), rent_id).await;
+        let invalid = c.rollback(Some("sp1; DROP TABLE rentings")).await;
+        c.rollback(None).await.unwrap();
+
+        assert_eq!(v.unwrap(), ControlResult::Rollback(false));
+        assert!(renting.is_err());
+        assert!(matches!(invalid, Err(ControlError::Converted(_))));
+    }
+
+    #[tokio::test]
+    async fn test_revenue() {
+        let mut c = init().await;
+        let tx = c.transaction.as_mut().unwrap();
+        let today = db::local_date(tx, OffsetDateTime::now_utc(), SCHOOL_TIME_ZONE)
+            .await
+            .unwrap()
+            .to_string();
+
+        let total = |v: Result<ControlResult, ControlError>| match v {
+            Ok(ControlResult::Revenue { total, .. }) => total,
+            v => panic!("Revenue should return ControlResult::Revenue, got {v:?}"),
+        };
+
+        let before = total(c.revenue(&today, &today, false).await);
+        if c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await.is_err() {
+            c.rollback(None).await.unwrap();
+            panic!("Failed renting, wrong params for rent()?");
+        }
+        let after = total(c.revenue(&today, &today, false).await);
+
+        let inverted = c.revenue("2024-11-30", "2024-09-01", false).await;
+        let invalid = c.revenue("2024-02-30", "2024-03-01", false).await;
+        c.rollback(None).await.unwrap();
+
+        assert!(after > before);
+        assert!(matches!(inverted, Err(ControlError::Converted(e)) if e.contains("after")));
+        assert!(matches!(invalid, Err(ControlError::Converted(e)) if e.contains("YYYY-MM-DD")));
+    }
+
+    #[tokio::test]
+    async fn test_find_student() {
+        let mut c = init().await;
+
+        let kerr = c.find_student("KERR").await;
+        let partial = c.find_student(" eila k ").await;
+        let wildcard = c.find_student("%").await;
+        let missing = c.find_student("nobody by this name").await;
+        c.rollback(None).await.unwrap();
+
+        match kerr.unwrap() {
+            ControlResult::FindStudent(v) => {
+                assert!(!v.is_empty());
+                assert!(v
+                    .iter()
+                    .all(|s| s.to_string().to_lowercase().contains("kerr")));
+            }
+            _ => panic!("FindStudent should return ControlResult::FindStudent"),
+        }
+        assert!(matches!(partial, Ok(ControlResult::FindStudent(v)) if v.len() == 1));
+        assert!(matches!(
+            wildcard,
+            Err(ControlError::StudentNameNotFound(_))
+        ));
+        assert_eq!(
+            missing.unwrap_err(),
+            ControlError::StudentNameNotFound("nobody by this name".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_only_transaction() {
+        let mut c = Controller::new().await;
+        c.begin(true).await.unwrap();
+
+        assert!(c.list(ListFilter::default()).await.is_ok());
+        let v = c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        c.rollback(None).await.unwrap();
+        assert_eq!(v.unwrap_err(), ControlError::ReadOnlyTransaction);
+    }
+
+    #[test]
+    fn test_late_fee() {
+        let d = |y, m, d| Date::from_calendar_date(y, m, d).unwrap();
+        let fee = BigDecimal::from(5);
+        let due = d(2024, Month::November, 15);
+
+        assert_eq!(late_fee(&fee, due, due), BigDecimal::from(0));
+        assert_eq!(
+            late_fee(&fee, due, d(2024, Month::November, 16)),
+            BigDecimal::from(5)
+        );
+        assert_eq!(
+            late_fee(&fee, due, d(2024, Month::December, 1)),
+            BigDecimal::from(80)
+        );
+        assert_eq!(
+            late_fee(&fee, due, d(2024, Month::November, 1)),
+            BigDecimal::from(0)
+        );
+        assert_eq!(
+            late_fee(&BigDecimal::from(0), due, d(2024, Month::December, 1)),
+            BigDecimal::from(0)
+        );
+    }
+
+    #[test]
+    fn test_add_months() {
+        let d = |y, m, d| Date::from_calendar_date(y, m, d).unwrap();
+        assert_eq!(
+            add_months(d(2024, Month::January, 15), 12),
+            Some(d(2025, Month::January, 15))
+        );
+        assert_eq!(
+            add_months(d(2024, Month::November, 30), 3),
+            Some(d(2025, Month::February, 28))
+        );
+        assert_eq!(
+            add_months(d(2023, Month::December, 31), 2),
+            Some(d(2024, Month::February, 29))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_txn_warning() {
+        let mut c = init().await;
+        assert!(!c.txn_warning_due());
+
+        c.txn_warn_after = Duration::ZERO;
+        assert!(c.txn_warning_due());
+        assert!(
+            !c.txn_warning_due(),
+            "Warning should only be emitted once per interval"
+        );
+
+        c.rollback(None).await.unwrap();
+        assert!(!c.txn_warning_due());
+    }
+
+    #[tokio::test]
+    async fn test_audit() {
+        let mut c = init().await;
+        c.session = format!("test-audit-{}", std::process::id());
+
+        let rented = c
+            .execute(Command::Rent(
+                TEST_STUDENT_ID.into(),
+                TEST_MULTI_INSTRUMENT_ID.into(),
+            ))
+            .await;
+        let failed = c.execute(Command::Rent("x".into(), "y".into())).await;
+        let listed = c.execute(Command::List(ListFilter::default())).await;
+        c.execute(Command::Rollback(None)).await.unwrap();
+
+        let mut tx = c.pools.primary.begin().await.unwrap();
+        let entries: Vec<AuditEntry> = db::list_audit(&mut tx, 100)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|a| a.get_session() == c.session)
+            .collect();
+        sqlx::query("DELETE FROM audit_log WHERE session = $1")
+            .bind(&c.session)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        assert!(rented.is_ok());
+        assert!(failed.is_err());
+        assert!(listed.is_ok());
+        let commands: Vec<(&str, bool)> = entries
+            .iter()
+            .map(|a| (a.get_command(), a.is_succeeded()))
+            .collect();
+        assert_eq!(commands, vec![("rollback", true), ("rent x y", false)]);
+    }
+
+    #[tokio::test]
+    async fn test_audit_failure_is_warning() {
+        let mut c = init().await;
+        // Longer than the session column allows
+        c.session = "x".repeat(101);
+
+        let rented = c
+            .execute(Command::Rent(
+                TEST_STUDENT_ID.into(),
+                TEST_MULTI_INSTRUMENT_ID.into(),
+            ))
+            .await;
+        // The failed audit insert did not abort the transaction
+        let next = c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        let rolled_back = c.execute(Command::Rollback(None)).await;
+
+        let rented = rented.unwrap();
+        assert!(matches!(rented.result, ControlResult::Rent { .. }));
+        assert!(rented.audit_warning.is_some());
+        assert!(next.is_ok());
+        assert!(rolled_back.unwrap().audit_warning.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rent_archived() {
+        let mut c = init().await;
+
+        sqlx::query("UPDATE instruments SET archived = true WHERE instrument_id = $1;")
+            .bind(TEST_MULTI_INSTRUMENT_ID.parse::<i32>().unwrap())
+            .execute(&mut **c.transaction.as_mut().unwrap())
+            .await
+            .unwrap();
+        let archived = c.rent(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID).await;
+        let missing = c.rent(TEST_STUDENT_ID, "999999").await;
+        let no_student = c.rent("999999", "999999").await;
+        c.rollback(None).await.unwrap();
+
+        assert_eq!(
+            archived.unwrap_err(),
+            ControlError::InstrumentNotFound(TEST_MULTI_INSTRUMENT_ID.parse().unwrap())
+        );
+        assert_eq!(
+            missing.unwrap_err(),
+            ControlError::InstrumentNotFound(999_999)
+        );
+        assert_eq!(
+            no_student.unwrap_err(),
+            ControlError::StudentNotFound(999_999)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let mut c = init().await;
+
+        async fn counts(c: &mut Controller<'_>) -> Vec<i64> {
+            let mut v = vec![];
+            for table in ["rentings", "instruments", "maintenance", "damages"] {
+                let q = format!("SELECT COUNT(*) FROM {table} WHERE true;");
+                let n: i64 = sqlx::query_scalar(&q)
+                    .fetch_one(&mut **c.transaction.as_mut().unwrap())
+                    .await
+                    .unwrap();
+                v.push(n);
+            }
+            v
+        }
+        let dry = |c: Command| Command::DryRun(Box::new(c));
+
+        let rent_id = match c.rent(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID).await {
+            Ok(ControlResult::Rent { rent_id, .. }) => rent_id,
+            v => {
+                c.rollback(None).await.unwrap();
+                panic!("Failed renting, got {v:?}");
+            }
+        };
+        let before = counts(&mut c).await;
+        let rent = c
+            .dispatch(dry(Command::Rent(
+                TEST_STUDENT_ID.into(),
+                TEST_INSTRUMENT_ID.into(),
+            )))
+            .await;
+        let rented = c
+            .dispatch(dry(Command::Rent(
+                TEST_STUDENT_ID.into(),
+                TEST_MULTI_INSTRUMENT_ID.into(),
+            )))
+            .await;
+        let terminate = c
+            .dispatch(dry(Command::Terminate(
+                rent_id.to_string(),
+                Some(Damage {
+                    note: "dent".into(),
+                    amount: Some("50".into()),
+                }),
+            )))
+            .await;
+        let add = c
+            .dispatch(dry(Command::AddInstrument {
+                instrument_type: "guitar".into(),
+                brand: "Gibson".into(),
+                model: "J-45".into(),
+                price: "101.01".into(),
+                count: "1".into(),
+            }))
+            .await;
+        let no_type = c
+            .dispatch(dry(Command::AddInstrument {
+                instrument_type: "theremin".into(),
+                brand: "Moog".into(),
+                model: "Etherwave".into(),
+                price: "101.01".into(),
+                count: "1".into(),
+            }))
+            .await;
+        let real_no_type = c
+            .add_instrument("theremin", "Moog", "Etherwave", "101.01", "1")
+            .await;
+        let repair = c
+            .dispatch(dry(Command::MaintenanceOn(
+                TEST_MULTI_INSTRUMENT_ID.into(),
+                "broken string".into(),
+            )))
+            .await;
+        let missing = c
+            .dispatch(dry(Command::Rent(TEST_STUDENT_ID.into(), "999999".into())))
+            .await;
+        let list = c.dispatch(dry(Command::List(ListFilter::default()))).await;
+        let after = counts(&mut c).await;
+        c.rollback(None).await.unwrap();
+
+        assert_eq!(before, after);
+        assert!(matches!(rent, Ok(ControlResult::DryRun(s))
+            if s.starts_with("Would create rental of instrument 1 for student 3 at")));
+        assert_eq!(rented.unwrap_err(), ControlError::AlreadyRented(3, 4));
+        assert_eq!(
+            terminate.unwrap(),
+            ControlResult::DryRun(format!(
+                "Would terminate rental {rent_id} of instrument 4 and charge 50.00 for damage"
+            ))
+        );
+        assert!(matches!(add, Ok(ControlResult::DryRun(_))));
+        assert_eq!(no_type.unwrap_err(), real_no_type.unwrap_err());
+        assert!(matches!(repair, Ok(ControlResult::DryRun(_))));
+        assert_eq!(
+            missing.unwrap_err(),
+            ControlError::InstrumentNotFound(999_999)
+        );
+        assert_eq!(
+            list.unwrap_err(),
+            ControlError::DryRunUnsupported("list".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deactivate() {
+        let mut c = init().await;
+
+        let deactivated = c.deactivate(TEST_STUDENT_ID).await;
+        let rent = c.rent(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID).await;
+        let renting = c.deactivate("1").await;
+        let missing = c.deactivate("999999").await;
+        c.rollback(None).await.unwrap();
+
+        let u = TEST_STUDENT_ID.parse().unwrap();
+        assert_eq!(deactivated.unwrap(), ControlResult::Deactivated(u));
+        let e = rent.unwrap_err();
+        assert_eq!(e, ControlError::StudentInactive(u));
+        assert_eq!(
+            e.to_string(),
+            format!("Student {u} is deactivated and can not rent!")
+        );
+        assert_eq!(
+            renting.unwrap_err(),
+            ControlError::Converted("Student has active rentals".into())
+        );
+        assert_eq!(missing.unwrap_err(), ControlError::StudentNotFound(999_999));
+    }
+
+    #[tokio::test]
+    async fn test_execute_all() {
+        let mut c = init().await;
+        let rent = || Command::Rent(TEST_STUDENT_ID.into(), TEST_MULTI_INSTRUMENT_ID.into());
+        let bad = || Command::Rent("x".into(), "y".into());
+
+        let continued = c
+            .execute_all(
+                vec![rent(), bad(), Command::List(ListFilter::default())],
+                BatchOptions::default(),
+            )
+            .await;
+        let stopped = c
+            .execute_all(
+                vec![bad(), Command::List(ListFilter::default())],
+                BatchOptions {
+                    stop_on_error: true,
+                    ..Default::default()
+                },
+            )
+            .await;
+        let open = c
+            .execute_all(
+                vec![rent()],
+                BatchOptions {
+                    atomic: true,
+                    ..Default::default()
+                },
+            )
+            .await;
+        c.rollback(None).await.unwrap();
+
+        let atomic = c
+            .execute_all(
+                vec![rent(), bad()],
+                BatchOptions {
+                    atomic: true,
+                    ..Default::default()
+                },
+            )
+            .await;
+        let dangling = c.transaction.is_some();
+        let mut refused = vec![];
+        for sp in [
+            Command::Savepoint("sp".into()),
+            Command::RollbackTo("sp".into()),
+            Command::ReleaseSavepoint("sp".into()),
+        ] {
+            let batch = BatchOptions {
+                atomic: true,
+                ..Default::default()
+            };
+            refused.push(c.execute_all(vec![rent(), sp], batch).await);
+        }
+        let refused_dangling = c.transaction.is_some();
+        c.begin(false).await.unwrap();
+        let rentals = db::count_user_rentals(
+            c.transaction.as_mut().unwrap(),
+            TEST_STUDENT_ID.parse().unwrap(),
+        )
+        .await;
+        c.rollback(None).await.unwrap();
+
+        assert_eq!(continued.len(), 3);
+        assert!(matches!(continued[0], Ok(ControlResult::Rent { .. })));
+        assert!(continued[1].is_err());
+        assert!(matches!(continued[2], Ok(ControlResult::List(_))));
+        assert_eq!(stopped.len(), 1);
+        assert!(stopped[0].is_err());
+        assert_eq!(open, vec![Err(ControlError::TransactionOpen)]);
+        assert_eq!(atomic.len(), 2);
+        assert!(matches!(atomic[0], Ok(ControlResult::Rent { .. })));
+        assert!(atomic[1].is_err());
+        assert!(!dangling);
+        for r in refused {
+            assert!(
+                matches!(&r[..], [Err(ControlError::Converted(e))] if e.contains("atomic batch"))
+            );
+        }
+        assert!(!refused_dangling);
+        assert_eq!(rentals.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        let mut c = Controller::new().await;
+        let rent = || Command::Rent(TEST_STUDENT_ID.into(), TEST_MULTI_INSTRUMENT_ID.into());
+
+        let set = c.execute(Command::SetReadOnly(true)).await;
+        let autocommitted = c.execute(rent()).await;
+        let listed = c.execute(Command::List(ListFilter::default())).await;
+        c.execute(Command::Begin { force: false }).await.unwrap();
+        let begun = c.execute(rent()).await;
+        c.rollback(None).await.unwrap();
+        c.execute(Command::SetReadOnly(false)).await.unwrap();
+        c.execute(Command::Begin { force: false }).await.unwrap();
+        let writable = c.execute(rent()).await;
+        c.rollback(None).await.unwrap();
+
+        assert_eq!(set.unwrap().result, ControlResult::ReadOnly(true));
+        assert_eq!(
+            autocommitted.unwrap_err(),
+            ControlError::ReadOnlyTransaction
+        );
+        assert!(matches!(listed.unwrap().result, ControlResult::List(_)));
+        assert_eq!(begun.unwrap_err(), ControlError::ReadOnlyTransaction);
+        assert!(matches!(
+            writable.unwrap().result,
+            ControlResult::Rent { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_autocommit() {
+        let mut c = Controller::new().await;
+        let list = || Command::List(ListFilter::default());
+
+        let listed = c.execute(list()).await;
+        let after_list = c.transaction.is_some();
+        let failed = c
+            .execute(Command::Rent(TEST_STUDENT_ID.into(), "999999".into()))
+            .await;
+        let after_fail = c.transaction.is_some();
+
+        c.execute(Command::Begin { force: false }).await.unwrap();
+        let manual = c.autocommits(&list());
+        c.execute(Command::Rollback(None)).await.unwrap();
+
+        let off = c.execute(Command::SetAutocommit(false)).await;
+        let without = c
+            .execute(Command::Rent(
+                TEST_STUDENT_ID.into(),
+                TEST_MULTI_INSTRUMENT_ID.into(),
+            ))
+            .await;
+        let reconciled = c
+            .execute(Command::Reconcile(TEST_INSTRUMENT_ID.into(), "1".into()))
+            .await;
+        c.execute(Command::SetAutocommit(true)).await.unwrap();
+
+        assert!(matches!(
+            listed,
+            Ok(ControlResultWithMeta {
+                result: ControlResult::List(_),
+                ..
+            })
+        ));
+        assert!(!after_list);
+        assert_eq!(
+            failed.unwrap_err(),
+            ControlError::InstrumentNotFound(999_999)
+        );
+        assert!(!after_fail);
+        assert!(!manual);
+        assert!(matches!(
+            off.map(|r| r.result),
+            Ok(ControlResult::Autocommit(false))
+        ));
+        assert_eq!(without.unwrap_err(), ControlError::TransactionNone);
+        assert!(matches!(
+            reconciled.map(|r| r.result),
+            Ok(ControlResult::Reconcile { counted: 1, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_savepoints() {
+        let mut c = init().await;
+
+        let saved = c.savepoint("before_smith").await;
+        let rent_id = match c.rent(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID).await {
+            Ok(ControlResult::Rent { rent_id, .. }) => rent_id,
+            v => {
+                c.rollback(None).await.unwrap();
+                panic!("Failed renting, got {v:?}");
+            }
+        };
+        let rolled_back = c.dispatch(Command::RollbackTo("before_smith".into())).await;
+        let renting = db::get_renting(c.transaction.as_mut().unwrap(), rent_id).await;
+        let released = c.release_savepoint("before_smith").await;
+        let invalid = c.savepoint("sp1; DROP TABLE rentings").await;
+        let unknown = c.release_savepoint("before_smith").await;
+        c.rollback(None).await.unwrap();
+        let none = c.savepoint("sp1").await;
+
+        assert_eq!(
+            saved.unwrap(),
+            ControlResult::Savepoint("before_smith".into())
+        );
+        assert_eq!(rolled_back.unwrap(), ControlResult::Rollback(false));
+        assert!(renting.is_err());
+        assert_eq!(
+            released.unwrap(),
+            ControlResult::Released("before_smith".into())
+        );
+        assert!(matches!(invalid, Err(ControlError::Converted(_))));
+        assert_eq!(
+            unknown.unwrap_err(),
+            ControlError::SavepointNotFound("before_smith".into())
+        );
+        assert_eq!(none.unwrap_err(), ControlError::TransactionNone);
+    }
+
+    #[tokio::test]
+    async fn test_with_savepoint() {
+        let mut c = init().await;
+
+        let before = c
+            .rent(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID)
+            .await
+            .is_ok();
+        let failed = c
+            .with_savepoint("batch_rent", async |c| {
+                c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await?;
+                c.with_savepoint("inner", async |c| c.rent("x", "y").await)
+                    .await?;
+                Ok(())
+            })
+            .await;
+        let rentals = db::count_user_rentals(
+            c.transaction.as_mut().unwrap(),
+            TEST_STUDENT_ID.parse().unwrap(),
+        )
+        .await;
+        let ok = c
+            .with_savepoint("batch_rent", async |c| c.list(ListFilter::default()).await)
+            .await;
+        let invalid = c.with_savepoint("a b", async |_| Ok(())).await;
+        // Releasing the savepoint inside makes the rollback to it fail, aborting the transaction
+        let cleanup = c
+            .with_savepoint("gone", async |c| -> Result<(), ControlError> {
+                c.savepoint_query("RELEASE SAVEPOINT", "gone").await?;
+                Err(ControlError::Converted("inner".into()))
+            })
+            .await;
+        c.rollback(None).await.unwrap();
+
+        assert!(before);
+        assert!(matches!(failed, Err(ControlError::Converted(_))));
+        assert_eq!(rentals.unwrap(), 1);
+        assert!(matches!(ok, Ok(ControlResult::List(_))));
+        assert!(matches!(invalid, Err(ControlError::Converted(_))));
+        assert_eq!(
+            cleanup.unwrap_err(),
+            ControlError::SavepointCleanupFailed {
+                name: "gone".into(),
+                error: Box::new(ControlError::Converted("inner".into())),
+                cleanup: Box::new(ControlError::SavepointNotFound("gone".into())),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_without_transaction() {
+        let mut c = Controller::new().await;
+        let listed = |v: Result<ControlResult, ControlError>| match v {
+            Ok(ControlResult::List(v)) => v.iter().any(|i| {
+                i.to_string()
+                    .starts_with(&format!("ID:{TEST_INSTRUMENT_ID} "))
+            }),
+            v => panic!("List should return ControlResult::List, got {v:?}"),
+        };
+
+        let without = c.list(ListFilter::default()).await;
+        let cost = c.student_cost(TEST_STUDENT_ID.parse().unwrap()).await;
+        let still_none = c.transaction.is_none();
+
+        c.begin(false).await.unwrap();
+        if c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await.is_err() {
+            c.rollback(None).await.unwrap();
+            panic!("Failed renting, wrong params for rent()?");
+        }
+        let within = c.list(ListFilter::default()).await;
+        c.rollback(None).await.unwrap();
+
+        assert!(listed(without));
+        assert!(matches!(cost, Ok(ControlResult::StudentCost { .. })));
+        assert!(still_none);
+        assert!(!listed(within));
+    }
+
+    #[tokio::test]
+    async fn test_begin_open_transaction() {
+        let mut c = init().await;
+
+        let rent_id = match c.rent(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID).await {
+            Ok(ControlResult::Rent { rent_id, .. }) => rent_id,
+            v => {
+                c.rollback(None).await.unwrap();
+                panic!("Failed renting, got {v:?}");
+            }
+        };
+        let again = c.begin(false).await;
+        let read_only = c.begin(true).await;
+        let kept = db::get_renting(c.transaction.as_mut().unwrap(), rent_id).await;
+        let forced = c.dispatch(Command::Begin { force: true }).await;
+        let dropped = db::get_renting(c.transaction.as_mut().unwrap(), rent_id).await;
+        c.rollback(None).await.unwrap();
+        let fresh = c.dispatch(Command::Begin { force: true }).await;
+        c.rollback(None).await.unwrap();
+
+        assert_eq!(again.unwrap_err(), ControlError::TransactionOpen);
+        assert_eq!(read_only.unwrap_err(), ControlError::TransactionOpen);
+        assert!(kept.is_ok());
+        assert_eq!(forced.unwrap(), ControlResult::Begin(true));
+        assert!(dropped.is_err());
+        assert_eq!(fresh.unwrap(), ControlResult::Begin(false));
+    }
+
+    #[tokio::test]
+    async fn test_commit_failure() {
+        /// Forwards the first connection to `upstream` until a commit is sent on it, then drops
+        /// the commit along with the connection, refusing every connection after the first
+        async fn proxy_dropping_commit(upstream: (String, u16)) -> u16 {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                let (mut client, _) = listener.accept().await.unwrap();
+                drop(listener);
+                let mut server = tokio::net::TcpStream::connect(upstream).await.unwrap();
+                let (mut client_read, mut client_write) = client.split();
+                let (mut server_read, mut server_write) = server.split();
+                let to_server = async {
+                    let mut buf = vec![0; 8192];
+                    loop {
+                        let n = client_read.read(&mut buf).await?;
+                        if n == 0 || buf[..n].windows(6).any(|w| w == b"COMMIT") {
+                            return Ok::<_, std::io::Error>(());
+                        }
+                        server_write.write_all(&buf[..n]).await?;
+                    }
+                };
+                let to_client = tokio::io::copy(&mut server_read, &mut client_write);
+                tokio::select! {
+                    _ = to_server => {}
+                    _ = to_client => {}
+                }
+            });
+            port
+        }
+
+        let mut c = init().await;
+        let _ = c.release_savepoint("missing").await;
+        let aborted = c.commit().await;
+        let none = c.transaction.is_none();
+
+        c.begin(false).await.unwrap();
+        c.rent(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID)
+            .await
+            .unwrap();
+        let pid: i32 = sqlx::query_scalar("SELECT pg_backend_pid();")
+            .fetch_one(&mut **c.transaction.as_mut().unwrap())
+            .await
+            .unwrap();
+        sqlx::query("SELECT pg_terminate_backend($1, 5000);")
+            .bind(pid)
+            .execute(&c.pools.primary)
+            .await
+            .unwrap();
+        let lost = c.commit().await;
+        let lost_none = c.transaction.is_none();
+
+        // The connection drops while the commit is sent, and as the server can not be reached
+        // afterwards it is unknown if the commit arrived
+        let options = c.pools.primary.connect_options();
+        let port =
+            proxy_dropping_commit((options.get_host().to_string(), options.get_port())).await;
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(1)
+            .acquire_timeout(Duration::from_secs(1))
+            .connect_with(
+                (*options)
+                    .clone()
+                    .host("127.0.0.1")
+                    .port(port)
+                    .ssl_mode(sqlx::postgres::PgSslMode::Disable),
+            )
+            .await
+            .unwrap();
+        let mut proxied = Controller::with_pools(DbPools {
+            primary: pool,
+            replica: None,
+        });
+        proxied.begin(false).await.unwrap();
+        proxied
+            .rent(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID)
+            .await
+            .unwrap();
+        let uncertain = proxied.commit().await;
+
+        assert!(matches!(aborted, Err(ControlError::CommitRolledBack(_))));
+        assert!(none);
+        assert!(matches!(lost, Err(ControlError::CommitRolledBack(_))));
+        assert!(lost_none);
+        assert!(matches!(uncertain, Err(ControlError::CommitUncertain(_))));
+        assert!(proxied.transaction.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sql() {
+        let mut c = init().await;
+        let select = || {
+            Command::Sql(format!(
+                "SELECT student_id, name, active, NULL::date AS \"none\", 1.50::numeric AS \"price\",
+                10::numeric(10, 2) AS \"fee\", 0.00001::numeric AS \"small\"
+                FROM students NATURAL JOIN person_details WHERE student_id = {TEST_STUDENT_ID}"
+            ))
+        };
+
+        let denied = c.dispatch(select()).await;
+        c.allow_sql(true);
+        let selected = c.dispatch(select()).await;
+        let updated = c
+            .dispatch(Command::Sql(format!(
+                "UPDATE students SET active = true WHERE student_id = {TEST_STUDENT_ID}"
+            )))
+            .await;
+        let two = c.dispatch(Command::Sql("SELECT 1; SELECT 2".into())).await;
+        let commit = c.dispatch(Command::Sql("/* done */ commit".into())).await;
+        let open = c.transaction.is_some();
+        c.rollback(None).await.unwrap();
+
+        assert_eq!(denied.unwrap_err(), ControlError::SqlNotAllowed);
+        let Ok(ControlResult::Sql(SqlOutput::Rows { columns, rows })) = selected else {
+            panic!("Expected rows, got {selected:?}");
+        };
+        assert_eq!(
+            columns,
+            [
+                "student_id",
+                "name",
+                "active",
+                "none",
+                "price",
+                "fee",
+                "small"
+            ]
+        );
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][0], TEST_STUDENT_ID);
+        assert!(!rows[0][1].is_empty());
+        assert_eq!(rows[0][2..], ["true", "NULL", "1.50", "10.00", "0.00001"]);
+        assert_eq!(updated.unwrap(), ControlResult::Sql(SqlOutput::Affected(1)));
+        assert!(two.is_err());
+        assert!(commit.is_err());
+        assert!(open);
+    }
+
+    #[tokio::test]
+    async fn test_export() {
         let mut c = Controller::new().await;
-        c.begin().await.unwrap();
-        c
+        let path = env::temp_dir().join(format!("sgdb-export-{}.csv", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+
+        let exported = c
+            .export(ExportKind::Inventory, ExportFormat::Csv, path.clone())
+            .await;
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let unwritable = c
+            .export(
+                ExportKind::Rentals,
+                ExportFormat::Csv,
+                "/nonexistent/dir/rentals.csv".into(),
+            )
+            .await;
+
+        let Ok(ControlResult::Export { rows, .. }) = exported else {
+            panic!("Expected an export, got {exported:?}");
+        };
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], db::InventoryRow::CSV_HEADER);
+        assert_eq!(lines.len(), rows + 1);
+        assert!(lines[1].starts_with("1,"));
+        assert!(matches!(
+            unwritable,
+            Err(ControlError::Converted(e)) if e.starts_with("Failed to write /nonexistent/dir/rentals.csv")
+        ));
     }
 
     #[tokio::test]
-    async fn test_rent_too_many() {
+    async fn test_export_failure_removes_partial_file() {
+        let mut c = Controller::new().await;
+        // A file can not be renamed over a directory which is not empty, so the export fails
+        // after every row is written
+        let dir = env::temp_dir().join(format!("sgdb-export-dir-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("keep")).unwrap();
+        let path = dir.to_string_lossy().to_string();
+
+        let failed = c
+            .export(ExportKind::Inventory, ExportFormat::Csv, path.clone())
+            .await;
+        let partial_left = std::path::Path::new(&format!("{path}{PARTIAL_SUFFIX}")).exists();
+        let dir_kept = dir.join("keep").is_dir();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(
+            matches!(failed, Err(ControlError::Converted(e)) if e.starts_with("Failed to write"))
+        );
+        assert!(!partial_left);
+        assert!(dir_kept);
+    }
+
+    #[tokio::test]
+    async fn test_list_hides_fully_rented() {
+        let listed = |r: &Result<ControlResult, ControlError>, id: i32| match r {
+            Ok(ControlResult::List(v)) => v.iter().any(|i| i.id == id),
+            v => panic!("List should return ControlResult::List, got {v:?}"),
+        };
+        let id = TEST_INSTRUMENT_ID.parse::<i32>().unwrap();
+        let mut c = init().await;
+
+        let before = c.list(ListFilter::default()).await;
+        let rented = c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        let after = c.list(ListFilter::default()).await;
+        c.rollback(None).await.unwrap();
+
+        // The test instrument only has a single unit
+        assert!(listed(&before, id));
+        assert!(matches!(rented, Ok(ControlResult::Rent { .. })));
+        assert!(!listed(&after, id));
+        let Ok(ControlResult::List(v)) = after else {
+            unreachable!()
+        };
+        assert!(v.iter().all(|i| i.available > 0));
+    }
+
+    #[tokio::test]
+    async fn test_archive() {
+        let total = |r: Result<ControlResult, ControlError>| match r {
+            Ok(ControlResult::Revenue { total, .. }) => total,
+            v => panic!("Revenue should return ControlResult::Revenue, got {v:?}"),
+        };
         let mut c = init().await;
-        let max = db::get_max_rentals(c.transaction.as_mut().unwrap())
+        let today = OffsetDateTime::now_utc().date().to_string();
+        let tx = c.transaction.as_mut().unwrap();
+        let mut seeded = vec![];
+        // An old and a recent terminated renting, and an old renting which is still active
+        for (start, end) in [
+            ("2020-01-01", Some("2020-03-01")),
+            ("2024-01-01", Some(today.as_str())),
+            ("2020-01-01", None),
+        ] {
+            let id = sqlx::query!(
+                "INSERT INTO rentings (student_id, instrument_id, start_date, end_date, agreed_price)
+                 VALUES (3, 3, $1::text::date, $2::text::date, 100) RETURNING rent_id;",
+                start,
+                end
+            )
+            .fetch_one(&mut **tx)
             .await
             .unwrap()
-            .parse::<i64>()
+            .rent_id;
+            seeded.push(id);
+        }
+
+        let before = total(c.revenue("2020-01-01", "2020-02-29", false).await);
+        let archived = c.archive(24).await;
+        let tx = c.transaction.as_mut().unwrap();
+        let left: Vec<i32> = sqlx::query_scalar!(
+            "SELECT rent_id FROM rentings WHERE rent_id = ANY($1) ORDER BY rent_id;",
+            &seeded
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .unwrap();
+        let moved: Vec<i32> = sqlx::query_scalar!(
+            "SELECT rent_id FROM rentings_archive WHERE rent_id = ANY($1);",
+            &seeded
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .unwrap();
+        let in_archive =
+            sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM rentings_archive;"#)
+                .fetch_one(&mut **tx)
+                .await
+                .unwrap();
+        let without = total(c.revenue("2020-01-01", "2020-02-29", false).await);
+        let with = total(c.revenue("2020-01-01", "2020-02-29", true).await);
+        let zero = c.archive(0).await;
+        c.rollback(None).await.unwrap();
+
+        // The test data has a renting which ended in 2022, which is archived as well
+        assert_eq!(
+            archived.unwrap(),
+            ControlResult::Archived(u64::try_from(in_archive).unwrap())
+        );
+        assert_eq!(moved, vec![seeded[0]]);
+        assert_eq!(left, vec![seeded[1], seeded[2]]);
+        assert!(before > BigDecimal::from(0));
+        assert_eq!(with, before);
+        assert_eq!(without, &before - BigDecimal::from(200));
+        assert!(matches!(zero, Err(ControlError::Converted(_))));
+    }
+
+    /// The reports which must not change when rentings are archived
+    async fn archive_reports(c: &mut Controller<'_>) -> Vec<Result<ControlResult, ControlError>> {
+        vec![
+            c.payments(3).await,
+            c.damages().await,
+            c.invoice(TEST_STUDENT_ID, "2020-02").await,
+            c.stats_lifetime().await,
+            c.ledger(3).await,
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_archive_keeps_history() {
+        let mut c = init().await;
+        let tx = c.transaction.as_mut().unwrap();
+        let rent_id = sqlx::query_scalar!(
+            "INSERT INTO rentings (student_id, instrument_id, start_date, end_date, agreed_price)
+             VALUES (3, 3, '2020-01-01', '2020-03-01', 100) RETURNING rent_id;"
+        )
+        .fetch_one(&mut **tx)
+        .await
+        .unwrap();
+        db::record_charge(tx, rent_id, &BigDecimal::from(100), &BigDecimal::from(5))
+            .await
             .unwrap();
+        db::record_damage(tx, rent_id, "dent", &BigDecimal::from(20))
+            .await
+            .unwrap();
+        let reservation = sqlx::query_scalar!(
+            "INSERT INTO reservations (student_id, instrument_id, created_at, active, fulfilled_rent_id)
+             VALUES (3, 3, '2019-12-20', false, $1) RETURNING reservation_id;",
+            rent_id
+        )
+        .fetch_one(&mut **tx)
+        .await
+        .unwrap();
 
-        for _ in 0..max {
-            let v = c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
-            if v.is_err() {
-                c.rollback().await.unwrap();
-                panic!("Failed renting, wrong params for rent()?");
-            } else {
-                assert_eq!(v.unwrap(), ControlResult::Rent(1));
+        let before = archive_reports(&mut c).await;
+        let archived = c.archive(24).await;
+        let after = archive_reports(&mut c).await;
+        let tx = c.transaction.as_mut().unwrap();
+        let fulfilled = db::get_reservation(tx, reservation).await.unwrap();
+        let renting = db::get_renting(tx, rent_id).await;
+        let terminated = c.terminate(&rent_id.to_string(), None, None).await;
+        let tx = c.transaction.as_mut().unwrap();
+        let dangling =
+            db::record_charge(tx, 999_999, &BigDecimal::from(1), &BigDecimal::from(0)).await;
+        c.rollback(None).await.unwrap();
+
+        assert!(matches!(archived, Ok(ControlResult::Archived(n)) if n >= 1));
+        assert!(before.iter().all(Result::is_ok), "{before:?}");
+        assert_eq!(before, after);
+        assert_eq!(fulfilled.unwrap().get_fulfilled_rent_id(), Some(rent_id));
+        assert_eq!(renting.unwrap().get_id(), rent_id);
+        assert_eq!(
+            terminated,
+            Err(ControlError::RentingAlreadyTerminated(rent_id))
+        );
+        assert!(dangling.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_max_rentals_key() {
+        let mut c = init().await;
+        c.max_rentals_key("rent_max_count_trial");
+        let tx = c.transaction.as_mut().unwrap();
+        sqlx::query!(
+            "INSERT INTO business_rules (name, value) VALUES ('rent_max_count_trial', '0');"
+        )
+        .execute(&mut **tx)
+        .await
+        .unwrap();
+        let max = db::get_max_rentals(tx, "rent_max_count_trial").await;
+        let missing = db::get_max_rentals(tx, "rent_max_count_missing").await;
+
+        let refused = c.rent(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID).await;
+        c.rollback(None).await.unwrap();
+
+        assert_eq!(max.unwrap(), 0);
+        assert!(matches!(missing, Err(RuleError::Missing(n)) if n == "rent_max_count_missing"));
+        assert!(matches!(
+            refused,
+            Err(ControlError::TooManyRentals { max: 0, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rental_limit_trigger_uses_max_rentals_key() {
+        let mut c = Controller::new().await;
+        c.max_rentals_key("rent_max_count_trial");
+        c.begin(false).await.unwrap();
+        let tx = c.transaction.as_mut().unwrap();
+        db::create_rental_limit_trigger(tx).await.unwrap();
+        sqlx::query!(
+            "INSERT INTO business_rules (name, value) VALUES ('rent_max_count_trial', '3');"
+        )
+        .execute(&mut **tx)
+        .await
+        .unwrap();
+        let max = db::get_max_rentals(tx, db::MAX_RENTALS_KEY).await.unwrap();
+
+        let mut rented = vec![];
+        for i in [TEST_INSTRUMENT_ID, TEST_MULTI_INSTRUMENT_ID, "3"] {
+            rented.push(c.rent(TEST_STUDENT_ID, i).await);
+        }
+        c.rollback(None).await.unwrap();
+
+        // More rentings than the default rule allows, which the trigger would refuse
+        assert!(max < 3);
+        assert!(rented.iter().all(Result::is_ok), "{rented:?}");
+    }
+
+    #[tokio::test]
+    async fn test_list_all() {
+        let find = |r: &Result<ControlResult, ControlError>, id: i32| match r {
+            Ok(ControlResult::List(v) | ControlResult::ListAll(v)) => {
+                v.iter().find(|i| i.id == id).cloned()
+            }
+            v => panic!("List should return a list of instruments, got {v:?}"),
+        };
+        let id = TEST_INSTRUMENT_ID.parse::<i32>().unwrap();
+        let mut c = init().await;
+
+        let rented = c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        let list = c.list(ListFilter::default()).await;
+        let all = c.list_all(None).await;
+        let typed = c.list_all(Some(String::from("gui"))).await;
+        c.rollback(None).await.unwrap();
+
+        assert!(matches!(rented, Ok(ControlResult::Rent { .. })));
+        assert!(find(&list, id).is_none());
+        let full = find(&all, id).expect("ListAll should include fully rented instruments");
+        assert_eq!(full.available, 0);
+        assert!(!full.to_string().contains('['));
+        let Ok(ControlResult::ListAll(v)) = typed else {
+            panic!("ListAll should return ControlResult::ListAll, got {typed:?}");
+        };
+        assert!(v.iter().all(|i| i.type_name == "guitar"));
+    }
+
+    #[tokio::test]
+    async fn test_ledger() {
+        let u = TEST_STUDENT_ID.parse::<i32>().unwrap();
+        let mut c = init().await;
+
+        let empty = c.ledger(u).await;
+        let missing = c.ledger(999_999).await;
+        let rent_id = match c.rent(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID).await {
+            Ok(ControlResult::Rent { rent_id, .. }) => rent_id,
+            v => {
+                c.rollback(None).await.unwrap();
+                panic!("Failed renting, got {v:?}");
+            }
+        };
+        let damage = Damage {
+            note: "scratched".into(),
+            amount: Some("12.34".into()),
+        };
+        let terminated = c.terminate(&rent_id.to_string(), None, Some(&damage)).await;
+        let tx = c.transaction.as_mut().unwrap();
+        // One lesson paid ahead of when it is due and one not paid yet
+        sqlx::query!(
+            "INSERT INTO payments (student_id, instructor_id, payment_for, amount, due_date, paid, paid_at, outgoing)
+             VALUES ($1, 1, 'guitar lesson', 0.1, CURRENT_TIMESTAMP + interval '1 month', true, CURRENT_TIMESTAMP, false),
+                    ($1, 1, 'piano lesson', 5, CURRENT_TIMESTAMP + interval '2 months', false, NULL, false);",
+            u
+        )
+        .execute(&mut **tx)
+        .await
+        .unwrap();
+        let ledger = c.ledger(u).await;
+        c.rollback(None).await.unwrap();
+
+        assert_eq!(
+            empty.unwrap(),
+            ControlResult::Ledger {
+                entries: vec![],
+                balance: BigDecimal::from(0)
+            }
+        );
+        assert_eq!(missing.unwrap_err(), ControlError::StudentNotFound(999_999));
+        let Ok(ControlResult::Terminate {
+            charge: Some(charge),
+            ..
+        }) = terminated
+        else {
+            panic!("Terminate should record a charge, got {terminated:?}");
+        };
+        let Ok(ControlResult::Ledger { entries, balance }) = ledger else {
+            panic!("Ledger should return ControlResult::Ledger, got {ledger:?}");
+        };
+
+        // Renting, terminating and paying in one transaction happen at the same time, the
+        // lessons are charged when they are due
+        assert_eq!(entries.len(), 5);
+        assert!(entries[..3].iter().all(|e| e.at == entries[0].at));
+        assert!(
+            matches!(entries[0].event, LedgerEvent::RentalStarted { rent_id: r, .. } if r == rent_id)
+        );
+        assert!(
+            matches!(entries[1].event, LedgerEvent::RentalEnded { rent_id: r, .. } if r == rent_id)
+        );
+        let LedgerEvent::Payment { payment_id, .. } = entries[2].event else {
+            panic!("Expected the payment third, got {:?}", entries[2].event);
+        };
+        assert!(
+            matches!(entries[3].event, LedgerEvent::LessonCharge { payment_id: p, .. } if p == payment_id)
+        );
+        assert!(entries[3].at > entries[2].at);
+        assert!(matches!(entries[4].event, LedgerEvent::LessonCharge { .. }));
+
+        let LedgerEvent::RentalEnded {
+            deposit_refund,
+            damage,
+            ..
+        } = &entries[1].event
+        else {
+            unreachable!()
+        };
+        assert_eq!(damage, &"12.34".parse::<BigDecimal>().unwrap());
+        assert_eq!(deposit_refund, &charge.deposit_refund);
+        let LedgerEvent::RentalStarted { deposit, .. } = &entries[0].event else {
+            unreachable!()
+        };
+        let rentals = &charge.amount + damage - (deposit - deposit_refund);
+        assert_eq!(
+            entries[2].balance,
+            &rentals - "0.1".parse::<BigDecimal>().unwrap()
+        );
+        // Only the unpaid lesson is still owed
+        assert_eq!(balance, rentals + BigDecimal::from(5));
+        assert_eq!(
+            entries.iter().map(|e| &e.amount).sum::<BigDecimal>(),
+            balance
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lessons() {
+        let lessons = |r: Result<ControlResult, ControlError>| match r {
+            Ok(ControlResult::Lessons { lessons, .. }) => lessons,
+            v => panic!("Lessons should return ControlResult::Lessons, got {v:?}"),
+        };
+        let filter = |day: Option<&str>, student: Option<i32>| LessonFilter {
+            day: day.map(String::from),
+            student,
+        };
+        let mut c = Controller::new().await;
+
+        let all = lessons(c.lessons(filter(None, None)).await);
+        // The test lessons start 2022-05-20 15:00 at -09:00, which is the 21st at the school
+        let on_day = lessons(c.lessons(filter(Some("2022-05-21"), None)).await);
+        let day_before = lessons(c.lessons(filter(Some("2022-05-20"), None)).await);
+        let booked = lessons(c.lessons(filter(None, Some(1))).await);
+        let missing = c.lessons(filter(None, Some(999_999))).await;
+        let invalid = c.lessons(filter(Some("2022-13-01"), None)).await;
+
+        let basic = all.iter().find(|l| l.get_id() == 1).unwrap();
+        assert_eq!(basic.get_kind(), db::LessonKind::Individual);
+        assert_eq!(basic.get_subject(), "basic guitar");
+        assert_eq!((basic.get_booked(), basic.get_max_places()), (1, 1));
+        assert!(!basic.get_instructors().is_empty());
+        let ensemble = all.iter().find(|l| l.get_id() == 2).unwrap();
+        assert_eq!(ensemble.get_kind(), db::LessonKind::Ensemble);
+        assert_eq!(ensemble.get_subject(), "classic piano");
+        assert!(all
+            .windows(2)
+            .all(|w| w[0].get_start_date() <= w[1].get_start_date()));
+
+        assert!([1, 2]
+            .iter()
+            .all(|id| on_day.iter().any(|l| l.get_id() == *id)));
+        assert!(day_before.iter().all(|l| l.get_id() > 2));
+        assert_eq!(
+            booked.iter().map(Lesson::get_id).collect::<Vec<_>>(),
+            vec![1]
+        );
+        assert_eq!(missing.unwrap_err(), ControlError::StudentNotFound(999_999));
+        assert!(matches!(invalid, Err(ControlError::Converted(_))));
+    }
+
+    #[tokio::test]
+    async fn test_enroll() {
+        let mut c = init().await;
+        let u = TEST_STUDENT_ID.parse().unwrap();
+
+        // Lesson 2 has 40 places with one booked, lesson 1 has its only place booked
+        let enrolled = c.enroll(u, 2).await;
+        let again = c.enroll(u, 2).await;
+        let full = c.enroll(u, 1).await;
+        let missing = c.enroll(u, 999_999).await;
+        let unenrolled = c.unenroll(u, 2).await;
+        let not_enrolled = c.unenroll(u, 2).await;
+        c.rollback(None).await.unwrap();
+
+        assert_eq!(
+            enrolled.unwrap(),
+            ControlResult::Enrolled {
+                student: u,
+                lesson: 2,
+                free_places: 38
+            }
+        );
+        assert_eq!(again.unwrap_err(), ControlError::AlreadyEnrolled(u, 2));
+        assert_eq!(
+            full.unwrap_err(),
+            ControlError::LessonFull {
+                lesson: 1,
+                max: 1,
+                booked: 1
+            }
+        );
+        assert_eq!(missing.unwrap_err(), ControlError::LessonNotFound(999_999));
+        assert_eq!(
+            unenrolled.unwrap(),
+            ControlResult::Unenrolled {
+                student: u,
+                lesson: 2,
+                free_places: 39
+            }
+        );
+        assert_eq!(not_enrolled.unwrap_err(), ControlError::NotEnrolled(u, 2));
+    }
+
+    #[tokio::test]
+    async fn test_instructor() {
+        let mut c = init().await;
+
+        // Instructor 1 teaches lesson 1, on 2022-05-21 at the school
+        let range = Some(("2022-05-01".into(), "2022-05-31".into()));
+        let may = c.instructor(1, range).await;
+        let upcoming = c.instructor(1, None).await;
+        let missing = c.instructor(999_999, None).await;
+        let reversed = c
+            .instructor(1, Some(("2022-05-31".into(), "2022-05-01".into())))
+            .await;
+        c.rollback(None).await.unwrap();
+
+        let Ok(ControlResult::Instructor { lessons, range, .. }) = may else {
+            panic!("Listing the lessons of an instructor failed: {may:?}");
+        };
+        assert_eq!(lessons.len(), 1);
+        assert_eq!(lessons[0].get_id(), 1);
+        assert!(range.is_some());
+        let Ok(ControlResult::Instructor { lessons, .. }) = upcoming else {
+            panic!("Listing the upcoming lessons of an instructor failed: {upcoming:?}");
+        };
+        assert!(lessons
+            .iter()
+            .all(|l| l.get_start_date() >= OffsetDateTime::now_utc()));
+        assert_eq!(
+            missing.unwrap_err(),
+            ControlError::InstructorNotFound(999_999)
+        );
+        assert!(matches!(reversed, Err(ControlError::Converted(_))));
+    }
+
+    /// Builds one command of every variant, each paired with if it matches the pattern it is
+    /// listed under. The patterns are also matched without a wildcard, which fails to compile
+    /// when a variant is added to [`Command`] without being listed
+    macro_rules! every_command {
+        ($($pat:pat => $sample:expr),+ $(,)?) => {{
+            const fn covered(c: &Command) {
+                match c {
+                    $($pat => {})+
+                }
+            }
+            vec![$({
+                let c = $sample;
+                covered(&c);
+                let listed = matches!(c, $pat);
+                (c, listed)
+            }),+]
+        }};
+    }
+
+    #[tokio::test]
+    async fn all_commands_are_handled() {
+        let s = || String::from("1");
+        let export = std::env::temp_dir().join("sgdb_all_commands_rentals.csv");
+        let commands = every_command! {
+            Command::AddInstrument { .. } => Command::AddInstrument {
+                instrument_type: "guitar".into(),
+                brand: "Gibson".into(),
+                model: "J-45".into(),
+                price: s(),
+                count: s(),
+            },
+            Command::AddNote(..) => Command::AddNote(s(), "note".into()),
+            Command::Archive(_) => Command::Archive(12),
+            Command::Audit(_) => Command::Audit(5),
+            Command::Backup => Command::Backup,
+            Command::Begin { .. } => Command::Begin { force: false },
+            Command::BeginReadOnly => Command::BeginReadOnly,
+            Command::Commit => Command::Commit,
+            Command::Damages => Command::Damages,
+            Command::Deactivate(_) => Command::Deactivate(s()),
+            Command::DryRun(_) => Command::DryRun(Box::new(Command::Rent(s(), s()))),
+            Command::Enroll { .. } => Command::Enroll { student: 1, lesson: 1 },
+            Command::Export { .. } => Command::Export {
+                what: ExportKind::Rentals,
+                format: ExportFormat::Csv,
+                path: export.to_string_lossy().into_owned(),
+            },
+            Command::FindStudent(_) => Command::FindStudent("a".into()),
+            Command::Fulfil(_) => Command::Fulfil(s()),
+            Command::Instructor { .. } => Command::Instructor { instructor: 1, range: None },
+            Command::InstrumentTypes => Command::InstrumentTypes,
+            Command::Invoice { .. } => Command::Invoice {
+                student: s(),
+                year_month: "2024-11".into(),
+            },
+            Command::Ledger(_) => Command::Ledger(1),
+            Command::Lessons(_) => Command::Lessons(LessonFilter::default()),
+            Command::List(_) => Command::List(ListFilter {
+                limit: Some(10),
+                ..ListFilter::default()
+            }),
+            Command::ListAll(_) => Command::ListAll(None),
+            Command::MaintenanceList => Command::MaintenanceList,
+            Command::MaintenanceOff(_) => Command::MaintenanceOff(s()),
+            Command::MaintenanceOn(..) => Command::MaintenanceOn(s(), "strings".into()),
+            Command::Payments(_) => Command::Payments(1),
+            Command::Quote(..) => Command::Quote(s(), s()),
+            Command::Reconcile(..) => Command::Reconcile(s(), s()),
+            Command::ReconcileReport => Command::ReconcileReport,
+            Command::ReleaseSavepoint(_) => Command::ReleaseSavepoint("sp".into()),
+            Command::Rent(..) => Command::Rent(s(), s()),
+            Command::Reservations(_) => Command::Reservations(None),
+            Command::Reserve(..) => Command::Reserve(s(), s()),
+            Command::Reset => Command::Reset,
+            Command::Revenue { .. } => Command::Revenue {
+                from: "2024-01-01".into(),
+                to: "2024-12-31".into(),
+                include_archived: false,
+            },
+            Command::Rollback(_) => Command::Rollback(None),
+            Command::RollbackTo(_) => Command::RollbackTo("sp".into()),
+            Command::Savepoint(_) => Command::Savepoint("sp".into()),
+            Command::SetAutocommit(_) => Command::SetAutocommit(true),
+            Command::SetLockWait(_) => Command::SetLockWait(LockWait::Fail),
+            Command::SetLocking(_) => Command::SetLocking(Locking::Advisory),
+            Command::SetReadOnly(_) => Command::SetReadOnly(false),
+            Command::Sql(_) => Command::Sql("SELECT 1".into()),
+            Command::Stats(_) => Command::Stats(StatsKind::default()),
+            Command::StudentCost(_) => Command::StudentCost(1),
+            Command::Terminate(..) => Command::Terminate(s(), None),
+            Command::TerminateWithNote(..) => Command::TerminateWithNote(s(), "note".into()),
+            Command::TryTerminate(..) => Command::TryTerminate(s(), s(), None),
+            Command::TryUnreserve(..) => Command::TryUnreserve(s(), s()),
+            Command::Unenroll { .. } => Command::Unenroll { student: 1, lesson: 1 },
+            Command::Unreserve(_) => Command::Unreserve(s()),
+            Command::Warmup => Command::Warmup,
+            Command::ClearCache => Command::ClearCache,
+        };
+
+        let mut ctl = Controller::new().await;
+        for (c, listed) in commands {
+            // Each sample must be of the variant it is listed under, so no arm lacks a sample
+            assert!(listed, "{c:?} is listed under the wrong pattern");
+            assert!(!c.to_string().is_empty(), "{c:?} has no text");
+            assert!(
+                !c.is_mutating() || c.needs_transaction(),
+                "{c} changes data but can run without a transaction"
+            );
+
+            // Run each command in a transaction which is rolled back, a todo!() or
+            // unimplemented!() arm panics and fails the test
+            let text = c.to_string();
+            if ctl.transaction.is_none() {
+                ctl.begin(false).await.unwrap();
+            }
+            let r = ctl.dispatch(c).await;
+            if ctl.transaction.is_some() {
+                ctl.rollback(None).await.unwrap();
             }
+            match &r {
+                Ok(ControlResult::Backup { file, .. }) => std::fs::remove_file(file).unwrap(),
+                Ok(ControlResult::Export { path, .. }) => std::fs::remove_file(path).unwrap(),
+                _ => {}
+            }
+            assert!(
+                !matches!(
+                    r,
+                    Err(ControlError::DryRunUnsupported(_) | ControlError::TransactionNone)
+                ),
+                "{text} is not handled: {r:?}"
+            );
         }
+    }
 
-        let v = c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
-        if v.is_ok() {
-            c.rollback().await.unwrap();
-            panic!("Renting should fail above max allowed")
+    #[tokio::test]
+    async fn test_list_rented_by_matches_per_row_counts() {
+        let mut c = init().await;
+        // Student 1 rents instrument 2 in the seed data, rent a second one so the list has rows
+        // with different availability
+        c.rent("1", TEST_MULTI_INSTRUMENT_ID).await.unwrap();
+        c.maintenance_on(TEST_MULTI_INSTRUMENT_ID, "strings")
+            .await
+            .unwrap();
+        let tx = c.transaction.as_mut().unwrap();
+
+        let mut old = vec![];
+        for i in db::list_instruments_for_student(tx, 1).await.unwrap() {
+            let rent_count = db::count_active_instrument_rentals(tx, i.get_id())
+                .await
+                .unwrap();
+            let repairs = db::count_instrument_maintenance(tx, i.get_id())
+                .await
+                .unwrap();
+            let available = i64::from(i.get_count()) - rent_count - repairs;
+            old.push(InstrumentDto::from((i, available)).to_string());
         }
+        old.sort();
+        let new = db::list_rented_by(tx, 1).await.unwrap();
+        c.rollback(None).await.unwrap();
 
-        assert_eq!(v.unwrap_err(), ControlError::TooManyRentals);
-        c.rollback().await.unwrap();
+        let mut new: Vec<String> = new.iter().map(ToString::to_string).collect();
+        new.sort();
+        assert_eq!(new.len(), 2);
+        assert_eq!(new, old);
     }
 
     #[tokio::test]
-    async fn test_terminate_multiple() {
+    async fn test_reset() {
         let mut c = init().await;
+        let Ok(ControlResult::Rent { rent_id, .. }) =
+            c.rent(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID).await
+        else {
+            c.rollback(None).await.unwrap();
+            panic!("Failed renting, wrong params for rent()?");
+        };
 
-        for _ in 0..2 {
-            let v = c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
-            if v.is_err() {
-                c.rollback().await.unwrap();
-                panic!("Failed renting, wrong params for rent()?");
-            } else {
-                assert_eq!(v.unwrap(), ControlResult::Rent(1));
+        let reset = c.execute(Command::Reset).await.map(|r| r.result);
+        let tx = c.transaction.as_mut().unwrap();
+        let renting = db::get_renting(tx, rent_id).await;
+        let again = c.rent(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID).await;
+        c.rollback(None).await.unwrap();
+
+        assert_eq!(reset.unwrap(), ControlResult::Reset);
+        assert!(matches!(renting, Err(sqlx::Error::RowNotFound)));
+        assert!(matches!(again, Ok(ControlResult::Rent { .. })));
+
+        // Without an open transaction it only begins one
+        assert_eq!(
+            c.execute(Command::Reset).await.unwrap().result,
+            ControlResult::Reset
+        );
+        assert!(c.transaction.is_some());
+        c.rollback(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_list_order_is_stable() {
+        let mut c = init().await;
+        let ids = |r: Result<ControlResult, ControlError>| match r {
+            Ok(ControlResult::List(v) | ControlResult::ListAll(v)) => {
+                v.iter().map(|i| i.id).collect::<Vec<_>>()
+            }
+            r => panic!("Listing failed: {r:?}"),
+        };
+
+        let before = ids(c.list_all(None).await);
+        // An update moves the row in the heap, which changes the order of an unordered scan
+        let tx = c.transaction.as_mut().unwrap();
+        sqlx::query!(
+            "UPDATE instruments SET price = price + 1 WHERE instrument_id = $1;",
+            before[0]
+        )
+        .execute(&mut **tx)
+        .await
+        .unwrap();
+        let after = ids(c.list_all(None).await);
+        let tx = c.transaction.as_mut().unwrap();
+        let expected: Vec<i32> = sqlx::query_scalar!(
+            "SELECT i.instrument_id FROM instruments i
+             JOIN instrument_types t ON i.instrument_type_id = t.instrument_type_id
+             ORDER BY t.instrument_type, i.brand, i.model, i.instrument_id;"
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .unwrap();
+        c.rollback(None).await.unwrap();
+
+        assert_eq!(before, after);
+        assert_eq!(after, expected);
+    }
+
+    #[tokio::test]
+    async fn test_list_pages() {
+        let mut c = init().await;
+        let all = c.list(ListFilter::default()).await;
+        let mut pages = vec![];
+        // Page on until a page after the last instrument
+        for offset in (0..).step_by(2) {
+            let page = c
+                .list(ListFilter {
+                    limit: Some(2),
+                    offset: Some(offset),
+                    ..ListFilter::default()
+                })
+                .await;
+            let Ok(ControlResult::ListPage {
+                instruments,
+                offset: o,
+                total,
+            }) = page
+            else {
+                c.rollback(None).await.unwrap();
+                panic!("Listing a page failed: {page:?}");
+            };
+            let last = instruments.is_empty();
+            pages.push((instruments, o, total));
+            if last || offset > 1000 {
+                break;
             }
         }
+        c.rollback(None).await.unwrap();
 
-        let v = c.try_terminate(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
-        if v.is_ok() {
-            c.rollback().await.unwrap();
-            panic!("Having mutliple possible terminations should return an error!")
+        let Ok(ControlResult::List(all)) = all else {
+            panic!("Listing failed: {all:?}");
+        };
+        let total = i64::try_from(all.len()).unwrap();
+        // Every full or partial page, then the empty one after them
+        assert_eq!(pages.len(), all.len().div_ceil(2) + 1);
+        let mut paged = vec![];
+        for ((instruments, offset, t), expected) in pages.iter().zip((0..).step_by(2)) {
+            assert_eq!(*offset, expected);
+            assert_eq!(*t, total);
+            assert!(instruments.len() <= 2);
+            paged.extend(instruments.iter().map(|i| i.id));
         }
+        assert_eq!(paged, all.iter().map(|i| i.id).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_list_limit() {
+        let mut c = init().await;
+        let limited = c
+            .list(ListFilter {
+                limit: Some(1),
+                ..ListFilter::default()
+            })
+            .await;
+        c.rollback(None).await.unwrap();
 
-        assert!(matches!(v.unwrap_err(), ControlError::TerminateMultiple(_)));
-        c.rollback().await.unwrap();
+        let Ok(ControlResult::ListPage {
+            instruments, total, ..
+        }) = limited
+        else {
+            panic!("Listing with a limit failed: {limited:?}");
+        };
+        assert_eq!(instruments.len(), 1);
+        assert!(total >= 1);
     }
 
     #[tokio::test]
-    async fn test_guard() {
+    async fn test_bad_business_rule() {
+        let mut c = init().await;
+        let tx = c.transaction.as_mut().unwrap();
+        sqlx::query!(
+            "UPDATE business_rules SET value = '2x'
+             WHERE name IN ('rent_max_count', 'reserve_max_count');"
+        )
+        .execute(&mut **tx)
+        .await
+        .unwrap();
+        let max = db::get_max_rentals(tx, db::MAX_RENTALS_KEY).await;
+        let allowance = db::get_rental_allowance(tx, 3, db::MAX_RENTALS_KEY).await;
+
+        let reserve = c.reserve(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        let refused = c.rent(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID).await;
+        c.rollback(None).await.unwrap();
+
+        assert!(matches!(
+            max,
+            Err(RuleError::Invalid { name, value }) if name == "rent_max_count" && value == "2x"
+        ));
+        assert!(matches!(
+            allowance,
+            Err(RuleError::Invalid { name, value }) if name == "rent_max_count" && value == "2x"
+        ));
+        assert!(matches!(
+            reserve,
+            Err(ControlError::BadBusinessRule { name, value })
+                if name == "reserve_max_count" && value == "2x"
+        ));
+        let Err(e) = refused else {
+            panic!("Renting with a bad rule should fail, got {refused:?}");
+        };
+        assert!(matches!(
+            &e,
+            ControlError::BadBusinessRule { name, value } if name == "rent_max_count" && value == "2x"
+        ));
+        assert!(e.to_string().contains("configuration problem"));
+    }
+
+    #[tokio::test]
+    async fn test_check_config() {
+        let mut c = init().await;
+        let valid = c.check_config().await;
+        let tx = c.transaction.as_mut().unwrap();
+        sqlx::query!(
+            "INSERT INTO business_rules (name, value) VALUES ('rent_max_count_trial', '0');"
+        )
+        .execute(&mut **tx)
+        .await
+        .unwrap();
+        c.max_rentals_key("rent_max_count_trial");
+        let zero = c.check_config().await;
+        c.max_rentals_key("rent_max_count_missing");
+        let missing = c.check_config().await;
+        c.rollback(None).await.unwrap();
+
+        assert!(valid.is_ok());
+        assert!(matches!(
+            zero,
+            Err(ControlError::BadBusinessRule { name, value }) if name == "rent_max_count_trial" && value == "0"
+        ));
+        assert!(
+            matches!(missing, Err(ControlError::Converted(e)) if e.contains("rent_max_count_missing"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rules_cached_per_transaction() {
+        let mut c = init().await;
+        c.allow_sql(true);
+
+        let first = c.rent(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID).await;
+        let after_first = c.rules.queries();
+        let second = c.rent(TEST_STUDENT_ID, "3").await;
+        let after_second = c.rules.queries();
+        let set = c
+            .sql("UPDATE business_rules SET value = '0' WHERE name = 'rent_max_count';")
+            .await;
+        let refused = c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        let after_write = c.rules.queries();
+        c.rollback(None).await.unwrap();
+        c.begin(false).await.unwrap();
+        let next_tx = c.rent(TEST_STUDENT_ID, TEST_MULTI_INSTRUMENT_ID).await;
+        let after_begin = c.rules.queries();
+        c.rollback(None).await.unwrap();
+
+        assert!(matches!(first, Ok(ControlResult::Rent { .. })));
+        assert!(matches!(second, Ok(ControlResult::Rent { .. })));
+        assert!(after_first > 0);
+        assert_eq!(
+            after_second, after_first,
+            "The second rent should use the cache"
+        );
+        assert!(set.is_ok());
+        assert!(matches!(
+            refused,
+            Err(ControlError::TooManyRentals { max: 0, .. })
+        ));
+        assert_eq!(
+            after_write,
+            after_first + 1,
+            "Only the max should be read again"
+        );
+        assert!(matches!(next_tx, Ok(ControlResult::Rent { .. })));
+        assert!(after_begin > after_write);
+    }
+
+    #[tokio::test]
+    async fn test_warmup() {
         let mut c = Controller::new().await;
-        let v = c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
-        assert_eq!(v.unwrap_err(), ControlError::TransactionNone);
-        let v = c.try_terminate(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
-        assert_eq!(v.unwrap_err(), ControlError::TransactionNone);
-        let v = c.terminate(TEST_RENT_ID).await;
-        assert_eq!(v.unwrap_err(), ControlError::TransactionNone);
-        let v = c.list(None).await;
-        assert_eq!(v.unwrap_err(), ControlError::TransactionNone);
+        let types = || Command::InstrumentTypes;
+        let listed = c.execute(types()).await.map(|r| r.result);
+        let before = c.cached_instrument_types().map(<[InstrumentType]>::to_vec);
+        let warmed = c.execute(Command::Warmup).await.map(|r| r.result);
+        let cached = c.cached_instrument_types().map(<[InstrumentType]>::to_vec);
+        let open = c.transaction.is_some();
+        // A type only in the cache shows that the types are listed from it
+        let theremin = InstrumentType {
+            instrument_type_id: 999_999,
+            instrument_type: "theremin".into(),
+        };
+        c.type_cache = Some((Instant::now(), vec![theremin.clone()]));
+        let from_cache = c.execute(types()).await.map(|r| r.result);
+        let cleared = c.execute(Command::ClearCache).await.map(|r| r.result);
+        let after_clear = c.execute(types()).await.map(|r| r.result);
+
+        assert!(before.is_none());
+        let Ok(ControlResult::Warmup { types_loaded }) = warmed else {
+            panic!("Warmup failed: {warmed:?}");
+        };
+        let cached = cached.unwrap();
+        assert_eq!(cached.len(), types_loaded);
+        assert_eq!(
+            listed.unwrap(),
+            ControlResult::InstrumentTypes(cached.clone())
+        );
+        assert!(!open, "Warmup should not leave a transaction open");
+        assert_eq!(
+            from_cache.unwrap(),
+            ControlResult::InstrumentTypes(vec![theremin.clone()])
+        );
+        assert_eq!(cleared.unwrap(), ControlResult::CacheCleared);
+        assert_eq!(after_clear.unwrap(), ControlResult::InstrumentTypes(cached));
+
+        // Types loaded longer ago than the TTL are not used
+        c.type_cache = Some((
+            Instant::now() - TYPE_CACHE_TTL - Duration::from_secs(1),
+            vec![theremin],
+        ));
+        assert!(c.cached_instrument_types().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sibling_discount_optional() {
+        let mut c = init().await;
+        let tx = c.transaction.as_mut().unwrap();
+        sqlx::query!("DELETE FROM business_rules WHERE name = 'sibling_discount_pct';")
+            .execute(&mut **tx)
+            .await
+            .unwrap();
+
+        let quote = c.quote(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        let rented = c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        c.rollback(None).await.unwrap();
+
+        assert!(quote.is_ok(), "{quote:?}");
+        assert!(rented.is_ok(), "{rented:?}");
+    }
+
+    #[tokio::test]
+    async fn test_bad_amount_rules() {
+        let mut c = init().await;
+        let tx = c.transaction.as_mut().unwrap();
+        sqlx::query!("UPDATE business_rules SET value = '1x' WHERE name = 'deposit_amount';")
+            .execute(&mut **tx)
+            .await
+            .unwrap();
+        let deposit = c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        c.rollback(None).await.unwrap();
+
+        c.begin(false).await.unwrap();
+        let tx = c.transaction.as_mut().unwrap();
+        sqlx::query!("UPDATE business_rules SET value = '-1' WHERE name = 'rent_max_time';")
+            .execute(&mut **tx)
+            .await
+            .unwrap();
+        c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await.unwrap();
+        let months = c
+            .try_terminate(TEST_STUDENT_ID, TEST_INSTRUMENT_ID, None)
+            .await;
+        c.rollback(None).await.unwrap();
+
+        assert!(
+            matches!(&deposit, Err(ControlError::BadBusinessRule { name, .. }) if name == "deposit_amount"),
+            "{deposit:?}"
+        );
+        assert!(
+            matches!(&months, Err(ControlError::BadBusinessRule { name, .. }) if name == "rent_max_time"),
+            "{months:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fulfil_archived_instrument() {
+        let mut c = init().await;
+        let Ok(ControlResult::Reserved(id)) = c.reserve(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await
+        else {
+            panic!("Reserving should succeed");
+        };
+        let tx = c.transaction.as_mut().unwrap();
+        let instrument = db::get_reservation(tx, id)
+            .await
+            .unwrap()
+            .unwrap()
+            .get_instrument_id();
+        sqlx::query!(
+            "UPDATE instruments SET archived = true WHERE instrument_id = $1;",
+            instrument
+        )
+        .execute(&mut **tx)
+        .await
+        .unwrap();
+
+        let fulfilled = c.fulfil(&id.to_string()).await;
+        c.rollback(None).await.unwrap();
+
+        assert_eq!(fulfilled, Err(ControlError::InstrumentNotFound(instrument)));
+    }
+
+    #[tokio::test]
+    async fn test_archived_instrument_hidden() {
+        let id = TEST_INSTRUMENT_ID.parse::<i32>().unwrap();
+        let mut c = init().await;
+        let tx = c.transaction.as_mut().unwrap();
+        sqlx::query!(
+            "UPDATE instruments SET archived = true WHERE instrument_id = $1;",
+            id
+        )
+        .execute(&mut **tx)
+        .await
+        .unwrap();
+
+        let list = c.list(ListFilter::default()).await;
+        let tx = c.transaction.as_mut().unwrap();
+        let (listed, counted) = db::list_page(tx, None, false, None, 0).await.unwrap();
+        let reserved = c.reserve(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await;
+        c.rollback(None).await.unwrap();
+
+        let Ok(ControlResult::List(list)) = list else {
+            panic!("List should return ControlResult::List, got {list:?}");
+        };
+        assert!(list.iter().all(|i| i.id != id));
+        assert!(listed.iter().all(|i| i.id != id));
+        assert_eq!(counted, i64::try_from(listed.len()).unwrap());
+        assert_eq!(reserved, Err(ControlError::InstrumentNotFound(id)));
+    }
+
+    #[tokio::test]
+    async fn test_sibling_discount_out_of_range() {
+        let mut results = vec![];
+        for pct in ["150", "-10"] {
+            let mut c = init().await;
+            let tx = c.transaction.as_mut().unwrap();
+            sqlx::query!(
+                "UPDATE business_rules SET value = $1 WHERE name = 'sibling_discount_pct';",
+                pct
+            )
+            .execute(&mut **tx)
+            .await
+            .unwrap();
+            results.push(c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await);
+            c.rollback(None).await.unwrap();
+        }
+
+        for (r, pct) in results.into_iter().zip(["150", "-10"]) {
+            assert_eq!(
+                r,
+                Err(ControlError::BadBusinessRule {
+                    name: String::from("sibling_discount_pct"),
+                    value: String::from(pct),
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_terminate_twice() {
+        let mut c = init().await;
+
+        let rent_id = match c.rent(TEST_STUDENT_ID, TEST_INSTRUMENT_ID).await {
+            Ok(ControlResult::Rent { rent_id, .. }) => rent_id.to_string(),
+            _ => {
+                c.rollback(None).await.unwrap();
+                panic!("Failed renting, wrong params for rent()?");
+            }
+        };
+        let first = c.terminate(&rent_id, None, None).await;
+        let second = c.terminate(&rent_id, Some("again"), None).await;
+        let charges = c.payments(TEST_STUDENT_ID.parse().unwrap()).await;
+        let missing = c.terminate("999999", None, None).await;
+        c.rollback(None).await.unwrap();
+
+        assert!(matches!(first, Ok(ControlResult::Terminate { .. })));
+        assert_eq!(
+            second,
+            Err(ControlError::RentingAlreadyTerminated(
+                rent_id.parse().unwrap()
+            ))
+        );
+        match charges.unwrap() {
+            ControlResult::Payments(v) => assert_eq!(
+                v.iter()
+                    .filter(|p| p.to_string().contains(&format!("renting {rent_id} ")))
+                    .count(),
+                1
+            ),
+            _ => panic!("Payments should return ControlResult::Payments"),
+        }
+        assert_eq!(missing, Err(ControlError::from(sqlx::Error::RowNotFound)));
     }
 }
@@ -0,0 +1,267 @@
+/// The name and usage of a command, shown as one row of the help
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandHelp {
+    /// What the command does, e.g. "Rent"
+    pub name: &'static str,
+    /// How the command is typed, (is optional) [is required]
+    pub usage: &'static str,
+}
+
+/// The help of every command accepted by the repl, in the order they are shown
+pub const COMMANDS: &[CommandHelp] = &[
+    CommandHelp {
+        name: "Add",
+        usage: "add [type] [brand] [model] [price] [count]",
+    },
+    CommandHelp {
+        name: "Archive",
+        usage: "archive [months], moves rentings ended more than that long ago",
+    },
+    CommandHelp {
+        name: "Audit",
+        usage: "audit (count)",
+    },
+    CommandHelp {
+        name: "Backup",
+        usage: "backup",
+    },
+    CommandHelp {
+        name: "Begin",
+        usage: "b(egin) (--read-only)",
+    },
+    CommandHelp {
+        name: "Force begin",
+        usage: "fbegin | begin --force, rolls back the open transaction",
+    },
+    CommandHelp {
+        name: "Commit",
+        usage: "c(ommit)",
+    },
+    CommandHelp {
+        name: "Reset",
+        usage: "reset | rs, rolls back the open transaction and begins a new one",
+    },
+    CommandHelp {
+        name: "Damages",
+        usage: "damages",
+    },
+    CommandHelp {
+        name: "Deactivate",
+        usage: "deact [student]",
+    },
+    CommandHelp {
+        name: "Dry run",
+        usage: "append --dry-run to add, maintenance, rent or terminate",
+    },
+    CommandHelp {
+        name: "Cost",
+        usage: "cost [student]",
+    },
+    CommandHelp {
+        name: "Export",
+        usage: "export [rentals | inventory] (--json (--compact)) [path]",
+    },
+    CommandHelp {
+        name: "Find student",
+        usage: "f(ind) [name] | fs [name]",
+    },
+    CommandHelp {
+        name: "Fulfil",
+        usage: "fulfil [reservation_id]",
+    },
+    CommandHelp {
+        name: "Help",
+        usage: "h(elp)",
+    },
+    CommandHelp {
+        name: "Instrument types",
+        usage: "it | types",
+    },
+    CommandHelp {
+        name: "Invoice",
+        usage: "i(nvoice) [student] [YYYY-MM]",
+    },
+    CommandHelp {
+        name: "List",
+        usage: "l(ist) (instrument_type) (--limit [count]) (--offset [count])",
+    },
+    CommandHelp {
+        name: "List all",
+        usage: "la | listall (instrument_type), includes fully rented instruments",
+    },
+    CommandHelp {
+        name: "List rented",
+        usage: "l(ist) --rented-by [student] | lr [student]",
+    },
+    CommandHelp {
+        name: "Note",
+        usage: "note [rent_id] [note]",
+    },
+    CommandHelp {
+        name: "Maintenance",
+        usage:
+            "maintenance [instrument] on [note] | maintenance [instrument] off | maintenance list",
+    },
+    CommandHelp {
+        name: "Payments",
+        usage: "p(ayments) [student]",
+    },
+    CommandHelp {
+        name: "Lessons",
+        usage: "lessons (YYYY-MM-DD | student [student])",
+    },
+    CommandHelp {
+        name: "Instructor",
+        usage: "instructor [instructor] (YYYY-MM-DD YYYY-MM-DD), upcoming lessons or those between",
+    },
+    CommandHelp {
+        name: "Enroll",
+        usage: "enroll [student] [lesson] | unenroll [student] [lesson]",
+    },
+    CommandHelp {
+        name: "Ledger",
+        usage: "ledger [student], rentals, lesson charges and payments with a running balance",
+    },
+    CommandHelp {
+        name: "Quit",
+        usage: "q(uit)",
+    },
+    CommandHelp {
+        name: "Quote",
+        usage: "quote [student] [instrument]",
+    },
+    CommandHelp {
+        name: "Reconcile",
+        usage: "reconcile [instrument] [counted] | reconcile report",
+    },
+    CommandHelp {
+        name: "Rent",
+        usage: "re(nt) [student] [instrument]",
+    },
+    CommandHelp {
+        name: "Reserve",
+        usage: "reserve [student] [instrument]",
+    },
+    CommandHelp {
+        name: "Reservations",
+        usage: "reservations (instrument)",
+    },
+    CommandHelp {
+        name: "Revenue",
+        usage: "rev(enue) [YYYY-MM-DD] [YYYY-MM-DD] (--include-archived)",
+    },
+    CommandHelp {
+        name: "Release",
+        usage: "release [savepoint]",
+    },
+    CommandHelp {
+        name: "Rollback",
+        usage: "ro(llback) (savepoint) | rollback-to [savepoint]",
+    },
+    CommandHelp {
+        name: "Save",
+        usage: "save [savepoint]",
+    },
+    CommandHelp {
+        name: "Set",
+        usage:
+            "set autocommit [on | off], set lock-wait [block | fail], set locking [rows | advisory], \
+            set read-only [on | off]",
+    },
+    CommandHelp {
+        name: "SQL",
+        usage: "sql [statement], needs --allow-sql",
+    },
+    CommandHelp {
+        name: "Stats",
+        usage: "s(tats) (types | monthly (months) (--include-archived) | lifetime)",
+    },
+    CommandHelp {
+        name: "Terminate",
+        usage: "t(erminate) ([student] [instrument] | rent [rent_id]) (--damaged [note] (amount))",
+    },
+    CommandHelp {
+        name: "Terminate note",
+        usage: "tn [rent_id] [note]",
+    },
+    CommandHelp {
+        name: "Warmup",
+        usage: "w(armup), loads the instrument types so the first command does not wait",
+    },
+    CommandHelp {
+        name: "Clear cache",
+        usage: "clearcache, forgets the loaded instrument types and business rules",
+    },
+    CommandHelp {
+        name: "Unreserve",
+        usage: "unreserve [reservation_id] | unreserve [student] [instrument]",
+    },
+];
+
+/// Formats the help as a table with the usage of every command starting in the same column,
+/// two spaces after the longest name
+///
+/// # Parameters
+/// - `commands` the rows of the table, e.g. [`COMMANDS`]
+///
+/// # Returns
+/// The help with a heading line followed by one line per command
+pub fn format_help(commands: &[CommandHelp]) -> String {
+    let width = commands
+        .iter()
+        .map(|c| c.name.chars().count() + 1)
+        .max()
+        .unwrap_or_default()
+        + 2;
+
+    let mut help = String::from("Commands: (is optional) [is required]");
+    for c in commands {
+        help.push_str(&format!("\n{:<width$}{}", format!("{}:", c.name), c.usage));
+    }
+    help
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_help_aligned() {
+        let help = format_help(COMMANDS);
+        let lines: Vec<&str> = help.lines().skip(1).collect();
+        assert_eq!(lines.len(), COMMANDS.len());
+
+        let columns: Vec<usize> = lines
+            .iter()
+            .zip(COMMANDS)
+            .map(|(l, c)| {
+                let (name, usage) = l.split_once(':').unwrap();
+                assert_eq!(name, c.name);
+                assert_eq!(usage.trim_start(), c.usage);
+                l.len() - c.usage.len()
+            })
+            .collect();
+        assert!(columns.iter().all(|c| *c == columns[0]), "{columns:?}");
+        assert!(!help.contains('\t'));
+    }
+
+    #[test]
+    fn test_format_help_widths() {
+        let rows = [
+            CommandHelp {
+                name: "A",
+                usage: "a",
+            },
+            CommandHelp {
+                name: "Longer name",
+                usage: "b (c)",
+            },
+        ];
+
+        assert_eq!(
+            format_help(&rows),
+            "Commands: (is optional) [is required]\nA:            a\nLonger name:  b (c)"
+        );
+        assert_eq!(format_help(&[]), "Commands: (is optional) [is required]");
+    }
+}
@@ -0,0 +1,5 @@
+pub mod controller;
+pub mod db;
+pub mod help;
+pub mod parser;
+pub mod repl;
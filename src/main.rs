@@ -1,13 +1,51 @@
-use controller::Controller;
+use std::{env, process::ExitCode};
 
-mod controller;
-mod db;
-mod parser;
-mod repl;
+use sgdb::{
+    controller::Controller,
+    repl::{PromptStyle, ReplMode},
+};
+
+const USAGE: &str = "Usage: sgdb [--verbose] [--allow-sql] [--no-emoji] [--batch | --file <path>]";
 
 #[tokio::main]
-async fn main() -> Result<(), sqlx::Error> {
-    let con = Controller::new().await;
-    con.run_repl().await?;
-    Ok(())
+async fn main() -> Result<ExitCode, sqlx::Error> {
+    let mut args = env::args().skip(1).peekable();
+    let (mut verbose, mut allow_sql, mut no_emoji) = (false, false, false);
+    while let Some(flag) =
+        args.next_if(|a| a == "--verbose" || a == "--allow-sql" || a == "--no-emoji")
+    {
+        match flag.as_str() {
+            "--verbose" => verbose = true,
+            "--no-emoji" => no_emoji = true,
+            _ => allow_sql = true,
+        }
+    }
+    let mode = match args.next().as_deref() {
+        None => ReplMode::Interactive,
+        Some("--batch") => ReplMode::Batch,
+        Some("--file") => match args.next() {
+            Some(path) => ReplMode::File(path.into()),
+            None => {
+                eprintln!("{USAGE}");
+                return Ok(ExitCode::FAILURE);
+            }
+        },
+        Some(_) => {
+            eprintln!("{USAGE}");
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+
+    let mut con = Controller::new().await;
+    con.allow_sql(allow_sql);
+    if let Err(e) = con.upgrade_schema().await {
+        eprintln!("The database could not be upgraded: {e}");
+        return Ok(ExitCode::FAILURE);
+    }
+    if let Err(e) = con.check_config().await {
+        eprintln!("The database is misconfigured: {e}");
+        return Ok(ExitCode::FAILURE);
+    }
+    con.run_repl(mode, verbose, PromptStyle::detect(no_emoji))
+        .await
 }
@@ -1,18 +1,26 @@
-use std::{fmt, str::Split};
+use std::{
+    fmt,
+    iter::Peekable,
+    str::{Chars, Split},
+};
 
-use crate::controller::Command;
+use crate::{
+    controller::{Command, Damage, ExportFormat, ExportKind, LessonFilter, ListFilter, StatsKind},
+    db::{LockWait, Locking},
+};
 
 /// `ParseResult` represents an Ok result returned by parser when parsing was successful
 ///
 /// # Example
 /// ```rust
+/// use sgdb::controller::Command;
 /// use sgdb::parser::{self, ParseResult};
 /// let s = "begin";
-/// assert_eq!(parser::parse_to_command(s), ParseResult::Command::Begin);
+/// assert_eq!(parser::parse_to_command(s), Ok(ParseResult::Command(Command::Begin { force: false })));
 /// let s = "h";
-/// assert_eq!(parser::parse_to_command(s), ParseResult::Help);
+/// assert_eq!(parser::parse_to_command(s), Ok(ParseResult::Help));
 /// let s = "q";
-/// assert_eq!(parser::parse_to_command(s), ParseResult::Quit);
+/// assert_eq!(parser::parse_to_command(s), Ok(ParseResult::Quit));
 /// ```
 #[derive(Debug, PartialEq, Eq)]
 pub enum ParseResult {
@@ -30,11 +38,11 @@ pub enum ParseResult {
 /// ```rust
 /// use sgdb::parser::{self, ParseError};
 /// let s = "x";
-/// assert_eq!(parser::parse_to_command(s), ParseError::Default);
+/// assert_eq!(parser::parse_to_command(s), Err(ParseError::Default));
 /// let s = "re";
-/// assert_eq!(parser::parse_to_command(s), ParseError::NoStudent);
+/// assert_eq!(parser::parse_to_command(s), Err(ParseError::NoStudent));
 /// let s = "re 1";
-/// assert_eq!(parser::parse_to_command(s), ParseError::NoInstrument);
+/// assert_eq!(parser::parse_to_command(s), Err(ParseError::NoInstrument));
 /// ```
 #[derive(Debug, PartialEq, Eq)]
 pub enum ParseError {
@@ -42,8 +50,12 @@ pub enum ParseError {
     Default,
     /// No instrument was supplied to command which requires it
     NoInstrument,
+    /// No note was supplied to command which requires it
+    NoNote,
     /// No student was supplied to command which requires it
     NoStudent,
+    /// An id is not an integer, contains the name of the field and the value given
+    InvalidId(String, String),
 }
 
 impl From<Command> for ParseResult {
@@ -59,11 +71,152 @@ impl fmt::Display for ParseError {
             Self::NoInstrument => {
                 write!(f, "Command not understood! Missing instrument in command!")
             }
+            Self::NoNote => write!(f, "Command not understood! Missing note in command!"),
             Self::NoStudent => write!(f, "Command not understood! Missing student in command!"),
+            Self::InvalidId(field, value) => {
+                write!(f, "Invalid {field} ID '{value}': expected an integer")
+            }
         }
     }
 }
 
+/// Every command word, and its short form, accepted by [`parse_to_command`]
+const COMMANDS: [&str; 65] = [
+    "add",
+    "archive",
+    "audit",
+    "b",
+    "backup",
+    "begin",
+    "c",
+    "clearcache",
+    "commit",
+    "cost",
+    "damages",
+    "deact",
+    "enroll",
+    "export",
+    "f",
+    "fbegin",
+    "find",
+    "fs",
+    "fulfil",
+    "h",
+    "help",
+    "i",
+    "instructor",
+    "invoice",
+    "it",
+    "l",
+    "la",
+    "ledger",
+    "lessons",
+    "list",
+    "listall",
+    "lr",
+    "maintenance",
+    "note",
+    "p",
+    "payments",
+    "q",
+    "quit",
+    "quote",
+    "re",
+    "reconcile",
+    "release",
+    "rent",
+    "reservations",
+    "reserve",
+    "reset",
+    "rev",
+    "revenue",
+    "ro",
+    "rollback",
+    "rollback-to",
+    "rs",
+    "s",
+    "save",
+    "set",
+    "sql",
+    "stats",
+    "t",
+    "terminate",
+    "tn",
+    "types",
+    "unenroll",
+    "unreserve",
+    "w",
+    "warmup",
+];
+
+/// The instrument types completed when none are passed to [`complete`]
+const INSTRUMENT_TYPES: [&str; 2] = ["guitar", "piano"];
+
+/// Completes the last word of partially typed input, without any database calls
+///
+/// # Parameters
+/// - `partial` the input typed so far
+/// - `instrument_types` the instrument types to complete after `list`, e.g. fetched from the
+///   database once at startup, or `None` to use the types the school started with
+///
+/// # Returns
+/// The words which the last word of `partial` could be completed to, sorted
+///
+/// # Example
+/// ```rust
+/// use sgdb::parser;
+/// assert_eq!(parser::complete("b", None), vec!["b", "backup", "begin"]);
+/// assert_eq!(parser::complete("list g", None), vec!["guitar"]);
+/// ```
+pub fn complete(partial: &str, instrument_types: Option<&[String]>) -> Vec<String> {
+    let partial = partial.trim_start();
+    let (head, last) = partial.rsplit_once(' ').unwrap_or(("", partial));
+    let head: Vec<&str> = head.split_whitespace().collect();
+
+    let types = instrument_types.map_or_else(
+        || INSTRUMENT_TYPES.iter().map(|t| t.to_string()).collect(),
+        <[String]>::to_vec,
+    );
+    let candidates: Vec<String> = match head.as_slice() {
+        [] => COMMANDS.iter().map(|c| c.to_string()).collect(),
+        ["la" | "listall"] => types,
+        ["l" | "list"] => {
+            let mut v = types;
+            v.push(String::from("--rented-by"));
+            v
+        }
+        ["b" | "begin"] => vec![String::from("--force"), String::from("--read-only")],
+        ["maintenance"] => vec![String::from("list")],
+        ["export"] => vec![String::from("inventory"), String::from("rentals")],
+        ["reconcile"] => vec![String::from("report")],
+        ["lessons"] => vec![String::from("student")],
+        ["s" | "stats", "monthly", ..] => vec![String::from("--include-archived")],
+        ["s" | "stats"] => vec![
+            String::from("lifetime"),
+            String::from("monthly"),
+            String::from("types"),
+        ],
+        ["set"] => vec![
+            String::from("autocommit"),
+            String::from("lock-wait"),
+            String::from("locking"),
+            String::from("read-only"),
+        ],
+        ["set", "autocommit"] => vec![String::from("off"), String::from("on")],
+        ["set", "lock-wait"] => vec![String::from("block"), String::from("fail")],
+        ["set", "locking"] => vec![String::from("advisory"), String::from("rows")],
+        ["set", "read-only"] => vec![String::from("off"), String::from("on")],
+        _ => vec![],
+    };
+
+    let mut v: Vec<String> = candidates
+        .into_iter()
+        .filter(|c| c.starts_with(last))
+        .collect();
+    v.sort();
+    v
+}
+
 /// Parses a &str to a [`ParseResult`] or [`ParseError`]
 ///
 /// # Parameters
@@ -78,51 +231,606 @@ impl fmt::Display for ParseError {
 /// use sgdb::parser::{self, ParseResult};
 /// use sgdb::controller::Command;
 /// let s = "begin";
-/// assert_eq!(parser::parse_to_command(s), ParseResult::Command(Command::Begin));
+/// assert_eq!(parser::parse_to_command(s), Ok(ParseResult::Command(Command::Begin { force: false })));
 /// ```
+///
+/// A command ending with `--dry-run` is wrapped in [`Command::DryRun`]
 pub fn parse_to_command(s: &str) -> Result<ParseResult, ParseError> {
+    let s = s.trim();
+    match s.strip_suffix("--dry-run") {
+        Some(rest) if rest.ends_with(' ') => match parse_command(rest)? {
+            ParseResult::Command(c) => Ok(Command::DryRun(Box::new(c)).into()),
+            _ => Err(ParseError::Default),
+        },
+        _ => parse_command(s),
+    }
+}
+
+fn parse_command(s: &str) -> Result<ParseResult, ParseError> {
     let mut words = s.trim().split(' ');
 
-    words.next().map_or_else(
+    words.next().filter(|w| !w.is_empty()).map_or_else(
         || Err(ParseError::Default),
         |w| match w.chars().next().unwrap_or_default() {
-            'b' => Ok(Command::Begin.into()),
-            'c' => Ok(Command::Commit.into()),
+            'a' if w == "audit" => parse_audit(words),
+            'a' if w == "archive" => parse_archive(words),
+            'a' => parse_add(words),
+            'b' if w == "backup" => Ok(Command::Backup.into()),
+            'b' if matches!(w, "b" | "b!" | "begin" | "begin!") => match words.next() {
+                Some("--read-only") => Ok(Command::BeginReadOnly.into()),
+                Some("--force") => Ok(Command::Begin { force: true }.into()),
+                _ => Ok(Command::Begin {
+                    force: w.ends_with('!'),
+                }
+                .into()),
+            },
+            'c' => match w {
+                "clearcache" => Ok(Command::ClearCache.into()),
+                "cost" => parse_cost(words),
+                "c" | "commit" => Ok(Command::Commit.into()),
+                _ => Err(ParseError::Default),
+            },
+            'd' if w == "damages" => Ok(Command::Damages.into()),
+            'd' if w == "deact" => parse_deactivate(words),
+            'e' if w == "enroll" => parse_enroll(words, false),
+            'e' if w == "export" => parse_export(words),
+            'f' if w == "fbegin" => Ok(Command::Begin { force: true }.into()),
+            'f' if w == "fulfil" => parse_fulfil(words),
+            'f' if w == "f" || w == "fs" || w == "find" => parse_find_student(words),
             'h' => Ok(ParseResult::Help),
-            'l' => Ok(parse_list(words)),
-            'q' => Ok(ParseResult::Quit),
-            't' => parse_terminate(words),
+            'i' if w == "instructor" => parse_instructor(words),
+            'i' if w == "it" => Ok(Command::InstrumentTypes.into()),
+            'i' => parse_invoice(words),
+            'l' if w == "ledger" => parse_ledger(words),
+            'l' if w == "lessons" => parse_lessons(words),
+            'l' => match w {
+                "lr" => parse_rented_by(words),
+                "la" | "listall" => parse_list_all(words),
+                _ => parse_list(words),
+            },
+            'm' if w == "maintenance" => parse_maintenance(words),
+            'n' if w == "note" => parse_add_note(words),
+            'p' => parse_payments(words),
+            'q' => match w {
+                "quote" => parse_quote(words),
+                "q" | "quit" => Ok(ParseResult::Quit),
+                _ => Err(ParseError::Default),
+            },
+            's' if w == "save" => parse_savepoint(words).map(|n| Command::Savepoint(n).into()),
+            's' if w == "set" => parse_set(words),
+            's' if w == "sql" => parse_sql(words),
+            's' => parse_stats(words),
+            't' if w == "types" => Ok(Command::InstrumentTypes.into()),
+            't' => match w {
+                "tn" => parse_terminate_note(words),
+                _ => parse_terminate(words),
+            },
+            'r' if w == "reconcile" => parse_reconcile(words),
+            'r' if w == "release" => {
+                parse_savepoint(words).map(|n| Command::ReleaseSavepoint(n).into())
+            }
+            'r' if w == "rollback-to" => {
+                parse_savepoint(words).map(|n| Command::RollbackTo(n).into())
+            }
+            'r' if w == "reserve" => parse_reserve(words),
+            'r' if w == "reset" || w == "rs" => Ok(Command::Reset.into()),
+            'r' if w == "reservations" => parse_reservations(words),
+            'r' if w == "rev" || w == "revenue" => parse_revenue(words),
             'r' => match w.chars().nth(1).unwrap_or_default() {
                 'e' => parse_rent(words),
-                'o' => Ok(Command::Rollback.into()),
+                'o' => Ok(Command::Rollback(
+                    words.next().filter(|w| !w.is_empty()).map(String::from),
+                )
+                .into()),
                 _ => Err(ParseError::Default),
             },
+            'u' if w == "unenroll" => parse_enroll(words, true),
+            'u' if w == "unreserve" => parse_unreserve(words),
+            'w' if w == "warmup" || w == "w" => Ok(Command::Warmup.into()),
             _ => Err(ParseError::Default),
         },
     )
 }
 
-fn parse_list(mut words: Split<'_, char>) -> ParseResult {
-    let instrument_type = words.next().unwrap_or_default();
-    if instrument_type.is_empty() {
-        Command::List(None).into()
-    } else {
-        Command::List(Some(String::from(instrument_type))).into()
+fn parse_add(words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let args = split_quoted(words);
+    let [instrument_type, brand, model, price, count] =
+        <[String; 5]>::try_from(args).map_err(|_| ParseError::Default)?;
+
+    Ok(Command::AddInstrument {
+        instrument_type,
+        brand,
+        model,
+        price,
+        count,
+    }
+    .into())
+}
+
+/// Collects the remaining words into arguments, keeping words wrapped in double quotes together
+///
+/// Inside the quotes `\"` is a quote and `\\` a backslash, as written by `Command`'s Display.
+fn split_quoted(words: Split<'_, char>) -> Vec<String> {
+    let line = words.collect::<Vec<_>>().join(" ");
+    let mut chars = line.chars().peekable();
+    let mut args = vec![];
+    let mut arg: Option<String> = None;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if arg.is_none() => args.push(read_quoted(&mut chars)),
+            c if c.is_whitespace() => args.extend(arg.take()),
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+
+    args
+}
+
+/// Reads a quoted argument up to the closing quote or the end of the line, unescaping `\"` and
+/// `\\`
+fn read_quoted(chars: &mut Peekable<Chars<'_>>) -> String {
+    let mut arg = String::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => match chars.next_if(|n| matches!(n, '"' | '\\')) {
+                Some(n) => arg.push(n),
+                None => arg.push(c),
+            },
+            c => arg.push(c),
+        }
+    }
+
+    arg
+}
+
+fn parse_cost(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let student = words.next().ok_or(ParseError::NoStudent)?;
+    let student = student.parse::<i32>().map_err(|_| ParseError::Default)?;
+
+    Ok(Command::StudentCost(student).into())
+}
+
+fn parse_find_student(words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let name = words.collect::<Vec<_>>().join(" ");
+    let name = name.trim();
+
+    if name.is_empty() {
+        return Err(ParseError::NoStudent);
+    }
+
+    Ok(Command::FindStudent(name.into()).into())
+}
+
+fn parse_fulfil(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let id = words
+        .next()
+        .filter(|w| !w.is_empty())
+        .ok_or(ParseError::Default)?;
+
+    Ok(Command::Fulfil(id.into()).into())
+}
+
+fn parse_invoice(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let student = words.next().ok_or(ParseError::NoStudent)?;
+    let year_month = words.next().ok_or(ParseError::Default)?;
+
+    Ok(Command::Invoice {
+        student: student.into(),
+        year_month: year_month.into(),
     }
+    .into())
+}
+
+/// Returns the next word, skipping the empty words left by repeated spaces
+fn next_word<'a>(words: &mut Split<'a, char>) -> Option<&'a str> {
+    words.map(str::trim).find(|w| !w.is_empty())
+}
+
+fn parse_list(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let mut filter = ListFilter::default();
+    while let Some(w) = next_word(&mut words) {
+        match w {
+            "--rented-by" if filter == ListFilter::default() => return parse_rented_by(words),
+            "--limit" | "--offset" => {
+                let n = next_word(&mut words)
+                    .and_then(|n| n.parse::<u32>().ok())
+                    .ok_or(ParseError::Default)?;
+                if w == "--limit" {
+                    filter.limit = Some(n);
+                } else {
+                    filter.offset = Some(n);
+                }
+            }
+            t if filter.instrument_type.is_none() && !t.starts_with("--") => {
+                filter.instrument_type = Some(String::from(t));
+            }
+            _ => return Err(ParseError::Default),
+        }
+    }
+
+    Ok(Command::List(filter).into())
+}
+
+fn parse_list_all(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let t = next_word(&mut words).map(String::from);
+    if next_word(&mut words).is_some() {
+        return Err(ParseError::Default);
+    }
+
+    Ok(Command::ListAll(t).into())
+}
+
+fn parse_rented_by(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let student = words.next().ok_or(ParseError::NoStudent)?;
+    let student = student.parse::<i32>().map_err(|_| ParseError::Default)?;
+
+    Ok(Command::List(ListFilter {
+        rented_by: Some(student),
+        ..ListFilter::default()
+    })
+    .into())
 }
 
 fn parse_rent(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let user = next_word(&mut words).ok_or(ParseError::NoStudent)?;
+    let user = integer_id("student", user)?;
+    let instrument = next_word(&mut words).ok_or(ParseError::NoInstrument)?;
+    let instrument = integer_id("instrument", instrument)?;
+
+    Ok(Command::Rent(user.into(), instrument.into()).into())
+}
+
+/// Checks that `value` looks like an integer, digits with an optional leading '-', so that a
+/// mistyped id fails when parsing instead of in the controller
+///
+/// Returns `value` if it does, otherwise [`ParseError::InvalidId`] naming `field`
+fn integer_id<'a>(field: &str, value: &'a str) -> Result<&'a str, ParseError> {
+    let digits = value.strip_prefix('-').unwrap_or(value);
+    if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        Ok(value)
+    } else {
+        Err(ParseError::InvalidId(field.into(), value.into()))
+    }
+}
+
+fn parse_reconcile(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let instrument = words.next().ok_or(ParseError::NoInstrument)?;
+    if instrument == "report" {
+        return Ok(Command::ReconcileReport.into());
+    }
+    let counted = words.next().ok_or(ParseError::Default)?;
+
+    Ok(Command::Reconcile(instrument.into(), counted.into()).into())
+}
+
+fn parse_reserve(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
     let user = words.next().ok_or(ParseError::NoStudent)?;
     let instrument = words.next().ok_or(ParseError::NoInstrument)?;
 
-    Ok(Command::Rent(user.into(), instrument.into()).into())
+    Ok(Command::Reserve(user.into(), instrument.into()).into())
 }
 
-fn parse_terminate(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+fn parse_reservations(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let instrument = words
+        .next()
+        .map(str::parse::<i32>)
+        .transpose()
+        .map_err(|_| ParseError::Default)?;
+
+    Ok(Command::Reservations(instrument).into())
+}
+
+fn parse_unreserve(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let first = words
+        .next()
+        .filter(|w| !w.is_empty())
+        .ok_or(ParseError::Default)?;
+
+    match words.next() {
+        Some(instrument) => Ok(Command::TryUnreserve(first.into(), instrument.into()).into()),
+        None => Ok(Command::Unreserve(first.into()).into()),
+    }
+}
+
+fn parse_revenue(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let from = words.next().ok_or(ParseError::Default)?;
+    let to = words.next().ok_or(ParseError::Default)?;
+    let include_archived = match next_word(&mut words) {
+        Some("--include-archived") => true,
+        Some(_) => return Err(ParseError::Default),
+        None => false,
+    };
+
+    Ok(Command::Revenue {
+        from: from.into(),
+        to: to.into(),
+        include_archived,
+    }
+    .into())
+}
+
+fn parse_stats(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    match words.next().unwrap_or_default() {
+        "" => Ok(Command::Stats(StatsKind::Inventory).into()),
+        "types" => Ok(Command::Stats(StatsKind::Types).into()),
+        "lifetime" => Ok(Command::Stats(StatsKind::Lifetime).into()),
+        "monthly" => {
+            let mut months = None;
+            let mut include_archived = false;
+            for w in words.filter(|w| !w.is_empty()) {
+                match w {
+                    "--include-archived" if !include_archived => include_archived = true,
+                    _ if months.is_none() => {
+                        months = Some(w.parse::<u32>().map_err(|_| ParseError::Default)?);
+                    }
+                    _ => return Err(ParseError::Default),
+                }
+            }
+            Ok(Command::Stats(StatsKind::Monthly {
+                months: months.unwrap_or(12),
+                include_archived,
+            })
+            .into())
+        }
+        _ => Err(ParseError::Default),
+    }
+}
+
+fn parse_maintenance(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let instrument = next_word(&mut words).ok_or(ParseError::NoInstrument)?;
+    if instrument == "list" {
+        return Ok(Command::MaintenanceList.into());
+    }
+
+    match next_word(&mut words) {
+        Some("on") => Ok(Command::MaintenanceOn(instrument.into(), parse_note(words)?).into()),
+        Some("off") => Ok(Command::MaintenanceOff(instrument.into()).into()),
+        _ => Err(ParseError::Default),
+    }
+}
+
+fn parse_savepoint(mut words: Split<'_, char>) -> Result<String, ParseError> {
+    next_word(&mut words)
+        .map(String::from)
+        .ok_or(ParseError::Default)
+}
+
+fn parse_set(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    match (next_word(&mut words), next_word(&mut words)) {
+        (Some("autocommit"), Some("on")) => Ok(Command::SetAutocommit(true).into()),
+        (Some("autocommit"), Some("off")) => Ok(Command::SetAutocommit(false).into()),
+        (Some("lock-wait"), Some("block")) => Ok(Command::SetLockWait(LockWait::Block).into()),
+        (Some("lock-wait"), Some("fail")) => Ok(Command::SetLockWait(LockWait::Fail).into()),
+        (Some("locking"), Some("rows")) => Ok(Command::SetLocking(Locking::Rows).into()),
+        (Some("locking"), Some("advisory")) => Ok(Command::SetLocking(Locking::Advisory).into()),
+        (Some("read-only"), Some("on")) => Ok(Command::SetReadOnly(true).into()),
+        (Some("read-only"), Some("off")) => Ok(Command::SetReadOnly(false).into()),
+        _ => Err(ParseError::Default),
+    }
+}
+
+fn parse_deactivate(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let student = next_word(&mut words).ok_or(ParseError::NoStudent)?;
+
+    Ok(Command::Deactivate(student.into()).into())
+}
+
+fn parse_audit(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let count = next_word(&mut words)
+        .map_or(Ok(20), str::parse::<u32>)
+        .map_err(|_| ParseError::Default)?;
+
+    Ok(Command::Audit(count).into())
+}
+
+fn parse_archive(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let months = next_word(&mut words)
+        .ok_or(ParseError::Default)?
+        .parse::<u32>()
+        .map_err(|_| ParseError::Default)?;
+
+    Ok(Command::Archive(months).into())
+}
+
+fn parse_add_note(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let id = next_word(&mut words).ok_or(ParseError::Default)?;
+    let note = parse_note(words)?;
+
+    Ok(Command::AddNote(id.into(), note).into())
+}
+
+fn parse_export(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let what = match next_word(&mut words) {
+        Some("rentals") => ExportKind::Rentals,
+        Some("inventory") => ExportKind::Inventory,
+        _ => return Err(ParseError::Default),
+    };
+
+    let args = split_quoted(words);
+    let (format, path) = match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [path] => (ExportFormat::Csv, *path),
+        ["--json", path] => (ExportFormat::Json { pretty: true }, *path),
+        ["--json", "--compact", path] | ["--compact", "--json", path] => {
+            (ExportFormat::Json { pretty: false }, *path)
+        }
+        _ => return Err(ParseError::Default),
+    };
+
+    Ok(Command::Export {
+        what,
+        format,
+        path: path.into(),
+    }
+    .into())
+}
+
+fn parse_sql(words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let sql = words.collect::<Vec<_>>().join(" ");
+    match sql.trim() {
+        "" => Err(ParseError::Default),
+        sql => Ok(Command::Sql(sql.into()).into()),
+    }
+}
+
+fn parse_instructor(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let instructor = next_word(&mut words).ok_or(ParseError::Default)?;
+    let instructor = integer_id("instructor", instructor)?
+        .parse::<i32>()
+        .map_err(|_| ParseError::Default)?;
+    let range = match (next_word(&mut words), next_word(&mut words)) {
+        (None, _) => None,
+        (Some(from), Some(to)) => Some((from.into(), to.into())),
+        (Some(_), None) => return Err(ParseError::Default),
+    };
+    if next_word(&mut words).is_some() {
+        return Err(ParseError::Default);
+    }
+
+    Ok(Command::Instructor { instructor, range }.into())
+}
+
+fn parse_ledger(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let student = next_word(&mut words).ok_or(ParseError::NoStudent)?;
+    let student = student.parse::<i32>().map_err(|_| ParseError::Default)?;
+
+    Ok(Command::Ledger(student).into())
+}
+
+fn parse_enroll(mut words: Split<'_, char>, unenroll: bool) -> Result<ParseResult, ParseError> {
+    let student = next_word(&mut words).ok_or(ParseError::NoStudent)?;
+    let student = student.parse::<i32>().map_err(|_| ParseError::Default)?;
+    let lesson = next_word(&mut words).ok_or(ParseError::Default)?;
+    let lesson = lesson.parse::<i32>().map_err(|_| ParseError::Default)?;
+    if next_word(&mut words).is_some() {
+        return Err(ParseError::Default);
+    }
+
+    if unenroll {
+        Ok(Command::Unenroll { student, lesson }.into())
+    } else {
+        Ok(Command::Enroll { student, lesson }.into())
+    }
+}
+
+fn parse_lessons(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let filter = match next_word(&mut words) {
+        None => LessonFilter::default(),
+        Some("student") => {
+            let student = next_word(&mut words).ok_or(ParseError::NoStudent)?;
+            LessonFilter {
+                student: Some(student.parse::<i32>().map_err(|_| ParseError::Default)?),
+                ..LessonFilter::default()
+            }
+        }
+        Some(day) => LessonFilter {
+            day: Some(String::from(day)),
+            ..LessonFilter::default()
+        },
+    };
+    if next_word(&mut words).is_some() {
+        return Err(ParseError::Default);
+    }
+
+    Ok(Command::Lessons(filter).into())
+}
+
+fn parse_payments(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let student = words.next().ok_or(ParseError::NoStudent)?;
+    let student = student.parse::<i32>().map_err(|_| ParseError::Default)?;
+
+    Ok(Command::Payments(student).into())
+}
+
+fn parse_quote(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
     let user = words.next().ok_or(ParseError::NoStudent)?;
     let instrument = words.next().ok_or(ParseError::NoInstrument)?;
 
-    Ok(Command::TryTerminate(user.into(), instrument.into()).into())
+    Ok(Command::Quote(user.into(), instrument.into()).into())
+}
+
+fn parse_terminate(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let user = next_word(&mut words).ok_or(ParseError::NoStudent)?;
+    let instrument = next_word(&mut words).ok_or(ParseError::NoInstrument)?;
+
+    let damage = match split_quoted(words).as_slice() {
+        [] => None,
+        [flag] if flag == "--damaged" => return Err(ParseError::NoNote),
+        [flag, note] if flag == "--damaged" => Some(Damage {
+            note: note.clone(),
+            amount: None,
+        }),
+        [flag, note, amount] if flag == "--damaged" => Some(Damage {
+            note: note.clone(),
+            amount: Some(amount.clone()),
+        }),
+        _ => return Err(ParseError::Default),
+    };
+    if damage.as_ref().is_some_and(|d| d.note.trim().is_empty()) {
+        return Err(ParseError::NoNote);
+    }
+
+    // `terminate rent [rent_id]` is how a terminate by rent id is shown, e.g. in the audit log
+    if user == "rent" {
+        return Ok(Command::Terminate(instrument.into(), damage).into());
+    }
+
+    Ok(Command::TryTerminate(user.into(), instrument.into(), damage).into())
+}
+
+fn parse_terminate_note(mut words: Split<'_, char>) -> Result<ParseResult, ParseError> {
+    let id = words.next().ok_or(ParseError::Default)?;
+    let note = parse_note(words)?;
+
+    Ok(Command::TerminateWithNote(id.into(), note).into())
+}
+
+/// Joins the remaining words into a note, removing the quotes around it if there are any and
+/// unescaping a double quoted note, see [`split_quoted`]
+fn parse_note(words: Split<'_, char>) -> Result<String, ParseError> {
+    let note = words.collect::<Vec<_>>().join(" ");
+    let note = note.trim();
+    let note = match note.strip_prefix('"').and_then(|n| n.strip_suffix('"')) {
+        Some(n) => unescape(n),
+        None => note
+            .strip_prefix('\'')
+            .and_then(|n| n.strip_suffix('\''))
+            .unwrap_or(note)
+            .into(),
+    };
+    let note = note.trim();
+
+    if note.is_empty() {
+        return Err(ParseError::NoNote);
+    }
+
+    Ok(note.into())
+}
+
+/// Replaces `\"` with a quote and `\\` with a backslash, leaving other backslashes as they are
+fn unescape(s: &str) -> String {
+    let mut chars = s.chars().peekable();
+    let mut out = String::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next_if(|n| matches!(n, '"' | '\\')) {
+                Some(n) => out.push(n),
+                None => out.push(c),
+            },
+            c => out.push(c),
+        }
+    }
+
+    out
 }
 
 #[cfg(test)]
@@ -130,48 +838,390 @@ mod tests {
     use super::*;
     use crate::controller::Command;
 
+    #[test]
+    fn test_complete() {
+        assert_eq!(complete("", None).len(), COMMANDS.len());
+        assert_eq!(complete("b", None), vec!["b", "backup", "begin"]);
+        assert_eq!(
+            complete("l", None),
+            vec!["l", "la", "ledger", "lessons", "list", "listall", "lr"]
+        );
+        assert_eq!(complete("list g", None), vec!["guitar"]);
+        assert_eq!(complete("la p", None), vec!["piano"]);
+        assert_eq!(
+            complete("list ", None),
+            vec!["--rented-by", "guitar", "piano"]
+        );
+        assert_eq!(
+            complete(
+                "l d",
+                Some(&[String::from("drums"), String::from("guitar")])
+            ),
+            vec!["drums"]
+        );
+        assert_eq!(complete("set autocommit o", None), vec!["off", "on"]);
+        assert_eq!(complete("set lock-wait ", None), vec!["block", "fail"]);
+        assert_eq!(complete("set loc", None), vec!["lock-wait", "locking"]);
+        assert_eq!(complete("set read-only ", None), vec!["off", "on"]);
+        assert!(complete("rent 3 ", None).is_empty());
+        assert!(complete("x", None).is_empty());
+    }
+
     #[test]
     fn test_corr_parse_to_command() {
         let corr = vec![
-            ParseResult::Command(Command::Begin),
-            ParseResult::Command(Command::Begin),
+            ParseResult::Command(Command::AddInstrument {
+                instrument_type: "guitar".into(),
+                brand: "Gibson".into(),
+                model: "J-45 Studio Walnut".into(),
+                price: "101.01".into(),
+                count: "1".into(),
+            }),
+            ParseResult::Command(Command::Audit(20)),
+            ParseResult::Command(Command::Audit(5)),
+            ParseResult::Command(Command::Backup),
+            ParseResult::Command(Command::Begin { force: false }),
+            ParseResult::Command(Command::Begin { force: false }),
+            ParseResult::Command(Command::BeginReadOnly),
+            ParseResult::Command(Command::Begin { force: true }),
+            ParseResult::Command(Command::Begin { force: true }),
+            ParseResult::Command(Command::Begin { force: true }),
             ParseResult::Command(Command::Commit),
             ParseResult::Command(Command::Commit),
+            ParseResult::Command(Command::StudentCost(3)),
+            ParseResult::Command(Command::Damages),
+            ParseResult::Command(Command::FindStudent("john".into())),
+            ParseResult::Command(Command::FindStudent("leila kerr".into())),
+            ParseResult::Command(Command::Fulfil("15".into())),
             ParseResult::Help,
             ParseResult::Help,
-            ParseResult::Command(Command::List(None)),
-            ParseResult::Command(Command::List(None)),
-            ParseResult::Command(Command::List(Some(String::from("gui")))),
-            ParseResult::Command(Command::List(Some(String::from("gui")))),
+            ParseResult::Command(Command::InstrumentTypes),
+            ParseResult::Command(Command::InstrumentTypes),
+            ParseResult::Command(Command::Invoice {
+                student: "3".into(),
+                year_month: "2024-11".into(),
+            }),
+            ParseResult::Command(Command::List(ListFilter::default())),
+            ParseResult::Command(Command::List(ListFilter::default())),
+            ParseResult::Command(Command::List(ListFilter {
+                instrument_type: Some(String::from("gui")),
+                rented_by: None,
+                ..ListFilter::default()
+            })),
+            ParseResult::Command(Command::List(ListFilter {
+                instrument_type: Some(String::from("gui")),
+                rented_by: None,
+                ..ListFilter::default()
+            })),
+            ParseResult::Command(Command::List(ListFilter {
+                instrument_type: Some(String::from("guitar")),
+                rented_by: None,
+                ..ListFilter::default()
+            })),
+            ParseResult::Command(Command::List(ListFilter {
+                instrument_type: Some(String::from("guitar")),
+                rented_by: None,
+                ..ListFilter::default()
+            })),
+            ParseResult::Command(Command::List(ListFilter {
+                instrument_type: None,
+                rented_by: Some(42),
+                ..ListFilter::default()
+            })),
+            ParseResult::Command(Command::List(ListFilter {
+                instrument_type: None,
+                rented_by: Some(42),
+                ..ListFilter::default()
+            })),
+            ParseResult::Command(Command::MaintenanceList),
+            ParseResult::Command(Command::MaintenanceOn("7".into(), "broken string".into())),
+            ParseResult::Command(Command::MaintenanceOff("7".into())),
+            ParseResult::Command(Command::AddNote(
+                "10432".into(),
+                "returning after summer camp".into(),
+            )),
+            ParseResult::Command(Command::Payments(3)),
             ParseResult::Quit,
             ParseResult::Quit,
+            ParseResult::Command(Command::Quote("3".into(), "7".into())),
+            ParseResult::Command(Command::Reconcile("7".into(), "3".into())),
+            ParseResult::Command(Command::ReconcileReport),
             ParseResult::Command(Command::Rent("1".into(), "2".into())),
             ParseResult::Command(Command::Rent("1".into(), "2".into())),
-            ParseResult::Command(Command::Rollback),
-            ParseResult::Command(Command::Rollback),
-            ParseResult::Command(Command::TryTerminate("1".into(), "2".into())),
-            ParseResult::Command(Command::TryTerminate("1".into(), "2".into())),
+            ParseResult::Command(Command::Rent("1".into(), "2".into())),
+            ParseResult::Command(Command::Reserve("3".into(), "7".into())),
+            ParseResult::Command(Command::Reservations(None)),
+            ParseResult::Command(Command::Reservations(Some(7))),
+            ParseResult::Command(Command::Revenue {
+                from: "2024-09-01".into(),
+                to: "2024-11-30".into(),
+                include_archived: false,
+            }),
+            ParseResult::Command(Command::Rollback(None)),
+            ParseResult::Command(Command::Rollback(None)),
+            ParseResult::Command(Command::Rollback(Some("sp1".into()))),
+            ParseResult::Command(Command::Rollback(Some("sp1".into()))),
+            ParseResult::Command(Command::Stats(StatsKind::Inventory)),
+            ParseResult::Command(Command::Stats(StatsKind::Inventory)),
+            ParseResult::Command(Command::Stats(StatsKind::Types)),
+            ParseResult::Command(Command::Stats(StatsKind::Monthly {
+                months: 12,
+                include_archived: false,
+            })),
+            ParseResult::Command(Command::Stats(StatsKind::Monthly {
+                months: 6,
+                include_archived: false,
+            })),
+            ParseResult::Command(Command::Stats(StatsKind::Lifetime)),
+            ParseResult::Command(Command::TryTerminate("1".into(), "2".into(), None)),
+            ParseResult::Command(Command::TryTerminate("1".into(), "2".into(), None)),
+            ParseResult::Command(Command::TryTerminate("1".into(), "2".into(), None)),
+            ParseResult::Command(Command::TryTerminate(
+                "1".into(),
+                "2".into(),
+                Some(Damage {
+                    note: "dent".into(),
+                    amount: None,
+                }),
+            )),
+            ParseResult::Command(Command::TryTerminate(
+                "1".into(),
+                "2".into(),
+                Some(Damage {
+                    note: "cracked body".into(),
+                    amount: Some("50".into()),
+                }),
+            )),
+            ParseResult::Command(Command::TerminateWithNote("1".into(), "graduated".into())),
+            ParseResult::Command(Command::TerminateWithNote(
+                "1".into(),
+                "instrument damaged".into(),
+            )),
+            ParseResult::Command(Command::Unreserve("15".into())),
+            ParseResult::Command(Command::TryUnreserve("3".into(), "7".into())),
+            ParseResult::Command(Command::DryRun(Box::new(Command::Rent(
+                "3".into(),
+                "1".into(),
+            )))),
+            ParseResult::Command(Command::DryRun(Box::new(Command::TryTerminate(
+                "1".into(),
+                "2".into(),
+                Some(Damage {
+                    note: "cracked body".into(),
+                    amount: Some("50".into()),
+                }),
+            )))),
+            ParseResult::Command(Command::Deactivate("3".into())),
+            ParseResult::Command(Command::SetAutocommit(true)),
+            ParseResult::Command(Command::SetAutocommit(false)),
+            ParseResult::Command(Command::Savepoint("before_smith".into())),
+            ParseResult::Command(Command::RollbackTo("before_smith".into())),
+            ParseResult::Command(Command::ReleaseSavepoint("before_smith".into())),
+            ParseResult::Command(Command::Export {
+                what: ExportKind::Rentals,
+                format: ExportFormat::Csv,
+                path: "rentals.csv".into(),
+            }),
+            ParseResult::Command(Command::Export {
+                what: ExportKind::Inventory,
+                format: ExportFormat::Csv,
+                path: "my inventory.csv".into(),
+            }),
+            ParseResult::Command(Command::Export {
+                what: ExportKind::Inventory,
+                format: ExportFormat::Json { pretty: true },
+                path: "inventory.json".into(),
+            }),
+            ParseResult::Command(Command::Export {
+                what: ExportKind::Rentals,
+                format: ExportFormat::Json { pretty: false },
+                path: "rentals.json".into(),
+            }),
+            ParseResult::Command(Command::Sql("SELECT name, 'a  b' FROM students".into())),
+            ParseResult::Command(Command::Archive(24)),
+            ParseResult::Command(Command::ListAll(None)),
+            ParseResult::Command(Command::ListAll(Some("guitar".into()))),
+            ParseResult::Command(Command::Ledger(3)),
+            ParseResult::Command(Command::Lessons(LessonFilter::default())),
+            ParseResult::Command(Command::Lessons(LessonFilter {
+                day: Some("2024-11-20".into()),
+                ..LessonFilter::default()
+            })),
+            ParseResult::Command(Command::Lessons(LessonFilter {
+                student: Some(3),
+                ..LessonFilter::default()
+            })),
+            ParseResult::Command(Command::Revenue {
+                from: "2024-09-01".into(),
+                to: "2024-11-30".into(),
+                include_archived: true,
+            }),
+            ParseResult::Command(Command::Stats(StatsKind::Monthly {
+                months: 12,
+                include_archived: true,
+            })),
+            ParseResult::Command(Command::Stats(StatsKind::Monthly {
+                months: 6,
+                include_archived: true,
+            })),
+            ParseResult::Command(Command::Enroll {
+                student: 3,
+                lesson: 55,
+            }),
+            ParseResult::Command(Command::Unenroll {
+                student: 3,
+                lesson: 55,
+            }),
+            ParseResult::Command(Command::Rent("-1".into(), "2".into())),
+            ParseResult::Command(Command::Instructor {
+                instructor: 4,
+                range: None,
+            }),
+            ParseResult::Command(Command::Instructor {
+                instructor: 4,
+                range: Some(("2024-11-18".into(), "2024-11-24".into())),
+            }),
+            ParseResult::Command(Command::Reset),
+            ParseResult::Command(Command::Reset),
+            ParseResult::Command(Command::List(ListFilter {
+                instrument_type: Some(String::from("guitar")),
+                limit: Some(20),
+                offset: Some(40),
+                ..ListFilter::default()
+            })),
+            ParseResult::Command(Command::List(ListFilter {
+                limit: Some(20),
+                ..ListFilter::default()
+            })),
+            ParseResult::Command(Command::Warmup),
+            ParseResult::Command(Command::Warmup),
+            ParseResult::Command(Command::ClearCache),
+            ParseResult::Command(Command::SetLockWait(LockWait::Block)),
+            ParseResult::Command(Command::SetLockWait(LockWait::Fail)),
+            ParseResult::Command(Command::SetLocking(Locking::Rows)),
+            ParseResult::Command(Command::SetLocking(Locking::Advisory)),
+            ParseResult::Command(Command::SetReadOnly(true)),
+            ParseResult::Command(Command::SetReadOnly(false)),
+            ParseResult::Command(Command::Terminate("5".into(), None)),
+            ParseResult::Command(Command::Terminate(
+                "5".into(),
+                Some(Damage {
+                    note: "dent".into(),
+                    amount: None,
+                }),
+            )),
         ];
 
         let data = vec![
+            "add guitar Gibson \"J-45 Studio Walnut\" 101.01 1",
+            "audit",
+            "audit 5",
+            "backup",
             "b",
             "begin",
+            "begin --read-only",
+            "begin --force",
+            "begin!",
+            "fbegin",
             "c",
             "commit",
+            "cost 3",
+            "damages",
+            "fs john",
+            "find  leila kerr ",
+            "fulfil 15",
             "h",
             "help",
+            "it",
+            "types",
+            "invoice 3 2024-11",
             "l",
             "list",
             "l gui",
             "list gui",
+            "list guitar ",
+            "list  guitar",
+            "list --rented-by 42",
+            "lr 42",
+            "maintenance list",
+            "maintenance 7 on \"broken string\"",
+            "maintenance 7 off",
+            "note 10432 \"returning after summer camp\"",
+            "payments 3",
             "q",
             "quit",
+            "quote 3 7",
+            "reconcile 7 3",
+            "reconcile report",
             "re 1 2",
             "rent 1 2",
+            "re 1  2",
+            "reserve 3 7",
+            "reservations",
+            "reservations 7",
+            "revenue 2024-09-01 2024-11-30",
             "ro",
             "rollback",
+            "ro sp1",
+            "rollback sp1",
+            "s",
+            "stats",
+            "stats types",
+            "stats monthly",
+            "stats monthly 6",
+            "stats lifetime",
             "t 1 2",
             "terminate 1 2",
+            "t  1 2",
+            "t 1 2 --damaged dent",
+            "t 1 2 --damaged \"cracked body\" 50",
+            "tn 1 graduated",
+            "tn 1 \"instrument damaged\"",
+            "unreserve 15",
+            "unreserve 3 7",
+            "re 3 1 --dry-run",
+            "t 1 2 --damaged \"cracked body\" 50 --dry-run",
+            "deact 3",
+            "set autocommit on",
+            "set  autocommit off",
+            "save before_smith",
+            "rollback-to before_smith",
+            "release  before_smith",
+            "export rentals rentals.csv",
+            "export  inventory \"my inventory.csv\"",
+            "export inventory --json inventory.json",
+            "export rentals --json --compact rentals.json",
+            "sql SELECT name, 'a  b' FROM students ",
+            "archive 24",
+            "la",
+            "listall  guitar",
+            "ledger 3",
+            "lessons",
+            "lessons 2024-11-20",
+            "lessons  student 3",
+            "revenue 2024-09-01 2024-11-30 --include-archived",
+            "stats monthly --include-archived",
+            "stats monthly 6 --include-archived",
+            "enroll 3 55",
+            "unenroll 3 55",
+            "re -1 2",
+            "instructor 4",
+            "instructor 4 2024-11-18 2024-11-24",
+            "reset",
+            "rs",
+            "list guitar --limit 20 --offset 40",
+            "l --limit 20",
+            "warmup",
+            "w",
+            "clearcache",
+            "set lock-wait block",
+            "set lock-wait fail",
+            "set locking rows",
+            "set locking advisory",
+            "set read-only on",
+            "set read-only off",
+            "terminate rent 5",
+            "t rent 5 --damaged dent",
         ];
 
         for i in 0..data.len() {
@@ -181,18 +1231,198 @@ mod tests {
 
     #[test]
     fn test_fail_parse_to_command() {
-        let corr = vec![
+        let corr = [
             ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::NoStudent,
+            ParseError::NoStudent,
+            ParseError::NoInstrument,
+            ParseError::NoInstrument,
             ParseError::NoStudent,
+            ParseError::Default,
+            ParseError::NoNote,
+            ParseError::NoNote,
             ParseError::NoStudent,
+            ParseError::Default,
+            ParseError::NoInstrument,
+            ParseError::Default,
             ParseError::NoInstrument,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
             ParseError::NoInstrument,
+            ParseError::NoNote,
+            ParseError::Default,
+            ParseError::NoNote,
+            ParseError::Default,
+            ParseError::NoNote,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::NoStudent,
+            ParseError::NoStudent,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::NoStudent,
+            ParseError::Default,
+            ParseError::NoStudent,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::NoStudent,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::InvalidId("student".into(), "abc".into()),
+            ParseError::InvalidId("instrument".into(), "1x".into()),
+            ParseError::InvalidId("student".into(), "-".into()),
+            ParseError::Default,
+            ParseError::InvalidId("instructor".into(), "x".into()),
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
+            ParseError::Default,
         ];
 
-        let data = vec!["x", "re", "t", "re 1", "t 1"];
+        let data = [
+            "",
+            " ",
+            "  ",
+            "x",
+            "add guitar Gibson 101.01 1",
+            "re",
+            "t",
+            "re 1",
+            "t 1",
+            "lr",
+            "list --rented-by x",
+            "tn 1",
+            "tn 1 \"\"",
+            "fs",
+            "stats monthly x",
+            "reconcile",
+            "reconcile 7",
+            "reserve 3",
+            "reservations x",
+            "unreserve",
+            "fulfil",
+            "maintenance",
+            "maintenance 7 on",
+            "maintenance 7",
+            "t 1 2 --damaged",
+            "t 1 2 x",
+            "note 10432",
+            "audit x",
+            "help --dry-run",
+            "re --dry-run",
+            "deact",
+            "set autocommit",
+            "set x on",
+            "save",
+            "release",
+            "sql  ",
+            "export rentals",
+            "export students s.csv",
+            "export inventory a.csv b.csv",
+            "export inventory --compact a.json",
+            "archive",
+            "archive x",
+            "revenue 2024-09-01 2024-11-30 --archived",
+            "stats monthly 6 7",
+            "ledger",
+            "ledger x",
+            "lessons student",
+            "lessons student x",
+            "lessons 2024-11-20 x",
+            "enroll",
+            "enroll 3",
+            "unenroll 3 x",
+            "enroll 3 55 1",
+            "re abc 1",
+            "rent 3 1x",
+            "re - 1",
+            "instructor",
+            "instructor x",
+            "instructor 4 2024-11-18",
+            "instructor 4 2024-11-18 2024-11-24 x",
+            "list --limit",
+            "list --offset -1",
+            "list guitar --limit 5 --rented-by 3",
+            "costs 3",
+            "cst 3",
+            "quotes 3 1",
+            "qiut",
+            "la guitar piano",
+            "fetch john",
+            "fbegn",
+            "bogus",
+            "beginning",
+        ];
 
         for i in 0..data.len() {
             assert_eq!(parse_to_command(data[i]).unwrap_err(), corr[i]);
         }
+        assert_eq!(
+            ParseError::InvalidId("student".into(), "abc".into()).to_string(),
+            "Invalid student ID 'abc': expected an integer"
+        );
+    }
+
+    #[test]
+    fn test_terminate_round_trip() {
+        let damage = |amount: Option<&str>| {
+            Some(Damage {
+                note: "cracked body".into(),
+                amount: amount.map(String::from),
+            })
+        };
+        let commands = || {
+            [
+                Command::Terminate("5".into(), None),
+                Command::Terminate("5".into(), damage(None)),
+                Command::Terminate("5".into(), damage(Some("50"))),
+                Command::TryTerminate("1".into(), "2".into(), None),
+                Command::TryTerminate("1".into(), "2".into(), damage(Some("50"))),
+                Command::Terminate(
+                    "5".into(),
+                    Some(Damage {
+                        note: r#"the "good" side \ front"#.into(),
+                        amount: Some("50".into()),
+                    }),
+                ),
+                Command::TerminateWithNote("5".into(), r#""quoted""#.into()),
+                Command::TerminateWithNote("5".into(), r#"said "fine" \n"#.into()),
+            ]
+        };
+
+        for (c, expected) in commands().into_iter().zip(commands()) {
+            assert_eq!(
+                parse_to_command(&c.to_string()).unwrap(),
+                ParseResult::Command(expected),
+                "{c}"
+            );
+        }
     }
 }
@@ -1,19 +1,162 @@
-use std::io::{self, Write};
+use sqlx::types::BigDecimal;
+use std::{
+    cmp::Ordering,
+    env,
+    fmt::Display,
+    fs::File,
+    io::{self, BufRead, BufReader, IsTerminal, Write},
+    path::PathBuf,
+    process::ExitCode,
+};
 
 use crate::{
-    controller::{Command, ControlError, ControlResult, Controller},
+    controller::{Command, ControlError, ControlResult, ControlResultWithMeta, Controller},
+    db::{self, Lesson, LockWait, Locking, Reservation, SqlOutput},
+    help,
     parser::{self, ParseResult},
 };
 
-const COMMAND_STRING: &str = "Commands: (is optional) [is required]\n\
-                              Begin:\t\tb(egin)\n\
-                              Commit:\t\tc(ommit)\n\
-                              Help:\t\th(elp)\n\
-                              List:\t\tl(ist) (instrument_type)\n\
-                              Quit:\t\tq(uit)\n\
-                              Rent:\t\tre(nt) [student] [instrument]\n\
-                              Rollback:\tro(llback)\n\
-                              Terminate:\tt(erminate) [student] [instrument]";
+/// How the repl reads its commands
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReplMode {
+    /// Prints the welcome, help and prompts and reads from stdin
+    Interactive,
+    /// Reads commands from stdin line by line without any prompts, continuing after errors
+    Batch,
+    /// Reads commands from a file line by line without any prompts, aborting on the first error
+    File(PathBuf),
+}
+
+const COLOR_KEY: &str = "SGDB_COLOR";
+const NO_EMOJI_KEY: &str = "SGDB_NO_EMOJI";
+/// How many times the user is asked for an id from the list before giving up
+const PICK_ATTEMPTS: usize = 3;
+
+/// If the output of the repl is colored, read from `SGDB_COLOR`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Colors each stream only if that stream is a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    /// Reads the mode from `SGDB_COLOR` which can be `auto`, `always` or `never`, defaulting to
+    /// [`ColorMode::Auto`] if it is unset or not understood
+    pub fn from_env() -> Self {
+        match env::var(COLOR_KEY).unwrap_or_default().trim() {
+            "always" => Self::Always,
+            "never" => Self::Never,
+            _ => Self::Auto,
+        }
+    }
+
+    fn enabled(self, stream: Stream) -> bool {
+        match self {
+            Self::Auto => stream.is_terminal(),
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+/// The stream colored output is written to, which decides if [`ColorMode::Auto`] colors it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    /// Results, printed with `println!`
+    Stdout,
+    /// Errors and warnings, printed with `eprintln!`
+    Stderr,
+}
+
+impl Stream {
+    fn is_terminal(self) -> bool {
+        match self {
+            Self::Stdout => io::stdout().is_terminal(),
+            Self::Stderr => io::stderr().is_terminal(),
+        }
+    }
+}
+
+/// How the prompt and welcome message are marked, plain for terminals and log files which can not
+/// show emoji
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptStyle {
+    /// Marked with 🎵
+    Emoji,
+    /// Marked with `*`
+    Plain,
+}
+
+impl PromptStyle {
+    /// Picks [`PromptStyle::Plain`] if `no_emoji` is set, e.g. by `--no-emoji`, if `SGDB_NO_EMOJI`
+    /// is `true` or if stdout is not a terminal, otherwise [`PromptStyle::Emoji`]
+    pub fn detect(no_emoji: bool) -> Self {
+        Self::choose(
+            no_emoji,
+            env::var(NO_EMOJI_KEY).ok().as_deref(),
+            io::stdout().is_terminal(),
+        )
+    }
+
+    fn choose(no_emoji: bool, env: Option<&str>, terminal: bool) -> Self {
+        let env = env.is_some_and(|v| matches!(v.trim(), "true" | "1" | "yes"));
+        if no_emoji || env || !terminal {
+            Self::Plain
+        } else {
+            Self::Emoji
+        }
+    }
+
+    const fn mark(self) -> &'static str {
+        match self {
+            Self::Emoji => "🎵",
+            Self::Plain => "*",
+        }
+    }
+
+    /// The prompt printed before reading each command
+    pub fn prompt(self) -> String {
+        format!("{}>>> ", self.mark())
+    }
+
+    /// The message printed when the repl starts
+    pub fn welcome(self) -> String {
+        let m = self.mark();
+        format!("Welcome to the {m} Soundgood Music School Database Program {m}")
+    }
+}
+
+/// The colors used for the output of the repl
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    /// Successful changes, such as rent, commit and terminate
+    Green,
+    /// Errors
+    Red,
+    /// Pending state, such as a newly begun transaction
+    Yellow,
+}
+
+impl Color {
+    const fn code(self) -> &'static str {
+        match self {
+            Self::Green => "\x1b[32m",
+            Self::Red => "\x1b[31m",
+            Self::Yellow => "\x1b[33m",
+        }
+    }
+}
+
+/// Wraps `s` in the ANSI escape codes for `color` if `mode` has colors enabled for `stream`
+pub fn colorize(s: &str, color: Color, mode: ColorMode, stream: Stream) -> String {
+    if mode.enabled(stream) {
+        format!("{}{s}\x1b[0m", color.code())
+    } else {
+        s.to_string()
+    }
+}
 
 /// Starts the read-evaluate-print-loop
 ///
@@ -23,81 +166,750 @@ const COMMAND_STRING: &str = "Commands: (is optional) [is required]\n\
 ///
 /// # Parameters
 /// - `con` mutable refernce to the controller which acts as the "parent" to this repl view
-pub async fn repl<'a>(con: &mut Controller<'a>) {
+/// - `mode` where to read commands from and if prompts should be printed, see [`ReplMode`]
+/// - `verbose` if the time each successful command took should be printed after its result
+/// - `style` how the prompt and welcome message are marked, see [`PromptStyle::detect`]
+///
+/// # Returns
+/// [`ExitCode::SUCCESS`] when quit or the end of the input is reached without any command
+/// failing, otherwise [`ExitCode::FAILURE`]
+pub async fn repl<'a>(
+    con: &mut Controller<'a>,
+    mode: ReplMode,
+    verbose: bool,
+    style: PromptStyle,
+) -> ExitCode {
+    let interactive = mode == ReplMode::Interactive;
+    let color = ColorMode::from_env();
+    let abort_on_error = matches!(mode, ReplMode::File(_));
+    let mut reader: Box<dyn BufRead> = match mode {
+        ReplMode::Interactive | ReplMode::Batch => Box::new(io::stdin().lock()),
+        ReplMode::File(path) => match File::open(&path) {
+            Ok(f) => Box::new(BufReader::new(f)),
+            Err(e) => {
+                eprintln!("Could not open {}: {e}", path.display());
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+
     let mut input = String::new();
-    println!("Welcome to the 🎵 Soundgood Music School Database Program 🎵");
-    println!("{COMMAND_STRING}");
+    let mut failed = false;
+    if interactive {
+        println!("{}", style.welcome());
+        println!("{}", help::format_help(help::COMMANDS));
+    }
 
     loop {
-        print!("\n🎵>>> ");
-        flush_and_read(&mut input);
+        if interactive {
+            print!("\n{}", style.prompt());
+        }
+        if !flush_and_read(&mut reader, &mut input) {
+            break;
+        }
 
-        match parser::parse_to_command(&input) {
+        let ok = match parser::parse_to_command(&input) {
             Ok(r) => match r {
-                ParseResult::Help => println!("{COMMAND_STRING}"),
+                ParseResult::Help => {
+                    if interactive {
+                        println!("{}", help::format_help(help::COMMANDS));
+                    }
+                    true
+                }
                 ParseResult::Quit => break,
                 ParseResult::Command(c) => match c {
-                    Command::TryTerminate(u, i) => handle_terminate(con, u, i).await,
-                    _ => match con.execute(c).await {
-                        Ok(r) => print_control_result(r),
-                        Err(e) => eprintln!("{e}"),
-                    },
+                    Command::TryUnreserve(..) => {
+                        handle_multiple(con, &mut reader, interactive, verbose, color, c).await
+                    }
+                    Command::Begin { force: false } if interactive => {
+                        handle_begin(con, &mut reader, verbose, color).await
+                    }
+                    _ => execute(con, c, verbose, color).await.is_ok(),
                 },
             },
-            Err(e) => eprintln!("{e}"),
-        }
+            Err(e) => {
+                print_error(&e, color);
+                false
+            }
+        };
 
         input.clear();
+        failed |= !ok;
+        if !ok && abort_on_error {
+            break;
+        }
+    }
+
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
     }
 }
 
-async fn handle_terminate<'a>(con: &mut Controller<'a>, user: String, inst: String) {
-    let result = con.execute(Command::TryTerminate(user, inst)).await;
-    match result {
-        Ok(r) => print_control_result(r),
-        Err(e) => match e {
-            ControlError::TerminateMultiple(ref vec) => {
-                eprintln!("{e}");
-                println!("Please pick one from the following list:");
-                for row in vec {
-                    println!("{row}");
-                }
+/// Executes a command which may match several rows, and if it does lets the user pick the id of
+/// the row to act on
+async fn handle_multiple<'a>(
+    con: &mut Controller<'a>,
+    reader: &mut Box<dyn BufRead>,
+    interactive: bool,
+    verbose: bool,
+    color: ColorMode,
+    c: Command,
+) -> bool {
+    let Err(e) = execute(con, c, verbose, color).await else {
+        return true;
+    };
+
+    let ControlError::UnreserveMultiple(vec) = e else {
+        return false;
+    };
+
+    println!("Please pick one from the following list:");
+    for row in &vec {
+        println!("{row}");
+    }
 
-                print!("ID to terminate: ");
-                let mut input = String::new();
-                flush_and_read(&mut input);
+    let ids: Vec<i32> = vec.iter().map(Reservation::get_id).collect();
+    // Scripts are not asked again, the next line is their next command and not another id
+    let attempts = if interactive { PICK_ATTEMPTS } else { 1 };
+    for _ in 0..attempts {
+        if interactive {
+            print!("ID to cancel: ");
+        }
+        let mut input = String::new();
+        if !flush_and_read(reader, &mut input) {
+            return false;
+        }
 
-                let res = con.execute(Command::Terminate(input.trim().into())).await;
-                match res {
-                    Ok(cr) => print_control_result(cr),
-                    Err(e) => eprintln!("{e}"),
+        match pick_listed_id(input.trim(), &ids) {
+            Ok(id) => {
+                let pick = Command::Unreserve(id.to_string());
+                return execute(con, pick, verbose, color).await.is_ok();
+            }
+            Err(msg) => println!("{msg}"),
+        }
+    }
+
+    false
+}
+
+/// Parses the id the user picked, which must be one of the listed `ids` so that a typo can not
+/// act on an unrelated row
+fn pick_listed_id(input: &str, ids: &[i32]) -> Result<i32, String> {
+    let id = input
+        .parse::<i32>()
+        .map_err(|_| format!("'{input}' is not an ID. Please enter one of the shown IDs."))?;
+    if ids.contains(&id) {
+        Ok(id)
+    } else {
+        Err(String::from(
+            "ID not in the listed reservations. Please enter one of the shown IDs.",
+        ))
+    }
+}
+
+/// Executes a command and prints its result or error, noting if it ran in its own transaction
+/// because autocommit is on
+///
+/// Returns the error after printing it so the caller can act on it
+async fn execute<'a>(
+    con: &mut Controller<'a>,
+    c: Command,
+    verbose: bool,
+    color: ColorMode,
+) -> Result<(), ControlError> {
+    let autocommit = con.autocommits(&c);
+    match con.execute(c).await {
+        Ok(r) => {
+            print_control_result(r, verbose, color);
+            if autocommit {
+                println!("(autocommitted)");
+            }
+            Ok(())
+        }
+        Err(e) => {
+            if let ControlError::CommitUncertain(_) = e {
+                print_error(
+                    &"WARNING: THE COMMIT MAY OR MAY NOT HAVE BEEN SAVED!",
+                    color,
+                );
+                print_error(&e, color);
+                print_error(
+                    &"Verify the changes, e.g. with list, before retrying.",
+                    color,
+                );
+            } else {
+                // Busy is not a failure of the command, it may well succeed when retried
+                if e == ControlError::ResourceBusy {
+                    eprintln!(
+                        "{}",
+                        colorize(&e.to_string(), Color::Yellow, color, Stream::Stderr)
+                    );
+                } else {
+                    print_error(&e, color);
+                }
+                if autocommit {
+                    eprintln!("(autocommit rolled back)");
                 }
             }
-            _ => eprintln!("{e}"),
-        },
+            Err(e)
+        }
+    }
+}
+
+/// Begins a transaction, and if one is already open asks the user if it should be rolled back
+/// to begin a new one
+async fn handle_begin<'a>(
+    con: &mut Controller<'a>,
+    reader: &mut Box<dyn BufRead>,
+    verbose: bool,
+    color: ColorMode,
+) -> bool {
+    match execute(con, Command::Begin { force: false }, verbose, color).await {
+        Ok(()) => return true,
+        Err(ControlError::TransactionOpen) => {}
+        Err(_) => return false,
+    }
+
+    print!("Roll back the open transaction and begin a new one? (y/N): ");
+    let mut input = String::new();
+    if !flush_and_read(reader, &mut input) || !input.trim().eq_ignore_ascii_case("y") {
+        return false;
+    }
+
+    execute(con, Command::Begin { force: true }, verbose, color)
+        .await
+        .is_ok()
+}
+
+/// Prints the result of a command, followed by when it completed and how long it took if verbose
+fn print_control_result(r: ControlResultWithMeta, verbose: bool, color: ColorMode) {
+    let meta = r.to_string();
+    if let Some(w) = &r.audit_warning {
+        eprintln!(
+            "{}",
+            colorize(
+                &format!("Warning: {w}"),
+                Color::Yellow,
+                color,
+                Stream::Stderr
+            )
+        );
     }
+    print_result(r.result, color);
+    if verbose {
+        println!("{meta}");
+    }
+}
+
+/// Prints an error to stderr in red
+fn print_error(e: &dyn Display, color: ColorMode) {
+    eprintln!(
+        "{}",
+        colorize(&e.to_string(), Color::Red, color, Stream::Stderr)
+    );
 }
 
-fn print_control_result(cr: ControlResult) {
+fn print_result(cr: ControlResult, color: ColorMode) {
     match cr {
-        ControlResult::Begin => println!("Begun new transaction!"),
-        ControlResult::Commit => println!("Commited!"),
+        ControlResult::AddInstrument(id) => println!("Added! New instrument id {id}."),
+        ControlResult::Archived(n) => println!("Archived {n} terminated rentings."),
+        ControlResult::Audit(v) => v.iter().for_each(|a| println!("{a}")),
+        ControlResult::Backup { file, rows } => println!("Backup written to {file} ({rows} rows)"),
+        ControlResult::Export { what, path, rows } => {
+            println!("Exported {rows} {what} rows to {path}")
+        }
+        ControlResult::Autocommit(true) => {
+            println!("Autocommit on, commands outside a transaction are committed right away.");
+        }
+        ControlResult::Autocommit(false) => {
+            println!("Autocommit off, begin a transaction before running commands.");
+        }
+        ControlResult::LockWait(LockWait::Block) => {
+            println!("Lock wait block, commands wait for rentals locked by other sessions.");
+        }
+        ControlResult::LockWait(LockWait::Fail) => {
+            println!(
+                "Lock wait fail, commands fail at once if rentals are locked by other sessions."
+            );
+        }
+        ControlResult::Locking(Locking::Rows) => println!("Locking the rentings rows."),
+        ControlResult::Locking(Locking::Advisory) => println!("Locking with advisory locks."),
+        ControlResult::ReadOnly(true) => {
+            println!("Read only on, transactions are begun read only and refuse writes.");
+        }
+        ControlResult::ReadOnly(false) => println!("Read only off, transactions may write."),
+        ControlResult::Begin(rolled_back) => {
+            if rolled_back {
+                println!("Previous transaction rolled back!");
+            }
+            println!(
+                "{}",
+                colorize(
+                    "Begun new transaction!",
+                    Color::Yellow,
+                    color,
+                    Stream::Stdout
+                )
+            );
+        }
+        ControlResult::Commit => println!(
+            "{}",
+            colorize("Commited!", Color::Green, color, Stream::Stdout)
+        ),
+        ControlResult::Invoice { lines, total } => {
+            lines.iter().for_each(|l| println!("{l}"));
+            println!("Total to invoice {}.", db::format_currency(&total));
+        }
         ControlResult::List(v) => v.iter().for_each(|i| println!("{i}")),
-        ControlResult::Rent(r) => print_rows("Rented!", r),
-        ControlResult::Rollback => println!("Rolled back!"),
-        ControlResult::Terminate(r) | ControlResult::TryTerminate(r) => {
-            print_rows("Terminated!", r);
+        ControlResult::ListAll(v) => v
+            .iter()
+            .for_each(|i| println!("{i}{}", availability_marker(i.available))),
+        ControlResult::ListPage {
+            instruments,
+            offset,
+            total,
+        } => {
+            instruments.iter().for_each(|i| println!("{i}"));
+            if instruments.is_empty() {
+                println!("Nothing on this page, there are {total} instruments.");
+            } else if offset == 0 && (instruments.len() as i64) < total {
+                println!(
+                    "Showing {} of {total} available instruments. Use --limit to adjust.",
+                    instruments.len()
+                );
+            } else {
+                let last = offset as usize + instruments.len();
+                println!("Showing {}–{last} of {total}.", offset + 1);
+            }
+        }
+        ControlResult::Stats(v) => v.iter().for_each(|i| println!("{i}")),
+        ControlResult::StatsMonthly(v) => {
+            println!(
+                "{:<7}  {:>8} {:>8} {:>8}",
+                "Month", "Started", "Ended", "Net"
+            );
+            v.iter().for_each(|m| println!("{m}"));
+        }
+        ControlResult::StatsLifetime(v) => {
+            println!(
+                "{:<4} {:<10} {:<28} {:>8} {:>8}",
+                "Id", "Type", "Instrument", "Active", "Total"
+            );
+            v.iter().for_each(|r| println!("{r}"));
+        }
+        ControlResult::StatsTypes(v) => {
+            println!(
+                "{:<12} {:>8} {:>8} {:>12} {:>10}",
+                "Type", "Rented", "Units", "Utilisation", "Avg days"
+            );
+            v.iter().for_each(|t| println!("{t}"));
+        }
+        ControlResult::Quote { list_price, price } => print_price(&list_price, &price),
+        ControlResult::Reconcile { stock, counted } => {
+            println!("{stock}");
+            let diff = counted - stock.get_expected();
+            match diff.cmp(&0) {
+                Ordering::Equal => println!("Counted {counted}, matches the expected stock."),
+                Ordering::Less => println!("Counted {counted}, {} missing!", -diff),
+                Ordering::Greater => println!("Counted {counted}, {diff} more than expected!"),
+            }
+        }
+        ControlResult::ReconcileReport(v) => v.iter().for_each(|s| println!("{s}")),
+        ControlResult::Rent {
+            rent_id,
+            start_date,
+            list_price,
+            price,
+            deposit,
+            reserved_ahead,
+        } => {
+            let rented = format!(
+                "Rented! New rental id {rent_id} started {:04}-{:02}-{:02} {:02}:{:02}.",
+                start_date.year(),
+                u8::from(start_date.month()),
+                start_date.day(),
+                start_date.hour(),
+                start_date.minute()
+            );
+            println!("{}", colorize(&rented, Color::Green, color, Stream::Stdout));
+            print_price(&list_price, &price);
+            if deposit > BigDecimal::from(0) {
+                println!("Deposit required {}.", db::format_currency(&deposit));
+            }
+            if reserved_ahead > 0 {
+                eprintln!(
+                    "Warning: {reserved_ahead} reservation(s) for this instrument were made before this student's."
+                );
+            }
+        }
+        ControlResult::Revenue { rows, total } => {
+            println!(
+                "{:<7}  {:<12} {:>10} {:>10} {:>10}",
+                "Month", "Type", "Rentals", "Fees", "Total"
+            );
+            rows.iter().for_each(|r| println!("{r}"));
+            println!("Grand total {}.", db::format_currency(&total));
+        }
+        ControlResult::Reservations(v) => {
+            if v.is_empty() {
+                println!("No active reservations.");
+            }
+            v.iter().for_each(|r| println!("{r}"));
+        }
+        ControlResult::Fulfilled {
+            reservation_id,
+            rent_id,
+            list_price,
+            price,
+            deposit,
+        } => {
+            println!("Fulfilled reservation {reservation_id}! New rental id {rent_id}.");
+            print_price(&list_price, &price);
+            if deposit > BigDecimal::from(0) {
+                println!("Deposit required {}.", db::format_currency(&deposit));
+            }
+        }
+        ControlResult::Maintenance(v) => v.iter().for_each(|m| println!("{m}")),
+        ControlResult::NoteAdded {
+            note_id,
+            rent_id,
+            active,
+        } => {
+            println!("Added note {note_id} to renting {rent_id}!");
+            match active {
+                Some(true) => {}
+                Some(false) => println!("Note that renting {rent_id} has ended."),
+                None => println!("Note that there is no renting {rent_id}."),
+            }
+        }
+        ControlResult::MaintenanceEnded(id) => println!("Ended maintenance {id}!"),
+        ControlResult::MaintenanceStarted(id) => {
+            println!("Sent for repair! New maintenance id {id}.");
+        }
+        ControlResult::Reserved(id) => println!("Reserved! New reservation id {id}."),
+        ControlResult::Unreserved(id) => println!("Cancelled reservation {id}!"),
+        ControlResult::Enrolled {
+            student,
+            lesson,
+            free_places,
+        } => {
+            println!("Enrolled student {student} in lesson {lesson}! {free_places} place(s) left.")
+        }
+        ControlResult::Unenrolled {
+            student,
+            lesson,
+            free_places,
+        } => {
+            println!("Removed student {student} from lesson {lesson}! {free_places} place(s) left.")
+        }
+        ControlResult::Rollback(true) => println!("Rolled back!"),
+        ControlResult::Reset => println!("Transaction reset. New transaction started."),
+        ControlResult::Warmup { types_loaded } => {
+            println!("Warmed up, {types_loaded} instrument types cached.");
+        }
+        ControlResult::CacheCleared => println!("Cache cleared."),
+        ControlResult::Rollback(false) => println!("Rolled back to savepoint!"),
+        ControlResult::Released(name) => println!("Released savepoint {name}!"),
+        ControlResult::Savepoint(name) => {
+            println!("Saved! Roll back to it with rollback-to {name}.")
+        }
+        ControlResult::Sql(SqlOutput::Affected(n)) => print_rows("Executed!", n),
+        ControlResult::Sql(SqlOutput::Rows { columns, rows }) => print_table(&columns, &rows),
+        ControlResult::StudentCost { rentals, total } => {
+            rentals.iter().for_each(|r| println!("{r}"));
+            println!("Total monthly cost {}.", db::format_currency(&total));
+        }
+        ControlResult::Deactivated(u) => println!("Deactivated student {u}!"),
+        ControlResult::DryRun(s) => println!("{s}. Nothing was changed."),
+        ControlResult::Damages(v) => v.iter().for_each(|d| println!("{d}")),
+        ControlResult::InstrumentTypes(v) => v.iter().for_each(|t| println!("{t}")),
+        ControlResult::FindStudent(v) => v.iter().for_each(|s| println!("{s}")),
+        ControlResult::Payments(v) => v.iter().for_each(|p| println!("{p}")),
+        ControlResult::Lessons {
+            day,
+            student,
+            lessons,
+        } => match (lessons.is_empty(), day, student) {
+            (true, _, Some(u)) => println!("Student {u} has not booked any lessons."),
+            (true, Some(d), None) => println!("No lessons are scheduled on {d}."),
+            (true, None, None) => println!("No lessons are scheduled."),
+            (false, ..) => print_lessons(&lessons),
+        },
+        ControlResult::Instructor {
+            instructor,
+            range,
+            lessons,
+            this_month,
+        } => {
+            match (lessons.is_empty(), range) {
+                (true, Some((from, to))) => {
+                    println!("Instructor {instructor} has no lessons from {from} to {to}.");
+                }
+                (true, None) => println!("Instructor {instructor} has no upcoming lessons."),
+                (false, _) => print_lessons(&lessons),
+            }
+            println!("Instructor {instructor} teaches {this_month} lesson(s) this month.");
+        }
+        ControlResult::Ledger { entries, balance } => {
+            entries.iter().for_each(|e| println!("{e}"));
+            println!("Balance {}.", db::format_currency(&balance));
+        }
+        ControlResult::Terminate {
+            renting,
+            charge,
+            waiting,
+        }
+        | ControlResult::TryTerminate {
+            renting,
+            charge,
+            waiting,
+        } => {
+            let start = renting.get_start_date().date();
+            println!(
+                "{}",
+                colorize(
+                    &format!(
+                        "Terminated rent #{} for student {} (started {start})",
+                        renting.get_id(),
+                        renting.get_student_id()
+                    ),
+                    Color::Green,
+                    color,
+                    Stream::Stdout
+                )
+            );
+            if let Some(c) = charge {
+                println!(
+                    "Charge for the final period {}.",
+                    db::format_currency(&c.amount)
+                );
+                if c.late_fee > BigDecimal::from(0) {
+                    println!("Including late fee {}.", db::format_currency(&c.late_fee));
+                }
+                if c.damage > BigDecimal::from(0) {
+                    println!("Damage charge {}.", db::format_currency(&c.damage));
+                }
+                if c.deposit_refund > BigDecimal::from(0) {
+                    println!(
+                        "Deposit to refund {}.",
+                        db::format_currency(&c.deposit_refund)
+                    );
+                }
+            }
+            print_waiting(&waiting);
         }
     }
 }
 
-fn flush_and_read(buf: &mut String) {
-    io::stdout().flush().expect("Could not flush stdout!");
-    io::stdin()
-        .read_line(buf)
-        .expect("Could not read from stdin!");
+/// Prints the students waiting for a returned instrument, highlighting the front of the queue
+fn print_waiting(waiting: &[Reservation]) {
+    let students: Vec<String> = waiting
+        .iter()
+        .enumerate()
+        .map(|(n, r)| match n {
+            0 => format!("student {} (next)", r.get_student_id()),
+            _ => format!("student {}", r.get_student_id()),
+        })
+        .collect();
+
+    match students.len() {
+        0 => {}
+        1 => println!("1 student is waiting for this instrument: {}", students[0]),
+        n => println!(
+            "{n} students are waiting for this instrument: {}",
+            students.join(", ")
+        ),
+    }
+}
+
+/// Marks an instrument listed by `la` which has no unit left to rent, or more units rented out
+/// than the school has
+const fn availability_marker(available: i64) -> &'static str {
+    match available {
+        0 => " [FULLY RENTED]",
+        a if a < 0 => " [OVERBOOKED - DATA ERROR]",
+        _ => "",
+    }
+}
+
+/// Flushes stdout and reads a line into `buf`, retrying if the read is interrupted by a signal
+///
+/// Returns `false` at the end of the input or if flushing or reading fails, `true` if a line was
+/// read
+fn flush_and_read(reader: &mut Box<dyn BufRead>, buf: &mut String) -> bool {
+    if let Err(e) = io::stdout().flush() {
+        eprintln!("Write error: {e}");
+        return false;
+    }
+    loop {
+        match reader.read_line(buf) {
+            Ok(0) => return false,
+            Ok(_) => return true,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => buf.clear(),
+            Err(e) => {
+                eprintln!("Read error: {e}");
+                return false;
+            }
+        }
+    }
+}
+
+fn print_price(list_price: &BigDecimal, price: &BigDecimal) {
+    let (list_price, price) = (db::format_currency(list_price), db::format_currency(price));
+    if list_price == price {
+        println!("Monthly price {price}.");
+    } else {
+        println!("Monthly price {price} with sibling discount (list price {list_price}).");
+    }
+}
+
+/// Prints lessons as a table, one row per lesson
+fn print_lessons(lessons: &[Lesson]) {
+    let columns = [
+        "Id",
+        "Start",
+        "End",
+        "Type",
+        "Subject",
+        "Instructors",
+        "Places",
+    ]
+    .map(String::from);
+    let rows: Vec<Vec<String>> = lessons
+        .iter()
+        .map(|l| {
+            vec![
+                l.get_id().to_string(),
+                db::format_timestamp(l.get_start_date()),
+                db::format_timestamp(l.get_end_date()),
+                l.get_kind().to_string(),
+                String::from(l.get_subject()),
+                String::from(l.get_instructors()),
+                format!("{}/{}", l.get_booked(), l.get_max_places()),
+            ]
+        })
+        .collect();
+    print_table(&columns, &rows);
+}
+
+/// Prints rows as a table with a header, each column as wide as its widest value
+fn print_table(columns: &[String], rows: &[Vec<String>]) {
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            rows.iter()
+                .map(|r| r[i].chars().count())
+                .chain([c.chars().count()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let line = |values: &[String]| {
+        let cells: Vec<String> = values
+            .iter()
+            .zip(&widths)
+            .map(|(v, w)| format!("{v:<w$}"))
+            .collect();
+        println!("{}", cells.join(" | ").trim_end());
+    };
+
+    line(columns);
+    let rule: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+    println!("{}", rule.join("-+-"));
+    rows.iter().for_each(|r| line(r));
+    println!("({} rows)", rows.len());
 }
 
 fn print_rows(s: &str, n: u64) {
     println!("{s} {n} rows affected!");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prompt_style() {
+        let plain = [
+            (true, None, true),
+            (false, Some("true"), true),
+            (false, Some(" 1 "), true),
+            (false, None, false),
+        ];
+        for (flag, env, terminal) in plain {
+            assert_eq!(
+                PromptStyle::choose(flag, env, terminal),
+                PromptStyle::Plain,
+                "{flag} {env:?} {terminal}"
+            );
+        }
+        assert_eq!(
+            PromptStyle::choose(false, Some("false"), true),
+            PromptStyle::Emoji
+        );
+        assert_eq!(PromptStyle::choose(false, None, true), PromptStyle::Emoji);
+
+        assert_eq!(PromptStyle::Plain.prompt(), "*>>> ");
+        assert!(PromptStyle::Plain.welcome().is_ascii());
+        assert_eq!(PromptStyle::Emoji.prompt(), "🎵>>> ");
+    }
+
+    #[test]
+    fn test_colorize() {
+        for stream in [Stream::Stdout, Stream::Stderr] {
+            assert_eq!(
+                colorize("Commited!", Color::Green, ColorMode::Never, stream),
+                "Commited!"
+            );
+            assert_eq!(
+                colorize("Commited!", Color::Green, ColorMode::Always, stream),
+                "\x1b[32mCommited!\x1b[0m"
+            );
+            assert_eq!(
+                colorize("No such student", Color::Red, ColorMode::Always, stream),
+                "\x1b[31mNo such student\x1b[0m"
+            );
+            assert_eq!(
+                colorize("Begun", Color::Yellow, ColorMode::Always, stream),
+                "\x1b[33mBegun\x1b[0m"
+            );
+        }
+    }
+
+    #[test]
+    fn test_availability_marker() {
+        assert_eq!(availability_marker(2), "");
+        assert_eq!(availability_marker(0), " [FULLY RENTED]");
+        assert_eq!(availability_marker(-1), " [OVERBOOKED - DATA ERROR]");
+    }
+
+    #[test]
+    fn test_pick_listed_id() {
+        let ids = [4, 17];
+
+        assert_eq!(pick_listed_id("17", &ids), Ok(17));
+        assert_eq!(pick_listed_id("4", &ids), Ok(4));
+        assert_eq!(
+            pick_listed_id("abc", &ids),
+            Err(String::from(
+                "'abc' is not an ID. Please enter one of the shown IDs."
+            ))
+        );
+        assert_eq!(
+            pick_listed_id("", &ids),
+            Err(String::from(
+                "'' is not an ID. Please enter one of the shown IDs."
+            ))
+        );
+        assert_eq!(
+            pick_listed_id("5", &ids),
+            Err(String::from(
+                "ID not in the listed reservations. Please enter one of the shown IDs."
+            ))
+        );
+        assert!(pick_listed_id("4", &[]).is_err());
+    }
+}
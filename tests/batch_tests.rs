@@ -0,0 +1,87 @@
+use std::{
+    io::Write,
+    process::{Command, Output, Stdio},
+};
+
+/// Runs the binary in batch mode with `input` piped to stdin
+fn run_batch(input: &str) -> Output {
+    run_batch_with_color(input, "auto")
+}
+
+/// Runs the binary in batch mode with `input` piped to stdin and `SGDB_COLOR` set to `color`
+fn run_batch_with_color(input: &str, color: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_sgdb"))
+        .arg("--batch")
+        .env("SGDB_COLOR", color)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Could not start sgdb");
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_batch_success() {
+    let out = run_batch("begin\nlist\nrollback\n");
+    let stdout = String::from_utf8_lossy(&out.stdout);
+
+    assert!(out.status.success(), "{out:?}");
+    assert!(!stdout.contains("Welcome"));
+    assert!(!stdout.contains(">>>"));
+    assert!(stdout.contains("Begun new transaction!"));
+    assert!(stdout.contains("Rolled back!"));
+}
+
+#[test]
+fn test_batch_continues_after_error() {
+    let out = run_batch("x\nbegin\nrollback\nquit\n");
+    let stdout = String::from_utf8_lossy(&out.stdout);
+
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Command not understood!"));
+    assert!(stdout.contains("Rolled back!"));
+}
+
+#[test]
+fn test_batch_color() {
+    let always = run_batch_with_color("begin\nx\nrollback\n", "always");
+    let never = run_batch_with_color("begin\nx\nrollback\n", "never");
+    let auto = run_batch("begin\nrollback\n");
+
+    assert!(
+        String::from_utf8_lossy(&always.stdout).contains("\x1b[33mBegun new transaction!\x1b[0m")
+    );
+    assert!(String::from_utf8_lossy(&always.stderr).contains("\x1b[31mCommand not understood!"));
+    assert!(!String::from_utf8_lossy(&never.stdout).contains('\x1b'));
+    assert!(!String::from_utf8_lossy(&never.stderr).contains('\x1b'));
+    assert!(!String::from_utf8_lossy(&auto.stdout).contains('\x1b'));
+}
+
+#[test]
+fn test_batch_begin_twice() {
+    let out = run_batch("begin\nbegin\nfbegin\nrollback\n");
+    let stdout = String::from_utf8_lossy(&out.stdout);
+
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr)
+        .contains("Active transaction found. Use 'commit', 'rollback', or 'fbegin'."));
+    assert!(stdout.contains("Previous transaction rolled back!"));
+    assert!(stdout.contains("Rolled back!"));
+}
+
+#[test]
+fn test_batch_sql_not_allowed() {
+    let out = run_batch("sql SELECT 1\n");
+
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Raw SQL is disabled!"));
+}
@@ -0,0 +1,521 @@
+use std::time::{Duration, Instant};
+
+use sgdb::{
+    controller::{Command, ControlError, ControlResult, Controller},
+    db::{self, LockWait, Locking},
+};
+use sqlx::{types::BigDecimal, PgPool};
+use tokio::sync::Mutex;
+
+/// The instruments rented by two sessions at once, different ones so that only the lock taken to
+/// check the rental limit makes the second wait
+const TEST_INSTRUMENT_IDS: [&str; 2] = ["4", "1"];
+/// Instruments to bring the student up to the limit with, a student can rent each instrument only
+/// once at a time
+const SETUP_INSTRUMENT_IDS: [&str; 2] = ["3", "2"];
+const TEST_STUDENT_ID: &str = "2";
+/// The student and instrument of the renting terminated by two sessions at once
+const TERMINATE_STUDENT_ID: &str = "3";
+const TERMINATE_INSTRUMENT_ID: &str = "1";
+/// The student and instrument of the renting locked by one session while another is set to fail
+/// instead of waiting
+const BUSY_STUDENT_ID: &str = "1";
+const BUSY_INSTRUMENT_ID: &str = "3";
+/// Two students without active rentings and the instrument with a single unit which they both
+/// try to rent
+const LAST_UNIT_STUDENT_IDS: [&str; 2] = ["2", "3"];
+const LAST_UNIT_INSTRUMENT_ID: &str = "1";
+/// A student without active rentings, two instruments which they try to rent at once, and the
+/// business rule limiting them to one
+const MAX_ONE_STUDENT_ID: &str = "3";
+const MAX_ONE_INSTRUMENT_IDS: [&str; 2] = ["3", "4"];
+const MAX_ONE_KEY: &str = "test_rent_max_one";
+/// The student inserting rentings directly, checked only by the rental limit trigger, and the
+/// instruments to bring them up to the limit with followed by the two which are inserted at once
+const TRIGGER_STUDENT_ID: i32 = 2;
+const TRIGGER_INSTRUMENT_IDS: [i32; 4] = [3, 2, 1, 4];
+/// The student and instrument of the reservation fulfilled by one session while another
+/// cancels it
+const FULFIL_STUDENT_ID: &str = "3";
+const FULFIL_INSTRUMENT_ID: &str = "3";
+/// How long each renting transaction stays open after renting, long enough for the other task
+/// to reach its own rent
+const HOLD: Duration = Duration::from_millis(300);
+/// Every test runs under each strategy in turn, both must serialize the sessions the same way.
+/// The sessions racing each other always use the same strategy, as they do not exclude
+/// sessions using the other one
+const STRATEGIES: [Locking; 2] = [Locking::Rows, Locking::Advisory];
+
+/// Held by every test as they rent and terminate the same seeded students and instruments, and
+/// a renting committed by one would change what another expects
+static SERIAL: Mutex<()> = Mutex::const_new(());
+
+/// Starts a session using the given locking strategy
+async fn session(pool: PgPool, locking: Locking) -> Controller<'static> {
+    let mut c = Controller::with_pools(pool.into());
+    c.execute(Command::SetLocking(locking)).await.unwrap();
+    c
+}
+
+/// Rents in the session and keeps the transaction open for [`HOLD`] before committing, or rolls
+/// back on error
+async fn rent_and_hold(
+    mut c: Controller<'static>,
+    student: &str,
+    inst: &str,
+) -> Result<ControlResult, ControlError> {
+    c.execute(Command::Begin { force: false }).await?;
+
+    let rent = Command::Rent(student.into(), inst.into());
+    match c.execute(rent).await {
+        Ok(r) => {
+            tokio::time::sleep(HOLD).await;
+            c.execute(Command::Commit).await?;
+            Ok(r.result)
+        }
+        Err(e) => {
+            c.execute(Command::Rollback(None)).await?;
+            Err(e)
+        }
+    }
+}
+
+/// Terminates the renting of the student and instrument and keeps the transaction open for
+/// [`HOLD`] before committing, or rolls back on error
+async fn terminate_and_hold(
+    pool: PgPool,
+    student: &str,
+    inst: &str,
+    locking: Locking,
+) -> Result<ControlResult, ControlError> {
+    let mut c = session(pool, locking).await;
+    c.execute(Command::Begin { force: false }).await?;
+
+    let terminate = Command::TryTerminate(student.into(), inst.into(), None);
+    match c.execute(terminate).await {
+        Ok(r) => {
+            tokio::time::sleep(HOLD).await;
+            c.execute(Command::Commit).await?;
+            Ok(r.result)
+        }
+        Err(e) => {
+            c.execute(Command::Rollback(None)).await?;
+            Err(e)
+        }
+    }
+}
+
+/// Fulfils the reservation and keeps the transaction open for [`HOLD`] before committing, or
+/// rolls back on error
+async fn fulfil_and_hold(pool: PgPool, id: i32) -> Result<ControlResult, ControlError> {
+    let mut c = Controller::with_pools(pool.into());
+    c.execute(Command::Begin { force: false }).await?;
+
+    match c.execute(Command::Fulfil(id.to_string())).await {
+        Ok(r) => {
+            tokio::time::sleep(HOLD).await;
+            c.execute(Command::Commit).await?;
+            Ok(r.result)
+        }
+        Err(e) => {
+            c.execute(Command::Rollback(None)).await?;
+            Err(e)
+        }
+    }
+}
+
+fn rent_id(r: &Result<ControlResult, ControlError>) -> Option<i32> {
+    match r {
+        Ok(ControlResult::Rent { rent_id, .. }) => Some(*rent_id),
+        _ => None,
+    }
+}
+
+/// Deletes the rentings created by a test and their charges
+async fn delete_rentings(pool: &PgPool, ids: &[i32]) {
+    sqlx::query!("DELETE FROM rental_charges WHERE rent_id = ANY($1);", ids)
+        .execute(pool)
+        .await
+        .unwrap();
+    sqlx::query!("DELETE FROM rentings WHERE rent_id = ANY($1);", ids)
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_concurrent_rent_serialized() {
+    let _serial = SERIAL.lock().await;
+    for locking in STRATEGIES {
+        concurrent_rent(locking).await;
+    }
+}
+
+async fn concurrent_rent(locking: Locking) {
+    let pool = db::setup_conn().await.unwrap().primary;
+    let mut tx = pool.begin().await.unwrap();
+    let max = db::get_max_rentals(&mut tx, db::MAX_RENTALS_KEY)
+        .await
+        .unwrap();
+    tx.rollback().await.unwrap();
+
+    // The first session brings the student up to the limit and holds it there, nothing is
+    // committed so the database is left as it was even if the test fails
+    let mut first = session(pool.clone(), locking).await;
+    first
+        .execute(Command::Begin { force: false })
+        .await
+        .unwrap();
+    for inst in SETUP_INSTRUMENT_IDS
+        .iter()
+        .take(usize::try_from(max - 1).unwrap())
+        .chain(&TEST_INSTRUMENT_IDS[..1])
+    {
+        first
+            .execute(Command::Rent(TEST_STUDENT_ID.into(), (*inst).into()))
+            .await
+            .expect("Renting up to the limit failed");
+    }
+
+    let mut second = session(pool.clone(), locking).await;
+    let second = tokio::spawn(async move {
+        second.execute(Command::Begin { force: false }).await?;
+        let rent = Command::Rent(TEST_STUDENT_ID.into(), TEST_INSTRUMENT_IDS[1].into());
+        let r = second.execute(rent).await.map(|r| r.result);
+        let done = Instant::now();
+        second.execute(Command::Rollback(None)).await?;
+        Ok::<_, ControlError>((r, done))
+    });
+    tokio::time::sleep(HOLD).await;
+    let released = Instant::now();
+    first.execute(Command::Rollback(None)).await.unwrap();
+    let (second, done) = second.await.unwrap().unwrap();
+
+    // Without the lock the second session would count the rentings at once, while the first
+    // could still commit its own and take the student past the limit
+    assert!(
+        done >= released,
+        "The second rent should wait for the first session with {locking:?}"
+    );
+    assert!(
+        matches!(second, Ok(ControlResult::Rent { .. })),
+        "The second rent should succeed once the first is rolled back with {locking:?}: \
+        {second:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_concurrent_terminate_serialized() {
+    let _serial = SERIAL.lock().await;
+    for locking in STRATEGIES {
+        concurrent_terminate(locking).await;
+    }
+}
+
+async fn concurrent_terminate(locking: Locking) {
+    let pool = db::setup_conn().await.unwrap().primary;
+    let mut c = Controller::with_pools(pool.clone().into());
+    let rented = c
+        .execute(Command::Rent(
+            TERMINATE_STUDENT_ID.into(),
+            TERMINATE_INSTRUMENT_ID.into(),
+        ))
+        .await
+        .map(|r| r.result);
+    let id = rent_id(&rented).expect("Setup renting failed");
+
+    let hold = || {
+        terminate_and_hold(
+            pool.clone(),
+            TERMINATE_STUDENT_ID,
+            TERMINATE_INSTRUMENT_ID,
+            locking,
+        )
+    };
+    let first = tokio::spawn(hold());
+    let second = tokio::spawn(hold());
+    let results = [first.await.unwrap(), second.await.unwrap()];
+
+    delete_rentings(&pool, &[id]).await;
+
+    // The second session waits on the lock of the first and then finds nothing to terminate
+    let succeeded = results.iter().filter(|r| r.is_ok()).count();
+    assert_eq!(
+        succeeded, 1,
+        "Exactly one terminate should succeed with {locking:?}: {results:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_lock_wait_fail_busy() {
+    let _serial = SERIAL.lock().await;
+    for locking in STRATEGIES {
+        lock_wait_fail_busy(locking).await;
+    }
+}
+
+async fn lock_wait_fail_busy(locking: Locking) {
+    let pool = db::setup_conn().await.unwrap().primary;
+    let mut c = Controller::with_pools(pool.clone().into());
+    let rented = c
+        .execute(Command::Rent(
+            BUSY_STUDENT_ID.into(),
+            BUSY_INSTRUMENT_ID.into(),
+        ))
+        .await
+        .map(|r| r.result);
+    let id = rent_id(&rented).expect("Setup renting failed");
+
+    let first = tokio::spawn(terminate_and_hold(
+        pool.clone(),
+        BUSY_STUDENT_ID,
+        BUSY_INSTRUMENT_ID,
+        locking,
+    ));
+    // Give the first session time to lock the renting before the second tries
+    tokio::time::sleep(HOLD / 3).await;
+
+    let mut c = session(pool.clone(), locking).await;
+    c.execute(Command::SetLockWait(LockWait::Fail))
+        .await
+        .unwrap();
+    c.execute(Command::Begin { force: false }).await.unwrap();
+    let second = c
+        .execute(Command::TryTerminate(
+            BUSY_STUDENT_ID.into(),
+            BUSY_INSTRUMENT_ID.into(),
+            None,
+        ))
+        .await
+        .map(|r| r.result);
+    // Failing to lock does not abort the transaction
+    let after = c
+        .execute(Command::Rent(
+            TEST_STUDENT_ID.into(),
+            TEST_INSTRUMENT_IDS[0].into(),
+        ))
+        .await
+        .map(|r| r.result);
+    c.execute(Command::Rollback(None)).await.unwrap();
+    let first = first.await.unwrap();
+
+    delete_rentings(&pool, &[id]).await;
+
+    assert!(first.is_ok(), "{locking:?}: {first:?}");
+    assert_eq!(second, Err(ControlError::ResourceBusy), "{locking:?}");
+    assert!(after.is_ok(), "{locking:?}: {after:?}");
+}
+
+#[tokio::test]
+async fn test_concurrent_rent_last_unit() {
+    let _serial = SERIAL.lock().await;
+    for locking in STRATEGIES {
+        concurrent_rent_last_unit(locking).await;
+    }
+}
+
+/// Neither student nor the instrument has an active renting, so there are no rentings rows to
+/// lock and the sessions must still be serialized
+async fn concurrent_rent_last_unit(locking: Locking) {
+    let pool = db::setup_conn().await.unwrap().primary;
+
+    let [first, second] = [0, 1].map(|n| {
+        let (pool, u) = (pool.clone(), LAST_UNIT_STUDENT_IDS[n]);
+        tokio::spawn(async move {
+            let c = session(pool, locking).await;
+            rent_and_hold(c, u, LAST_UNIT_INSTRUMENT_ID).await
+        })
+    });
+    let results = [first.await.unwrap(), second.await.unwrap()];
+
+    let created: Vec<i32> = results.iter().filter_map(rent_id).collect();
+    delete_rentings(&pool, &created).await;
+
+    let succeeded = results.iter().filter(|r| r.is_ok()).count();
+    assert_eq!(
+        succeeded, 1,
+        "Exactly one rent should succeed with {locking:?}: {results:?}"
+    );
+    let i = LAST_UNIT_INSTRUMENT_ID.parse().unwrap();
+    assert!(
+        results
+            .iter()
+            .any(|r| r == &Err(ControlError::InstrumentUnavailable(i))),
+        "{locking:?}: {results:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_concurrent_rent_max_one() {
+    let _serial = SERIAL.lock().await;
+    let pool = db::setup_conn().await.unwrap().primary;
+    sqlx::query!(
+        "INSERT INTO business_rules (name, value) VALUES ($1, '1') ON CONFLICT (name) DO UPDATE SET value = '1';",
+        MAX_ONE_KEY
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    for locking in STRATEGIES {
+        concurrent_rent_max_one(&pool, locking).await;
+    }
+
+    sqlx::query!("DELETE FROM business_rules WHERE name = $1;", MAX_ONE_KEY)
+        .execute(&pool)
+        .await
+        .unwrap();
+}
+
+/// The student has no active renting, so there are no rentings rows to lock and the sessions
+/// must still be serialized
+async fn concurrent_rent_max_one(pool: &PgPool, locking: Locking) {
+    let [first, second] = [0, 1].map(|n| {
+        let (pool, i) = (pool.clone(), MAX_ONE_INSTRUMENT_IDS[n]);
+        tokio::spawn(async move {
+            let mut c = session(pool, locking).await;
+            c.max_rentals_key(MAX_ONE_KEY);
+            rent_and_hold(c, MAX_ONE_STUDENT_ID, i).await
+        })
+    });
+    let results = [first.await.unwrap(), second.await.unwrap()];
+
+    let created: Vec<i32> = results.iter().filter_map(rent_id).collect();
+    delete_rentings(pool, &created).await;
+
+    let succeeded = results.iter().filter(|r| r.is_ok()).count();
+    assert_eq!(
+        succeeded, 1,
+        "Exactly one rent should succeed with {locking:?}: {results:?}"
+    );
+    assert!(
+        results
+            .iter()
+            .any(|r| matches!(r, Err(ControlError::TooManyRentals { .. }))),
+        "{locking:?}: {results:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_rental_limit_trigger_serialized() {
+    let _serial = SERIAL.lock().await;
+    let pool = db::setup_conn().await.unwrap().primary;
+    // The sessions race each other, so the trigger has to be the one create.sql installed rather
+    // than one installed by a transaction of this test
+    let installed = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM pg_trigger WHERE tgname = 'rentings_rental_limit') AS "installed!";"#
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert!(
+        installed,
+        "The rental limit trigger is missing, run sql/create.sql"
+    );
+    let mut tx = pool.begin().await.unwrap();
+    let max = db::get_max_rentals(&mut tx, db::MAX_RENTALS_KEY)
+        .await
+        .unwrap();
+    tx.rollback().await.unwrap();
+    let price = BigDecimal::from(1);
+
+    // Bring the student up to max - 1 active rentals, bypassing the application's checks
+    let setup = usize::try_from(max - 1).unwrap();
+    let mut created = vec![];
+    let mut tx = pool.begin().await.unwrap();
+    for i in &TRIGGER_INSTRUMENT_IDS[..setup] {
+        let (id, _) = db::rent(&mut tx, TRIGGER_STUDENT_ID, *i, &price, &price)
+            .await
+            .unwrap();
+        created.push(id);
+    }
+    tx.commit().await.unwrap();
+
+    let mut first = pool.begin().await.unwrap();
+    let (id, _) = db::rent(
+        &mut first,
+        TRIGGER_STUDENT_ID,
+        TRIGGER_INSTRUMENT_IDS[setup],
+        &price,
+        &price,
+    )
+    .await
+    .unwrap();
+    created.push(id);
+
+    // The second insert must wait on the first, which is only committed after the hold
+    let second = {
+        let pool = pool.clone();
+        let price = price.clone();
+        tokio::spawn(async move {
+            let mut tx = pool.begin().await.unwrap();
+            let i = TRIGGER_INSTRUMENT_IDS[setup + 1];
+            let r = db::rent(&mut tx, TRIGGER_STUDENT_ID, i, &price, &price).await;
+            match r {
+                Ok((id, _)) => {
+                    tx.commit().await.unwrap();
+                    Ok(id)
+                }
+                Err(e) => Err(ControlError::from(e)),
+            }
+        })
+    };
+    tokio::time::sleep(HOLD).await;
+    first.commit().await.unwrap();
+    let second = second.await.unwrap();
+
+    created.extend(second.iter());
+    delete_rentings(&pool, &created).await;
+
+    assert!(
+        matches!(second, Err(ControlError::TooManyRentals { .. })),
+        "{second:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_concurrent_fulfil_unreserve() {
+    let _serial = SERIAL.lock().await;
+    let pool = db::setup_conn().await.unwrap().primary;
+    let mut c = Controller::with_pools(pool.clone().into());
+    let reserved = c
+        .execute(Command::Reserve(
+            FULFIL_STUDENT_ID.into(),
+            FULFIL_INSTRUMENT_ID.into(),
+        ))
+        .await
+        .unwrap();
+    let ControlResult::Reserved(id) = reserved.result else {
+        panic!("Setup reservation failed: {reserved:?}");
+    };
+
+    let first = tokio::spawn(fulfil_and_hold(pool.clone(), id));
+    // Give the first session time to lock the reservation before the second tries
+    tokio::time::sleep(HOLD / 3).await;
+
+    let mut c = Controller::with_pools(pool.clone().into());
+    c.execute(Command::Begin { force: false }).await.unwrap();
+    let second = c
+        .execute(Command::Unreserve(id.to_string()))
+        .await
+        .map(|r| r.result);
+    c.execute(Command::Rollback(None)).await.unwrap();
+    let first = first.await.unwrap();
+
+    let created: Vec<i32> = match &first {
+        Ok(ControlResult::Fulfilled { rent_id, .. }) => vec![*rent_id],
+        _ => vec![],
+    };
+    sqlx::query!("DELETE FROM reservations WHERE reservation_id = $1;", id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    delete_rentings(&pool, &created).await;
+
+    // The cancel waits on the fulfil and then finds the reservation no longer active
+    assert!(
+        matches!(first, Ok(ControlResult::Fulfilled { .. })),
+        "{first:?}"
+    );
+    assert_eq!(second, Err(ControlError::ReservationNotActive(id)));
+}